@@ -0,0 +1,244 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    BuildLease, BuildRecord, BuildReport, BuildResult, BuildSchedule, ErrorCode, ErrorResponse,
+    LeaseClaim, QueryBody, StepResult,
+};
+
+/// How many times to try a request that fails with a temporary error.
+const MAX_ATTEMPTS: u32 = 3;
+/// Delay before the first retry, doubled for each retry after that.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(thiserror::Error, Debug)]
+pub enum CacheError {
+    #[error("error connecting to cache server: {0}")]
+    Connecting(#[from] Box<ureq::Transport>),
+    #[error("cache server returned {status} ({code}): {message} (request {request_id})")]
+    Server {
+        status: u16,
+        code: ErrorCode,
+        message: String,
+        request_id: String,
+    },
+    #[error("error decoding response from cache server: {0}")]
+    Decoding(#[from] std::io::Error),
+}
+
+impl CacheError {
+    /// Whether the same request might succeed later. Anything else is a
+    /// problem with the request itself.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Connecting(_) => true,
+            Self::Server { status, .. } => *status == 429 || *status >= 500,
+            Self::Decoding(_) => false,
+        }
+    }
+}
+
+impl From<ureq::Error> for CacheError {
+    fn from(value: ureq::Error) -> Self {
+        match value {
+            ureq::Error::Status(status, resp) => {
+                let fallback_id = resp.header("x-request-id").unwrap_or("unknown").to_string();
+                let status_text = resp.status_text().to_string();
+                match resp.into_json::<ErrorResponse>() {
+                    Ok(body) => Self::Server {
+                        status,
+                        code: body.error.code,
+                        message: body.error.message,
+                        request_id: body.request_id,
+                    },
+                    // e.g. from a proxy in front of the server
+                    Err(_) => Self::Server {
+                        status,
+                        code: ErrorCode::Unknown,
+                        message: status_text,
+                        request_id: fallback_id,
+                    },
+                }
+            }
+            ureq::Error::Transport(t) => Self::Connecting(Box::new(t)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CacheClient {
+    url: String,
+    token: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl CacheClient {
+    pub fn new(url: String, token: Option<String>) -> Self {
+        let url = url.trim_end_matches('/').to_string();
+        let agent = ureq::Agent::new();
+
+        Self { url, token, agent }
+    }
+
+    /// A request that records data, and so needs to be authenticated.
+    fn write_request(&self, method: &str, path: &str) -> ureq::Request {
+        let req = self.agent.request(method, &self.endpoint(path));
+        match &self.token {
+            Some(token) => req.set("Authorization", &format!("Bearer {token}")),
+            None => {
+                log::warn!("no cache server token configured, request will likely fail");
+                req
+            }
+        }
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}/v1/{path}", self.url)
+    }
+
+    /// Run a request, retrying it if it fails with a temporary error.
+    fn with_retries<T>(
+        &self,
+        mut request: impl FnMut() -> Result<T, CacheError>,
+    ) -> Result<T, CacheError> {
+        let mut delay = RETRY_DELAY;
+        for attempt in 1.. {
+            match request() {
+                Err(e) if e.is_retryable() && attempt < MAX_ATTEMPTS => {
+                    log::warn!("{e}, retrying in {}s", delay.as_secs());
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+        unreachable!()
+    }
+
+    pub fn query(&self, hashes: &[String]) -> Result<Vec<BuildRecord>, CacheError> {
+        log::debug!("querying cache server for {} derivations", hashes.len());
+        let body = QueryBody {
+            hashes: hashes.to_vec(),
+        };
+        let records: Vec<BuildRecord> = self.with_retries(|| {
+            let resp = self
+                .agent
+                .post(&self.endpoint("derivation-builds/query"))
+                .send_json(&body)?;
+            Ok(resp.into_json()?)
+        })?;
+        log::debug!("cache server returned {} records", records.len());
+
+        Ok(records)
+    }
+
+    /// Wait until each derivation has a build that finished after `since`, or
+    /// until `timeout` (which the server may shorten), returning those builds.
+    pub fn wait_for_builds(
+        &self,
+        hashes: &[String],
+        since: DateTime<Utc>,
+        timeout: Duration,
+    ) -> Result<Vec<BuildRecord>, CacheError> {
+        log::debug!("waiting for builds of {} derivations", hashes.len());
+        self.with_retries(|| {
+            let resp = self
+                .agent
+                .get(&self.endpoint("derivation-builds/wait"))
+                .query("hashes", &hashes.join(","))
+                .query("since", &since.to_rfc3339())
+                .query("timeout_secs", &timeout.as_secs().to_string())
+                .call()?;
+            Ok(resp.into_json()?)
+        })
+    }
+
+    pub fn insert_start(&self, record: &BuildRecord) -> Result<(), CacheError> {
+        log::debug!("recording start of build of {}", record.hash);
+        self.with_retries(|| {
+            self.write_request("POST", "derivation-builds")
+                .send_json(record)?;
+            Ok(())
+        })
+    }
+
+    /// Tell the cache server which derivation each step of a build is
+    /// building, so it can record their results from Buildkite webhooks.
+    pub fn schedule_builds(&self, schedule: &BuildSchedule) -> Result<(), CacheError> {
+        log::debug!("recording {} scheduled builds", schedule.steps.len());
+        self.with_retries(|| {
+            self.write_request("POST", "scheduled-builds")
+                .send_json(schedule)?;
+            Ok(())
+        })
+    }
+
+    /// Tell the cache server about this CI build, so step results can be
+    /// recorded against it.
+    pub fn record_build(&self, build_id: &str, report: &BuildReport) -> Result<(), CacheError> {
+        log::debug!("recording build {build_id}");
+        let path = format!("builds/{build_id}");
+        self.with_retries(|| {
+            self.write_request("PUT", &path).send_json(report)?;
+            Ok(())
+        })
+    }
+
+    pub fn record_step_run(
+        &self,
+        build_id: &str,
+        step_key: &str,
+        result: &StepResult,
+    ) -> Result<(), CacheError> {
+        log::debug!("recording outcome of step {step_key}");
+        let path = format!("builds/{build_id}/steps/{step_key}");
+        self.with_retries(|| {
+            self.write_request("PUT", &path).send_json(result)?;
+            Ok(())
+        })
+    }
+
+    /// Claim the lease on building a derivation (or renew it, if we already
+    /// hold it), returning the build that holds it afterwards.
+    pub fn claim_lease(
+        &self,
+        hash: &str,
+        build_id: &str,
+        build_url: &str,
+        ttl: Duration,
+    ) -> Result<BuildLease, CacheError> {
+        let path = format!("build-leases/{hash}");
+        let claim = LeaseClaim {
+            build_id: build_id.to_string(),
+            build_url: build_url.to_string(),
+            ttl_secs: ttl.as_secs(),
+        };
+        self.with_retries(|| {
+            let resp = self.write_request("POST", &path).send_json(&claim)?;
+            Ok(resp.into_json()?)
+        })
+    }
+
+    pub fn release_lease(&self, hash: &str, build_id: &str) -> Result<(), CacheError> {
+        let path = format!("build-leases/{hash}/{build_id}");
+        self.with_retries(|| {
+            self.write_request("DELETE", &path).call()?;
+            Ok(())
+        })
+    }
+
+    pub fn mark_finish(&self, record: &BuildRecord) -> Result<(), CacheError> {
+        log::debug!("recording finish of build of {}", record.hash);
+        let path = format!("derivation-builds/{}/{}", record.hash, record.build_id);
+        let result = BuildResult {
+            started_at: Some(record.started_at),
+            finished_at: record.finished_at.unwrap_or_else(Utc::now),
+            success: record.succeeded(),
+        };
+        self.with_retries(|| {
+            self.write_request("PUT", &path).send_json(&result)?;
+            Ok(())
+        })
+    }
+}
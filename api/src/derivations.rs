@@ -0,0 +1,74 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A build of a derivation.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "postgres", derive(postgres_from_row::FromRow))]
+pub struct BuildRecord {
+    pub hash: String,
+    pub build_id: String,
+    pub build_url: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub success: Option<bool>,
+    /// The flake attribute the derivation was built as, which stays the same
+    /// when the derivation changes
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+impl BuildRecord {
+    pub fn succeeded(&self) -> bool {
+        self.success.unwrap_or(false)
+    }
+}
+
+/// The outcome of a finished build.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BuildResult {
+    /// Corrects the start time recorded at creation, if given
+    #[serde(default)]
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: DateTime<Utc>,
+    pub success: bool,
+}
+
+/// Hashes of derivations to find the builds of.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct QueryBody {
+    pub hashes: Vec<String>,
+}
+
+/// Build history of a derivation, or of every derivation built as a flake
+/// attribute.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BuildHistory {
+    /// The latest builds, newest first
+    pub builds: Vec<HistoryEntry>,
+    /// Statistics of every recorded build, even those not listed
+    pub stats: BuildStats,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HistoryEntry {
+    #[serde(flatten)]
+    pub record: BuildRecord,
+    /// Unset until the build finishes
+    pub duration_secs: Option<i64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BuildStats {
+    /// All recorded builds, including unfinished ones
+    pub total: usize,
+    pub finished: usize,
+    pub succeeded: usize,
+    /// Fraction of finished builds that succeeded, unset if none have
+    /// finished
+    pub success_rate: Option<f64>,
+    pub last_success: Option<BuildRecord>,
+    pub last_failure: Option<BuildRecord>,
+    /// Durations are only of successful builds
+    pub p50_duration_secs: Option<i64>,
+    pub p95_duration_secs: Option<i64>,
+}
@@ -0,0 +1,46 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Machine-readable error codes, for clients to match on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidRequest,
+    TooManyHashes,
+    Unauthorized,
+    NotFound,
+    Unavailable,
+    Internal,
+    /// A code this version doesn't know about, or an error that didn't come
+    /// from the server (e.g., from a proxy in front of it)
+    #[serde(other)]
+    Unknown,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidRequest => "invalid_request",
+            Self::TooManyHashes => "too_many_hashes",
+            Self::Unauthorized => "unauthorized",
+            Self::NotFound => "not_found",
+            Self::Unavailable => "unavailable",
+            Self::Internal => "internal",
+            Self::Unknown => "unknown",
+        })
+    }
+}
+
+/// The body of every error response.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ErrorResponse {
+    pub error: ErrorDetails,
+    pub request_id: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ErrorDetails {
+    pub code: ErrorCode,
+    pub message: String,
+}
@@ -0,0 +1,24 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A request to claim (or renew) the lease on building a derivation.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LeaseClaim {
+    pub build_id: String,
+    pub build_url: String,
+    /// How long the lease lasts without being renewed
+    pub ttl_secs: u64,
+}
+
+/// A claim by a build to be the one building a derivation, until
+/// `expires_at`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "postgres", derive(postgres_from_row::FromRow))]
+pub struct BuildLease {
+    pub hash: String,
+    pub build_id: String,
+    pub build_url: String,
+    pub pipeline_slug: String,
+    pub acquired_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
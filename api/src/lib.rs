@@ -0,0 +1,33 @@
+// The cache server's API, as shared by the server and the `ci` tool: the
+// bodies of its requests and responses, its error codes, and (with the
+// `client` feature) a client for it.
+//
+// Anything sent over the wire belongs here, so that changing it on one side
+// breaks the build of the other instead of CI.
+
+#[cfg(feature = "client")]
+mod client;
+mod derivations;
+mod errors;
+mod leases;
+mod pipelines;
+
+#[cfg(feature = "client")]
+pub use client::{CacheClient, CacheError};
+pub use derivations::{
+    BuildHistory, BuildRecord, BuildResult, BuildStats, HistoryEntry, QueryBody,
+};
+pub use errors::{ErrorCode, ErrorDetails, ErrorResponse};
+pub use leases::{BuildLease, LeaseClaim};
+pub use pipelines::{
+    BuildReport, BuildSchedule, BuildSteps, PipelineBuild, ScheduledStep, StepResult, StepRun,
+};
+
+/// Upper limit on the number of hashes in a single query.
+pub const MAX_QUERY_HASHES: usize = 2000;
+/// Upper limit on how long a lease can be claimed for at once. Longer builds
+/// should renew their lease.
+pub const MAX_LEASE_TTL_SECS: u64 = 60 * 60;
+/// Upper limit on how long to wait for builds to finish in one request.
+/// Clients should wait again if they need to.
+pub const MAX_WAIT_SECS: u64 = 60;
@@ -0,0 +1,91 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The derivations to be built by the steps of a CI build.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BuildSchedule {
+    pub build_id: String,
+    pub build_url: String,
+    pub steps: Vec<ScheduledStep>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ScheduledStep {
+    pub step_key: String,
+    /// The derivation the step builds
+    pub hash: String,
+    /// The flake attribute the derivation is built as
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+/// A CI build of a pipeline, as reported when it starts.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BuildReport {
+    pub url: String,
+    pub commit_sha: String,
+    #[serde(default)]
+    pub branch: Option<String>,
+    #[serde(default)]
+    pub tag: Option<String>,
+    /// Buildkite's ID for the pipeline
+    #[serde(default)]
+    pub pipeline_id: Option<String>,
+    #[serde(default)]
+    pub repository: Option<String>,
+}
+
+/// A CI build of a pipeline.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "postgres", derive(postgres_from_row::FromRow))]
+pub struct PipelineBuild {
+    pub id: String,
+    pub pipeline_slug: String,
+    pub url: String,
+    pub commit_sha: String,
+    pub branch: Option<String>,
+    pub tag: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The outcome of a step of a CI build, whether or not it built a
+/// derivation.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct StepResult {
+    #[serde(default)]
+    pub label: Option<String>,
+    /// The derivation the step built, if any
+    #[serde(default)]
+    pub hash: Option<String>,
+    #[serde(default)]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Unset if the step didn't run to completion (e.g., it was skipped or
+    /// cancelled)
+    #[serde(default)]
+    pub success: Option<bool>,
+}
+
+/// A step of a CI build, and its outcome.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "postgres", derive(postgres_from_row::FromRow))]
+pub struct StepRun {
+    pub build_id: String,
+    pub step_key: String,
+    pub label: Option<String>,
+    /// The derivation the step built, if it built one and that build was
+    /// recorded
+    pub hash: Option<String>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub success: Option<bool>,
+}
+
+/// A CI build, with the outcomes of its steps.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BuildSteps {
+    #[serde(flatten)]
+    pub build: PipelineBuild,
+    pub steps: Vec<StepRun>,
+}
@@ -68,6 +68,7 @@
             rustfmt = {
               package = pkgs.rust-bin.stable.latest.rustfmt-preview;
               checks = {
+                api.src = ./api/src;
                 tool.src = ./tool/src;
                 server.src = ./server/src;
               };
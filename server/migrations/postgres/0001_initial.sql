@@ -0,0 +1,22 @@
+-- NOTE: IF NOT EXISTS so this also applies cleanly to databases that were set
+-- up by hand before migrations were tracked.
+CREATE TABLE IF NOT EXISTS build_records (
+    hash TEXT NOT NULL,
+    build_id TEXT NOT NULL,
+    started_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    finished_at TIMESTAMP WITH TIME ZONE,
+    success BOOLEAN,
+    build_url TEXT NOT NULL
+);
+
+-- Tables set up by hand stored these as fixed-width strings, which come back
+-- padded with spaces. Store them as they are.
+ALTER TABLE build_records
+    ALTER COLUMN hash TYPE TEXT,
+    ALTER COLUMN build_id TYPE TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_build_records_hash
+    ON build_records (hash);
+
+CREATE INDEX IF NOT EXISTS idx_build_records_hash_build_id
+    ON build_records(hash, build_id);
@@ -0,0 +1,13 @@
+CREATE TABLE api_tokens (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    pipeline_slug TEXT NOT NULL,
+    token_hash CHARACTER(64) NOT NULL UNIQUE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+-- Pipeline of the token that recorded the build (NULL for records created
+-- before authentication was required)
+ALTER TABLE build_records
+    ADD COLUMN pipeline_slug TEXT;
@@ -0,0 +1,10 @@
+-- At most one build may hold the lease on a derivation at a time. Expired
+-- leases are left in place until they're claimed by another build.
+CREATE TABLE build_leases (
+    hash TEXT PRIMARY KEY,
+    build_id TEXT NOT NULL,
+    build_url TEXT NOT NULL,
+    pipeline_slug TEXT NOT NULL,
+    acquired_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
@@ -0,0 +1,13 @@
+-- Each build records at most one result per derivation. Earlier versions
+-- allowed duplicates, of which we keep the latest finished one.
+DELETE FROM build_records a
+USING build_records b
+WHERE
+    a.hash = b.hash
+    AND a.build_id = b.build_id
+    AND (a.finished_at IS NOT NULL, a.ctid) < (b.finished_at IS NOT NULL, b.ctid);
+
+DROP INDEX idx_build_records_hash_build_id;
+
+CREATE UNIQUE INDEX idx_build_records_hash_build_id
+    ON build_records (hash, build_id);
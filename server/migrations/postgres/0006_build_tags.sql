@@ -0,0 +1,8 @@
+-- The flake attribute each derivation was built as, which (unlike the hash)
+-- stays the same across changes to the derivation.
+ALTER TABLE build_records ADD COLUMN tag TEXT;
+
+CREATE INDEX idx_build_records_tag
+    ON build_records (tag);
+
+ALTER TABLE scheduled_builds ADD COLUMN tag TEXT;
@@ -0,0 +1,41 @@
+-- CI pipelines, their builds, and the results of every step of those builds
+-- (including steps that don't build a derivation, e.g. linters).
+CREATE TABLE pipelines (
+    slug TEXT PRIMARY KEY,
+    -- Buildkite's ID for the pipeline
+    buildkite_id TEXT,
+    repository TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE TABLE builds (
+    id TEXT PRIMARY KEY,
+    pipeline_slug TEXT NOT NULL REFERENCES pipelines (slug),
+    url TEXT NOT NULL,
+    commit_sha TEXT NOT NULL,
+    branch TEXT,
+    tag TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX idx_builds_pipeline_slug_branch
+    ON builds (pipeline_slug, branch);
+
+CREATE INDEX idx_builds_commit_sha
+    ON builds (commit_sha);
+
+CREATE TABLE step_runs (
+    build_id TEXT NOT NULL REFERENCES builds (id),
+    step_key TEXT NOT NULL,
+    label TEXT,
+    -- The derivation the step built, if any (and its build was recorded)
+    hash TEXT,
+    started_at TIMESTAMP WITH TIME ZONE,
+    finished_at TIMESTAMP WITH TIME ZONE,
+    success BOOLEAN,
+    PRIMARY KEY (build_id, step_key),
+    FOREIGN KEY (hash, build_id) REFERENCES build_records (hash, build_id)
+);
+
+CREATE INDEX idx_step_runs_hash_build_id
+    ON step_runs (hash, build_id);
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS build_records (
+    hash TEXT NOT NULL,
+    build_id TEXT NOT NULL,
+    started_at TEXT NOT NULL,
+    finished_at TEXT,
+    success INTEGER,
+    build_url TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_build_records_hash
+    ON build_records (hash);
+
+CREATE INDEX IF NOT EXISTS idx_build_records_hash_build_id
+    ON build_records(hash, build_id);
@@ -0,0 +1,13 @@
+CREATE TABLE api_tokens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    pipeline_slug TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at TEXT NOT NULL,
+    revoked_at TEXT
+);
+
+-- Pipeline of the token that recorded the build (NULL for records created
+-- before authentication was required)
+ALTER TABLE build_records
+    ADD COLUMN pipeline_slug TEXT;
@@ -0,0 +1,12 @@
+-- Which derivation each step of a CI build is building, recorded at
+-- evaluation time so that Buildkite webhooks about the step can be matched
+-- back to the derivation.
+CREATE TABLE scheduled_builds (
+    build_id TEXT NOT NULL,
+    step_key TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    build_url TEXT NOT NULL,
+    pipeline_slug TEXT NOT NULL,
+    scheduled_at TEXT NOT NULL,
+    PRIMARY KEY (build_id, step_key)
+);
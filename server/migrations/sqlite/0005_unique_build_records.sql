@@ -0,0 +1,20 @@
+-- Each build records at most one result per derivation. Earlier versions
+-- allowed duplicates, of which we keep the latest finished one.
+DELETE FROM build_records
+WHERE rowid NOT IN (
+    SELECT rowid FROM (
+        SELECT
+            rowid,
+            ROW_NUMBER() OVER (
+                PARTITION BY hash, build_id
+                ORDER BY finished_at IS NOT NULL DESC, rowid DESC
+            ) AS n
+        FROM build_records
+    )
+    WHERE n = 1
+);
+
+DROP INDEX idx_build_records_hash_build_id;
+
+CREATE UNIQUE INDEX idx_build_records_hash_build_id
+    ON build_records (hash, build_id);
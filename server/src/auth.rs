@@ -0,0 +1,29 @@
+// API tokens are only ever shown in full when they're created. The database
+// only keeps a SHA-256 hash of each token, so a leaked database doesn't leak
+// working credentials.
+
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+const TOKEN_PREFIX: &str = "cit_";
+const TOKEN_BYTES: usize = 32;
+
+pub struct GeneratedToken {
+    /// The token to hand to the client
+    pub secret: String,
+    /// What to store in the database
+    pub hash: String,
+}
+
+pub fn generate_token() -> GeneratedToken {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    let secret = format!("{TOKEN_PREFIX}{}", hex::encode(bytes));
+    let hash = hash_token(&secret);
+    GeneratedToken { secret, hash }
+}
+
+pub fn hash_token(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
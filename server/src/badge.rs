@@ -0,0 +1,176 @@
+// SVG status badges, for embedding in READMEs:
+//  - GET /badges/pipelines/:pipeline[?branch=<branch>][&label=<label>]
+//    State of the latest collected CI build of a pipeline (on a branch)
+//  - GET /badges/derivations?hash=<hash>|tag=<tag>[&label=<label>]
+//    State of the latest finished build of a derivation, or of every
+//    derivation built as a flake attribute
+//
+// A pipeline build only counts once `collect` has recorded its steps, so the
+// badge doesn't flip to an unknown state every time a new build starts.
+// Badges are served with `Cache-Control: no-cache`, since image proxies (e.g.
+// GitHub's) would otherwise cache them indefinitely.
+
+use axum::extract::{Path, Query, State};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+
+use crate::dashboard::Escaped;
+use crate::http::{self, AppState, HTTPHandlingError};
+use crate::store::{BuildFilter, HistoryFilter, StepRun};
+
+/// How many of a pipeline's latest builds to look through for one that has
+/// been collected.
+const PIPELINE_BUILDS_LIMIT: u32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BadgeState {
+    Passing,
+    Failing,
+    Unknown,
+}
+
+impl BadgeState {
+    fn from_steps(steps: &[StepRun]) -> Self {
+        if steps.iter().any(|s| s.success == Some(false)) {
+            Self::Failing
+        } else if steps.iter().all(|s| s.success == Some(true)) {
+            Self::Passing
+        } else {
+            Self::Unknown
+        }
+    }
+
+    fn text(self) -> &'static str {
+        match self {
+            Self::Passing => "passing",
+            Self::Failing => "failing",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    fn colour(self) -> &'static str {
+        match self {
+            Self::Passing => "#4c1",
+            Self::Failing => "#e05d44",
+            Self::Unknown => "#9f9f9f",
+        }
+    }
+}
+
+/// Rough width of some text in 11px Verdana, which is close enough as
+/// `textLength` squeezes or stretches it to fit.
+fn text_width(text: &str) -> usize {
+    text.chars()
+        .map(|c| match c {
+            'i' | 'j' | 'l' | '.' | ',' | ':' | ';' | '\'' | '|' | '!' => 4,
+            'f' | 'r' | 't' | '(' | ')' | '-' | '/' | ' ' => 5,
+            'm' | 'w' | 'M' | 'W' => 10,
+            c if c.is_uppercase() => 8,
+            _ => 7,
+        })
+        .sum()
+}
+
+/// Render a badge in the usual flat "label | state" style.
+fn render(label: &str, state: BadgeState) -> Response {
+    let value = state.text();
+    let (label_width, value_width) = (text_width(label) + 10, text_width(value) + 10);
+    let width = label_width + value_width;
+    // Text is positioned in tenths of a pixel, at 10x scale
+    let (label_x, value_x) = (label_width * 5, label_width * 10 + value_width * 5);
+    let (label_len, value_len) = ((label_width - 10) * 10, (value_width - 10) * 10);
+
+    let svg = format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {value}">
+<title>{label}: {value}</title>
+<linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient>
+<clipPath id="r"><rect width="{width}" height="20" rx="3" fill="#fff"/></clipPath>
+<g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="#555"/><rect x="{label_width}" width="{value_width}" height="20" fill="{colour}"/><rect width="{width}" height="20" fill="url(#s)"/></g>
+<g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="110">
+<text x="{label_x}" y="150" fill="#010101" fill-opacity=".3" transform="scale(.1)" textLength="{label_len}">{label}</text>
+<text x="{label_x}" y="140" transform="scale(.1)" textLength="{label_len}">{label}</text>
+<text x="{value_x}" y="150" fill="#010101" fill-opacity=".3" transform="scale(.1)" textLength="{value_len}">{value}</text>
+<text x="{value_x}" y="140" transform="scale(.1)" textLength="{value_len}">{value}</text>
+</g>
+</svg>
+"##,
+        label = Escaped(label),
+        colour = state.colour(),
+    );
+
+    (
+        [
+            (header::CONTENT_TYPE, "image/svg+xml"),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        svg,
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PipelineBadgeParams {
+    #[serde(default)]
+    branch: Option<String>,
+    #[serde(default)]
+    label: Option<String>,
+}
+
+pub async fn handle_pipeline_badge(
+    State(state): State<AppState>,
+    Path(pipeline): Path<String>,
+    Query(params): Query<PipelineBadgeParams>,
+) -> Result<Response, HTTPHandlingError> {
+    let filter = BuildFilter {
+        pipeline: Some(pipeline),
+        branch: params.branch.filter(|b| !b.is_empty()),
+        commit_sha: None,
+        limit: PIPELINE_BUILDS_LIMIT,
+    };
+    let builds = http::find_builds(&state, &filter).await?;
+    let badge_state = builds
+        .iter()
+        .find(|b| !b.steps.is_empty())
+        .map_or(BadgeState::Unknown, |b| BadgeState::from_steps(&b.steps));
+
+    Ok(render(
+        params.label.as_deref().unwrap_or("build"),
+        badge_state,
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct DerivationBadgeParams {
+    #[serde(default)]
+    hash: Option<String>,
+    #[serde(default)]
+    tag: Option<String>,
+    #[serde(default)]
+    label: Option<String>,
+}
+
+pub async fn handle_derivation_badge(
+    State(state): State<AppState>,
+    Query(params): Query<DerivationBadgeParams>,
+) -> Result<Response, HTTPHandlingError> {
+    let filter = match (params.hash, params.tag) {
+        (Some(hash), None) => HistoryFilter::Hash(hash),
+        (None, Some(tag)) => HistoryFilter::Tag(tag),
+        _ => return Err(HTTPHandlingError::InvalidHistoryQuery),
+    };
+    let records = state.store.history(&filter).await?;
+    let badge_state = match records.iter().find_map(|r| r.success) {
+        Some(true) => BadgeState::Passing,
+        Some(false) => BadgeState::Failing,
+        None => BadgeState::Unknown,
+    };
+
+    let label = match (params.label, &filter) {
+        (Some(label), _) => label,
+        (None, HistoryFilter::Tag(tag)) => tag.clone(),
+        (None, HistoryFilter::Hash(hash)) => hash.clone(),
+    };
+
+    Ok(render(&label, badge_state))
+}
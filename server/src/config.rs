@@ -0,0 +1,365 @@
+// Server settings can come from command-line flags, environment variables, or
+// a TOML config file, in that order of precedence. Every setting is optional
+// at each level, defaults are only applied once all sources are merged.
+//
+// Example config file:
+//
+//     store = "postgres"
+//     listen_address = "0.0.0.0"
+//     port = 8080
+//
+//     [postgres]
+//     host = "db.internal"
+//     user = "ci"
+//     dbname = "ci"
+//     tls_mode = "require"
+//
+//     [postgres.pool]
+//     max_size = 16
+//     connection_timeout_secs = 10
+//
+//     [retention]
+//     keep_per_hash = 10
+//     failure_max_age_days = 30
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bb8::ManageConnection;
+use chrono::{DateTime, Utc};
+use clap::{Args, ValueEnum};
+use rustls::pki_types::CertificateDer;
+use rustls::{ClientConfig, RootCertStore};
+use serde::Deserialize;
+use tokio_postgres::config::SslMode;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::store::RetentionPolicy;
+
+pub const DEFAULT_LISTEN_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+pub const DEFAULT_PORT: u16 = 1234;
+pub const DEFAULT_DB_HOST: &str = "localhost";
+pub const DEFAULT_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("error reading config file: {0}")]
+    ReadingFile(std::io::Error),
+    #[error("error parsing config file: {0}")]
+    ParsingFile(#[from] toml::de::Error),
+    #[error("missing required setting: {0}")]
+    MissingSetting(&'static str),
+    #[error("error reading CA certificate file: {0}")]
+    ReadingCaFile(std::io::Error),
+    #[error("error loading CA certificate: {0}")]
+    LoadingCa(#[from] rustls::Error),
+}
+
+#[derive(Clone, Copy, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    Postgres,
+    Sqlite,
+    /// Keep records in memory only, losing them on exit
+    Memory,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Never use TLS
+    #[default]
+    Disable,
+    /// Use TLS if the server supports it
+    Prefer,
+    /// Fail if the server doesn't support TLS
+    Require,
+}
+
+#[derive(Args, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Backend to store build records in [default: postgres]
+    #[arg(long, env = "CI_SERVER_STORE", value_enum)]
+    pub store: Option<StoreKind>,
+
+    /// Path to the database file (sqlite store only)
+    #[arg(long, env = "CI_SERVER_SQLITE_PATH")]
+    pub sqlite_path: Option<PathBuf>,
+
+    /// Address to listen for HTTP requests on [default: 127.0.0.1]
+    #[arg(long, env = "CI_SERVER_LISTEN_ADDRESS")]
+    pub listen_address: Option<IpAddr>,
+
+    /// Port to listen for HTTP requests on [default: 1234]
+    #[arg(long, env = "CI_SERVER_PORT")]
+    pub port: Option<u16>,
+
+    /// Token shared with Buildkite to authenticate webhooks (which are
+    /// rejected if unset)
+    #[arg(long, env = "CI_SERVER_WEBHOOK_TOKEN", hide_env_values = true)]
+    pub webhook_token: Option<String>,
+
+    #[command(flatten)]
+    pub postgres: PostgresSettings,
+
+    #[command(flatten)]
+    pub retention: RetentionSettings,
+}
+
+#[derive(Args, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PostgresSettings {
+    /// Hostname of the database server [default: localhost]
+    #[arg(short = 'a', long = "db-addr", env = "CI_SERVER_DB_ADDRESS")]
+    pub host: Option<String>,
+
+    /// Port of the database server [default: 5432]
+    #[arg(id = "db_port", long = "db-port", env = "CI_SERVER_DB_PORT")]
+    pub port: Option<u16>,
+
+    /// Directory containing the database server's unix socket, to connect
+    /// through instead of TCP (TLS settings are ignored)
+    #[arg(long = "db-socket-dir", env = "CI_SERVER_DB_SOCKET_DIR")]
+    pub socket_dir: Option<PathBuf>,
+
+    #[arg(short = 'u', long = "db-user", env = "CI_SERVER_DB_USER")]
+    pub user: Option<String>,
+
+    #[arg(short = 'p', env = "CI_SERVER_DB_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+
+    #[arg(short = 'n', long = "db-name", env = "CI_SERVER_DB_NAME")]
+    pub dbname: Option<String>,
+
+    /// Whether to connect to the database with TLS [default: disable]
+    #[arg(long = "db-tls-mode", env = "CI_SERVER_DB_TLS_MODE", value_enum)]
+    pub tls_mode: Option<TlsMode>,
+
+    /// PEM file of CA certificates to trust for database TLS, in addition to
+    /// the usual web PKI roots
+    #[arg(long = "db-tls-ca-file", env = "CI_SERVER_DB_TLS_CA_FILE")]
+    pub tls_ca_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub pool: PoolSettings,
+}
+
+#[derive(Args, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolSettings {
+    /// Maximum number of database connections [default: 10]
+    #[arg(long = "db-pool-size", env = "CI_SERVER_DB_POOL_SIZE")]
+    pub max_size: Option<u32>,
+
+    /// Minimum number of idle database connections to keep open
+    #[arg(long = "db-pool-min-idle", env = "CI_SERVER_DB_POOL_MIN_IDLE")]
+    pub min_idle: Option<u32>,
+
+    /// Seconds to wait for a database connection before failing a request
+    /// [default: 30]
+    #[arg(long = "db-connect-timeout", env = "CI_SERVER_DB_CONNECT_TIMEOUT")]
+    pub connection_timeout_secs: Option<u64>,
+
+    /// Seconds after which idle database connections are closed
+    #[arg(long = "db-idle-timeout", env = "CI_SERVER_DB_IDLE_TIMEOUT")]
+    pub idle_timeout_secs: Option<u64>,
+
+    /// Maximum lifetime of a database connection, in seconds
+    #[arg(long = "db-max-lifetime", env = "CI_SERVER_DB_MAX_LIFETIME")]
+    pub max_lifetime_secs: Option<u64>,
+}
+
+/// How long to keep build records. Nothing is pruned unless at least one rule
+/// is set.
+#[derive(Args, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionSettings {
+    /// Keep only this many of the latest finished build records of each
+    /// derivation
+    #[arg(
+        long = "retention-keep-per-hash",
+        env = "CI_SERVER_RETENTION_KEEP_PER_HASH"
+    )]
+    pub keep_per_hash: Option<u32>,
+
+    /// Delete failed builds after this many days
+    #[arg(
+        long = "retention-failure-max-age-days",
+        env = "CI_SERVER_RETENTION_FAILURE_MAX_AGE_DAYS"
+    )]
+    pub failure_max_age_days: Option<u32>,
+
+    /// Seconds between prunes while serving [default: 3600]
+    #[arg(long = "prune-interval", env = "CI_SERVER_PRUNE_INTERVAL")]
+    pub prune_interval_secs: Option<u64>,
+}
+
+impl Settings {
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let data = std::fs::read_to_string(path).map_err(ConfigError::ReadingFile)?;
+        Ok(toml::from_str(&data)?)
+    }
+
+    /// Fill in any settings that aren't set here from `fallback`.
+    pub fn or(self, fallback: Self) -> Self {
+        Self {
+            store: self.store.or(fallback.store),
+            sqlite_path: self.sqlite_path.or(fallback.sqlite_path),
+            listen_address: self.listen_address.or(fallback.listen_address),
+            port: self.port.or(fallback.port),
+            webhook_token: self.webhook_token.or(fallback.webhook_token),
+            postgres: self.postgres.or(fallback.postgres),
+            retention: self.retention.or(fallback.retention),
+        }
+    }
+
+    pub fn store_kind(&self) -> StoreKind {
+        self.store.unwrap_or(StoreKind::Postgres)
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        let address = self.listen_address.unwrap_or(DEFAULT_LISTEN_ADDRESS);
+        SocketAddr::new(address, self.port.unwrap_or(DEFAULT_PORT))
+    }
+}
+
+impl PostgresSettings {
+    fn or(self, fallback: Self) -> Self {
+        Self {
+            host: self.host.or(fallback.host),
+            port: self.port.or(fallback.port),
+            socket_dir: self.socket_dir.or(fallback.socket_dir),
+            user: self.user.or(fallback.user),
+            password: self.password.or(fallback.password),
+            dbname: self.dbname.or(fallback.dbname),
+            tls_mode: self.tls_mode.or(fallback.tls_mode),
+            tls_ca_file: self.tls_ca_file.or(fallback.tls_ca_file),
+            pool: self.pool.or(fallback.pool),
+        }
+    }
+
+    pub fn connection_config(&self) -> Result<tokio_postgres::Config, ConfigError> {
+        let user = self.user.as_ref();
+        let dbname = self.dbname.as_ref();
+
+        let mut config = tokio_postgres::Config::new();
+        config
+            .user(user.ok_or(ConfigError::MissingSetting("--db-user or postgres.user"))?)
+            .dbname(dbname.ok_or(ConfigError::MissingSetting("--db-name or postgres.dbname"))?);
+
+        if let Some(password) = &self.password {
+            config.password(password);
+        }
+
+        match &self.socket_dir {
+            Some(dir) => config.host_path(dir),
+            None => config.host(self.host.as_deref().unwrap_or(DEFAULT_DB_HOST)),
+        };
+
+        if let Some(port) = self.port {
+            config.port(port);
+        }
+
+        let ssl_mode = match self.tls_mode.unwrap_or_default() {
+            TlsMode::Disable => SslMode::Disable,
+            TlsMode::Prefer => SslMode::Prefer,
+            TlsMode::Require => SslMode::Require,
+        };
+        config.ssl_mode(ssl_mode);
+
+        Ok(config)
+    }
+
+    /// Connector for database TLS (only used if TLS is enabled by
+    /// `tls_mode`).
+    pub fn tls_connector(&self) -> Result<MakeRustlsConnect, ConfigError> {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+        if let Some(path) = &self.tls_ca_file {
+            let data = std::fs::read(path).map_err(ConfigError::ReadingCaFile)?;
+            let certs: Vec<CertificateDer> = rustls_pemfile::certs(&mut data.as_slice())
+                .collect::<Result<_, _>>()
+                .map_err(ConfigError::ReadingCaFile)?;
+            for cert in certs {
+                roots.add(cert)?;
+            }
+        }
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(MakeRustlsConnect::new(config))
+    }
+}
+
+impl RetentionSettings {
+    fn or(self, fallback: Self) -> Self {
+        Self {
+            keep_per_hash: self.keep_per_hash.or(fallback.keep_per_hash),
+            failure_max_age_days: self.failure_max_age_days.or(fallback.failure_max_age_days),
+            prune_interval_secs: self.prune_interval_secs.or(fallback.prune_interval_secs),
+        }
+    }
+
+    /// The policy to prune with at `now`, or `None` if none is configured.
+    pub fn policy(&self, now: DateTime<Utc>) -> Option<RetentionPolicy> {
+        let failures_before = self.failure_max_age_days.map(|days| {
+            chrono::Duration::try_days(days.into())
+                .and_then(|age| now.checked_sub_signed(age))
+                .unwrap_or(DateTime::<Utc>::MIN_UTC)
+        });
+        let policy = RetentionPolicy {
+            keep_per_hash: self.keep_per_hash,
+            failures_before,
+        };
+
+        Some(policy).filter(|p| !p.is_empty())
+    }
+
+    pub fn prune_interval(&self) -> Duration {
+        self.prune_interval_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_PRUNE_INTERVAL)
+    }
+}
+
+impl PoolSettings {
+    fn or(self, fallback: Self) -> Self {
+        Self {
+            max_size: self.max_size.or(fallback.max_size),
+            min_idle: self.min_idle.or(fallback.min_idle),
+            connection_timeout_secs: self
+                .connection_timeout_secs
+                .or(fallback.connection_timeout_secs),
+            idle_timeout_secs: self.idle_timeout_secs.or(fallback.idle_timeout_secs),
+            max_lifetime_secs: self.max_lifetime_secs.or(fallback.max_lifetime_secs),
+        }
+    }
+
+    /// A pool builder with these settings applied (leaving bb8's defaults for
+    /// anything unset).
+    pub fn builder<M: ManageConnection>(&self) -> bb8::Builder<M> {
+        let mut builder = bb8::Pool::builder().min_idle(self.min_idle);
+        if let Some(size) = self.max_size {
+            builder = builder.max_size(size);
+        }
+        if let Some(secs) = self.connection_timeout_secs {
+            builder = builder.connection_timeout(Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.idle_timeout_secs {
+            builder = builder.idle_timeout(Some(Duration::from_secs(secs)));
+        }
+        if let Some(secs) = self.max_lifetime_secs {
+            builder = builder.max_lifetime(Some(Duration::from_secs(secs)));
+        }
+
+        builder
+    }
+}
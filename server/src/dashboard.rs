@@ -0,0 +1,299 @@
+// A read-only HTML dashboard of what the server knows, for people rather than
+// the `ci` tool:
+//  - GET /dashboard?pipeline=<slug>&branch=<branch>
+//    Recent CI builds, grouped by pipeline and branch
+//  - GET /dashboard/history?hash=<hash>|tag=<tag>
+//    Past builds of a derivation, or of every derivation built as a flake
+//    attribute, with statistics
+//  - GET /dashboard/failing
+//    Targets whose latest finished build failed
+//
+// Pages are rendered on the server with plain `format!`s, so anything from the
+// database must be interpolated through `Escaped`, and there's nothing to
+// build on the client side.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Write};
+
+use api::BuildSteps;
+use axum::extract::{Query, State};
+use axum::response::{Html, Redirect};
+use chrono::{DateTime, Utc};
+
+use crate::history::build_history;
+use crate::http::{self, AppState, BuildsParams, HTTPHandlingError, HistoryParams};
+use crate::store::{BuildRecord, HistoryFilter, StepRun};
+
+/// How many builds the front page lists, if not given.
+const DEFAULT_BUILDS_LIMIT: u32 = 50;
+
+const STYLE: &str = r#"
+body { font-family: system-ui, sans-serif; margin: 2em auto; max-width: 70em; padding: 0 1em; }
+nav a { margin-right: 1em; }
+table { border-collapse: collapse; width: 100%; margin-bottom: 2em; }
+th, td { text-align: left; padding: 0.3em 0.6em; border-bottom: 1px solid #ddd; }
+code { font-size: 0.9em; }
+.passed { color: #1a7f37; }
+.failed { color: #cf222e; }
+.unfinished { color: #777; }
+dl { display: grid; grid-template-columns: max-content auto; gap: 0.3em 1em; }
+dd { margin: 0; }
+"#;
+
+/// HTML-escapes a string when displayed.
+pub(crate) struct Escaped<'a>(pub(crate) &'a str);
+
+impl Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => f.write_str("&amp;")?,
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                '"' => f.write_str("&quot;")?,
+                '\'' => f.write_str("&#39;")?,
+                c => f.write_char(c)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn page(title: &str, body: &str) -> Html<String> {
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{title} · CI</title>
+<style>{STYLE}</style>
+</head>
+<body>
+<nav><a href="/dashboard">Builds</a><a href="/dashboard/failing">Failing</a></nav>
+<h1>{title}</h1>
+{body}
+</body>
+</html>
+"#,
+        title = Escaped(title),
+    ))
+}
+
+/// A link to the history page of `filter`.
+fn history_link(filter: &HistoryFilter, text: &str) -> String {
+    let (param, value) = match filter {
+        HistoryFilter::Hash(hash) => ("hash", hash.as_str()),
+        HistoryFilter::Tag(tag) => ("tag", tag.as_str()),
+    };
+    let value: String = form_urlencoded::byte_serialize(value.as_bytes()).collect();
+
+    format!(
+        r#"<a href="/dashboard/history?{param}={value}">{}</a>"#,
+        Escaped(text)
+    )
+}
+
+/// A link to the history of whatever target a build record was built as.
+fn target_link(record: &BuildRecord) -> String {
+    match &record.tag {
+        Some(tag) => history_link(&HistoryFilter::Tag(tag.clone()), tag),
+        None => history_link(&HistoryFilter::Hash(record.hash.clone()), &record.hash),
+    }
+}
+
+fn outcome(success: Option<bool>) -> &'static str {
+    match success {
+        Some(true) => r#"<span class="passed">passed</span>"#,
+        Some(false) => r#"<span class="failed">failed</span>"#,
+        None => r#"<span class="unfinished">unfinished</span>"#,
+    }
+}
+
+fn time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+fn duration(secs: Option<i64>) -> String {
+    match secs {
+        None => "–".to_string(),
+        Some(secs) if secs >= 3600 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        Some(secs) if secs >= 60 => format!("{}m {}s", secs / 60, secs % 60),
+        Some(secs) => format!("{secs}s"),
+    }
+}
+
+pub async fn handle_root() -> Redirect {
+    Redirect::to("/dashboard")
+}
+
+pub async fn handle_builds(
+    State(state): State<AppState>,
+    Query(params): Query<BuildsParams>,
+) -> Result<Html<String>, HTTPHandlingError> {
+    let filter = params.into_filter(DEFAULT_BUILDS_LIMIT);
+    let builds = http::find_builds(&state, &filter).await?;
+
+    let mut body = format!(
+        r#"<form>
+<input name="pipeline" placeholder="pipeline" value="{}">
+<input name="branch" placeholder="branch" value="{}">
+<button>Filter</button>
+</form>
+"#,
+        Escaped(filter.pipeline.as_deref().unwrap_or_default()),
+        Escaped(filter.branch.as_deref().unwrap_or_default()),
+    );
+    if builds.is_empty() {
+        body.push_str("<p>No builds recorded yet.</p>\n");
+    }
+
+    // Builds are newest first, and stay that way within each group
+    let mut groups: BTreeMap<(&str, Option<&str>), Vec<&BuildSteps>> = BTreeMap::new();
+    for b in &builds {
+        let key = (b.build.pipeline_slug.as_str(), b.build.branch.as_deref());
+        groups.entry(key).or_default().push(b);
+    }
+
+    for ((pipeline, branch), builds) in groups {
+        let branch = branch.unwrap_or("(no branch)");
+        body.push_str(&format!(
+            "<h2>{} / {}</h2>\n",
+            Escaped(pipeline),
+            Escaped(branch)
+        ));
+        body.push_str(
+            "<table>\n<tr><th>Started</th><th>Commit</th><th>Tag</th><th>Steps</th><th></th></tr>\n",
+        );
+        for b in builds {
+            let commit: String = b.build.commit_sha.chars().take(12).collect();
+            body.push_str(&format!(
+                r#"<tr><td>{}</td><td><code>{}</code></td><td>{}</td><td>{}</td><td><a href="{}">build</a></td></tr>"#,
+                time(b.build.created_at),
+                Escaped(&commit),
+                Escaped(b.build.tag.as_deref().unwrap_or_default()),
+                step_summary(&b.steps),
+                Escaped(&b.build.url),
+            ));
+        }
+        body.push_str("</table>\n");
+    }
+
+    Ok(page("Recent builds", &body))
+}
+
+/// How many of a build's steps passed, and which failed.
+fn step_summary(steps: &[StepRun]) -> String {
+    let passed = steps.iter().filter(|s| s.success == Some(true)).count();
+    let mut summary = format!(r#"<span class="passed">{passed} passed</span>"#);
+
+    let failed: Vec<_> = steps.iter().filter(|s| s.success == Some(false)).collect();
+    if !failed.is_empty() {
+        let names: Vec<_> = failed
+            .iter()
+            .map(|s| {
+                let name = s.label.as_deref().unwrap_or(&s.step_key);
+                match &s.hash {
+                    Some(hash) => history_link(&HistoryFilter::Hash(hash.clone()), name),
+                    None => Escaped(name).to_string(),
+                }
+            })
+            .collect();
+        summary += &format!(
+            r#", <span class="failed">{} failed</span>: {}"#,
+            failed.len(),
+            names.join(", ")
+        );
+    }
+
+    summary
+}
+
+pub async fn handle_history(
+    State(state): State<AppState>,
+    Query(params): Query<HistoryParams>,
+) -> Result<Html<String>, HTTPHandlingError> {
+    let (filter, limit) = params.into_filter()?;
+    let records = state.store.history(&filter).await?;
+    let history = build_history(records, limit);
+    let title = match &filter {
+        HistoryFilter::Hash(hash) => format!("History of {hash}"),
+        HistoryFilter::Tag(tag) => format!("History of {tag}"),
+    };
+
+    let stats = &history.stats;
+    let last_build = |record: &Option<BuildRecord>| match record {
+        Some(r) => format!(
+            r#"<a href="{}">{}</a>"#,
+            Escaped(&r.build_url),
+            time(r.finished_at.unwrap_or(r.started_at))
+        ),
+        None => "never".to_string(),
+    };
+    let success_rate = match stats.success_rate {
+        Some(rate) => format!("{:.0}%", rate * 100.0),
+        None => "–".to_string(),
+    };
+    let mut body = format!(
+        r#"<dl>
+<dt>Builds</dt><dd>{} ({} finished)</dd>
+<dt>Success rate</dt><dd>{success_rate}</dd>
+<dt>Last success</dt><dd>{}</dd>
+<dt>Last failure</dt><dd>{}</dd>
+<dt>Median duration</dt><dd>{}</dd>
+<dt>95th percentile duration</dt><dd>{}</dd>
+</dl>
+"#,
+        stats.total,
+        stats.finished,
+        last_build(&stats.last_success),
+        last_build(&stats.last_failure),
+        duration(stats.p50_duration_secs),
+        duration(stats.p95_duration_secs),
+    );
+
+    if history.builds.is_empty() {
+        body.push_str("<p>No builds recorded.</p>\n");
+        return Ok(page(&title, &body));
+    }
+
+    body.push_str(
+        "<table>\n<tr><th>Started</th><th>Result</th><th>Duration</th><th>Derivation</th><th></th></tr>\n",
+    );
+    for entry in &history.builds {
+        let r = &entry.record;
+        body.push_str(&format!(
+            r#"<tr><td>{}</td><td>{}</td><td>{}</td><td><code>{}</code></td><td><a href="{}">build</a></td></tr>"#,
+            time(r.started_at),
+            outcome(r.success),
+            duration(entry.duration_secs),
+            history_link(&HistoryFilter::Hash(r.hash.clone()), &r.hash),
+            Escaped(&r.build_url),
+        ));
+    }
+    body.push_str("</table>\n");
+
+    Ok(page(&title, &body))
+}
+
+pub async fn handle_failing(
+    State(state): State<AppState>,
+) -> Result<Html<String>, HTTPHandlingError> {
+    let failing = state.store.failing_builds().await?;
+    if failing.is_empty() {
+        return Ok(page("Failing targets", "<p>Nothing is failing.</p>\n"));
+    }
+
+    let mut body = String::from("<table>\n<tr><th>Target</th><th>Failed at</th><th></th></tr>\n");
+    for r in &failing {
+        body.push_str(&format!(
+            r#"<tr><td>{}</td><td>{}</td><td><a href="{}">build</a></td></tr>"#,
+            target_link(r),
+            time(r.finished_at.unwrap_or(r.started_at)),
+            Escaped(&r.build_url),
+        ));
+    }
+    body.push_str("</table>\n");
+
+    Ok(page("Failing targets", &body))
+}
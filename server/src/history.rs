@@ -0,0 +1,67 @@
+// Build history of a derivation, or of every derivation built as a flake
+// attribute, with statistics for spotting slow or flaky builds.
+//
+// Statistics cover every recorded build, even when only the latest few are
+// listed. Durations are only taken from successful builds, since failures
+// often stop early and would make builds look faster than they are.
+
+use api::{BuildHistory, BuildRecord, BuildStats, HistoryEntry};
+
+/// Summarise `records` (newest first), listing at most `limit` of them.
+pub fn build_history(records: Vec<BuildRecord>, limit: usize) -> BuildHistory {
+    let stats = build_stats(&records);
+    let builds = records
+        .into_iter()
+        .take(limit)
+        .map(|record| HistoryEntry {
+            duration_secs: duration_secs(&record),
+            record,
+        })
+        .collect();
+
+    BuildHistory { builds, stats }
+}
+
+fn build_stats(records: &[BuildRecord]) -> BuildStats {
+    let finished: Vec<_> = records.iter().filter(|r| r.success.is_some()).collect();
+    let successes: Vec<_> = finished
+        .iter()
+        .filter(|r| r.success == Some(true))
+        .collect();
+    let last = |success: bool| {
+        let found = finished.iter().find(|r| r.success == Some(success));
+        found.map(|r| (*r).clone())
+    };
+
+    let mut durations: Vec<i64> = successes.iter().filter_map(|r| duration_secs(r)).collect();
+    durations.sort_unstable();
+
+    BuildStats {
+        total: records.len(),
+        finished: finished.len(),
+        succeeded: successes.len(),
+        success_rate: match finished.len() {
+            0 => None,
+            n => Some(successes.len() as f64 / n as f64),
+        },
+        last_success: last(true),
+        last_failure: last(false),
+        p50_duration_secs: percentile(&durations, 50),
+        p95_duration_secs: percentile(&durations, 95),
+    }
+}
+
+fn duration_secs(record: &BuildRecord) -> Option<i64> {
+    Some(
+        (record.finished_at? - record.started_at)
+            .num_seconds()
+            .max(0),
+    )
+}
+
+/// The `p`th percentile of sorted `values`, by the nearest-rank method (so
+/// it's always one of the values).
+fn percentile(values: &[i64], p: usize) -> Option<i64> {
+    let rank = (values.len() * p).div_ceil(100).max(1);
+    values.get(rank - 1).copied()
+}
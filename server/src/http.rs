@@ -1,72 +1,742 @@
-// API:
-//  (NOTE: This is not a particularly RESTful API, but for these purposes it
-//  doesn't need to be.)
-//  - GET /derivation-builds
+// API (all routes are prefixed with the API version, e.g. `/v1`):
+//  - GET /derivation-builds?hashes=<hash>,<hash>,...
 //    Return previous builds of the given derivations
-//    (JSON body in GET request defines hashes to query for)
+//  - POST /derivation-builds/query
+//    As above, but with hashes given in a JSON body (for large queries)
 //  - POST /derivation-builds
-//    Record new derivation builds in the database
+//    Record the start of a new derivation build
+//  - GET /derivation-builds/history?hash=<hash>|tag=<tag>[&limit=<n>]
+//    Return the latest builds of a derivation, or of every derivation built
+//    as a flake attribute, with success rates and durations
+//  - GET /derivation-builds/wait?hashes=<hash>,...[&since=<time>][&timeout_secs=<n>]
+//    Wait until each of the given derivations has a build that finished after
+//    `since` (or ever), or until the timeout, then return those builds
+//  - PUT /derivation-builds/:hash/:build_id
+//    Record the result of a previously-started derivation build
+//  - POST /build-leases/:hash
+//    Claim (or renew) the lease on building a derivation, returning whichever
+//    build holds the lease afterwards
+//  - GET /build-leases/:hash
+//    Return the unexpired lease on building a derivation
+//  - DELETE /build-leases/:hash/:build_id
+//    Release a lease before it expires
+//  - POST /scheduled-builds
+//    Record which derivation each step of a CI build is building
+//  - PUT /builds/:build_id
+//    Record a CI build of the token's pipeline, and which commit it's building
+//  - PUT /builds/:build_id/steps/:step_key
+//    Record the outcome of a step of a previously-recorded CI build
+//  - GET /builds?pipeline=<slug>&branch=<branch>&commit_sha=<sha>[&limit=<n>]
+//    Return the latest CI builds matching all of the given filters, with the
+//    outcomes of their steps
+//  - POST /webhooks/buildkite
+//    Receive Buildkite webhooks, recording the results of scheduled builds
+//
+// Unversioned operational routes:
+//  - GET /metrics
+//    Prometheus metrics
+//  - GET /healthz
+//    Succeeds as long as the server is running
+//  - GET /readyz
+//    Succeeds if the database is reachable and fully migrated
+//
+// The HTML dashboard is served under `/dashboard` (see `dashboard`), and
+// status badges under `/badges` (see `badge`).
+//
+// Writes must be authenticated with an `Authorization: Bearer <token>` header,
+// and are recorded against the token's pipeline. Webhooks are authenticated
+// with the webhook token instead (see `webhook`).
+//
+// Every response has an `X-Request-Id` header (taken from the request if the
+// client sent one). Errors have a JSON body of the form:
+//
+//     {"error": {"code": "not_found", "message": "..."}, "request_id": "..."}
+//
+// Errors with a 5xx status are worth retrying, anything else won't succeed
+// without changing the request.
 
-use axum::body::Body;
-use axum::extract::{Json, State};
-use axum::http::{Response, StatusCode};
-use axum::response::IntoResponse;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::store::{BuildRecord, Store, StoreError};
+use api::{
+    BuildHistory, BuildSteps, ErrorCode, ErrorDetails, ErrorResponse, QueryBody,
+    MAX_LEASE_TTL_SECS, MAX_QUERY_HASHES, MAX_WAIT_SECS,
+};
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{
+    FromRequest, FromRequestParts, Json, MatchedPath, Path, Query, Request, State,
+};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::auth::hash_token;
+use crate::history::build_history;
+use crate::metrics;
+use crate::migrations;
+use crate::store::{
+    BuildFilter, BuildLease, BuildRecord, BuildReport, BuildResult, BuildSchedule, HistoryFilter,
+    LeaseClaim, StepResult, StepRun, Store, StoreError,
+};
+use crate::webhook::{self, WebhookError};
+
+/// How many builds are listed in a history, if not given.
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+/// Upper limit on the number of builds listed in a history. Statistics still
+/// cover every build.
+const MAX_HISTORY_LIMIT: usize = 1000;
+/// How long to wait for builds to finish, if not given.
+const DEFAULT_WAIT_SECS: u64 = 30;
+/// How many CI builds are listed, if not given.
+const DEFAULT_BUILDS_LIMIT: u32 = 20;
+/// Upper limit on the number of CI builds listed at once.
+const MAX_BUILDS_LIMIT: u32 = 100;
 
 #[derive(Clone)]
 pub struct AppState {
-    store: Store,
+    pub(crate) store: Arc<dyn Store>,
+    /// Shared with Buildkite to authenticate webhooks, which are rejected if
+    /// unset
+    webhook_token: Option<Arc<str>>,
 }
 
 impl AppState {
-    pub fn new(store: Store) -> Self {
-        Self { store }
+    pub fn new(store: Arc<dyn Store>, webhook_token: Option<Arc<str>>) -> Self {
+        Self {
+            store,
+            webhook_token,
+        }
     }
 }
 
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
 #[derive(thiserror::Error, Debug)]
 pub enum HTTPHandlingError {
     #[error("db error: {0}")]
     StoreError(#[from] StoreError),
+    #[error("too many hashes in query ({0}, max {MAX_QUERY_HASHES})")]
+    TooManyHashes(usize),
+    #[error("missing or invalid API token")]
+    Unauthorized,
+    #[error("invalid request body: {}", .0.body_text())]
+    InvalidBody(#[from] JsonRejection),
+    #[error("exactly one of `hash` or `tag` must be given")]
+    InvalidHistoryQuery,
+    #[error("no such route")]
+    NoRoute,
+    #[error("lease TTL must be between 1 and {MAX_LEASE_TTL_SECS} seconds (got {0})")]
+    InvalidLeaseTtl(u64),
+    #[error("no active lease on this derivation")]
+    NoLease,
+    #[error("webhooks are not enabled on this server")]
+    WebhooksDisabled,
+    #[error("{0}")]
+    InvalidWebhook(#[from] WebhookError),
+    #[error("invalid webhook body: {0}")]
+    InvalidWebhookBody(serde_json::Error),
+    #[error("database has {0} pending migrations")]
+    MigrationsPending(usize),
+}
+
+/// An error response, before the request ID is filled in.
+#[derive(Clone)]
+struct FailedRequest {
+    error: ErrorDetails,
+    /// The full error, for the server log
+    cause: String,
+}
+
+impl HTTPHandlingError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            Self::StoreError(e) => match e {
+                StoreError::UpdateMissingEntry => ErrorCode::NotFound,
+                StoreError::ConnectionTimeout | StoreError::SchemaTooNew { .. } => {
+                    ErrorCode::Unavailable
+                }
+                StoreError::DatabaseError(e) if e.is_closed() => ErrorCode::Unavailable,
+                StoreError::SqliteError(rusqlite::Error::SqliteFailure(e, _))
+                    if matches!(
+                        e.code,
+                        rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+                    ) =>
+                {
+                    ErrorCode::Unavailable
+                }
+                StoreError::DatabaseError(_)
+                | StoreError::SqliteError(_)
+                | StoreError::TaskFailed(_) => ErrorCode::Internal,
+            },
+            Self::TooManyHashes(_) => ErrorCode::TooManyHashes,
+            Self::Unauthorized | Self::InvalidWebhook(_) => ErrorCode::Unauthorized,
+            Self::InvalidBody(_) => ErrorCode::InvalidRequest,
+            Self::NoRoute | Self::NoLease | Self::WebhooksDisabled => ErrorCode::NotFound,
+            Self::InvalidLeaseTtl(_) | Self::InvalidHistoryQuery | Self::InvalidWebhookBody(_) => {
+                ErrorCode::InvalidRequest
+            }
+            Self::MigrationsPending(_) => ErrorCode::Unavailable,
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match (self, self.code()) {
+            // Keep axum's distinction between syntax errors, missing fields
+            // and the wrong content type
+            (Self::InvalidBody(rejection), _) => rejection.status(),
+            (_, ErrorCode::InvalidRequest | ErrorCode::TooManyHashes) => StatusCode::BAD_REQUEST,
+            (_, ErrorCode::Unauthorized) => StatusCode::UNAUTHORIZED,
+            (_, ErrorCode::NotFound) => StatusCode::NOT_FOUND,
+            (_, ErrorCode::Unavailable) => StatusCode::SERVICE_UNAVAILABLE,
+            (_, ErrorCode::Internal | ErrorCode::Unknown) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 }
 
 impl IntoResponse for HTTPHandlingError {
-    fn into_response(self) -> axum::response::Response {
-        let b = match self {
-            Self::StoreError(_e) => Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR),
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let mut resp = status.into_response();
+        if let Self::Unauthorized = self {
+            resp.headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+
+        // Server errors may include details of the database that clients
+        // don't need to see, so they only go to the log
+        let message = match status.is_server_error() {
+            true => status.canonical_reason().unwrap_or_default().to_string(),
+            false => self.to_string(),
         };
+        resp.extensions_mut().insert(FailedRequest {
+            error: ErrorDetails {
+                code: self.code(),
+                message,
+            },
+            cause: self.to_string(),
+        });
+
+        resp
+    }
+}
+
+/// Middleware that tags every response with a request ID, and fills in the
+/// body of error responses (which need the ID).
+pub async fn handle_request_id(req: Request, next: Next) -> Response {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= MAX_REQUEST_ID_LEN)
+        .map(String::from)
+        .unwrap_or_else(generate_request_id);
+
+    let mut resp = next.run(req).await;
+
+    if let Some(failed) = resp.extensions_mut().remove::<FailedRequest>() {
+        if resp.status().is_server_error() {
+            eprintln!("request {request_id} failed: {}", failed.cause);
+        }
+
+        let (mut parts, _) = resp.into_parts();
+        parts.headers.remove(header::CONTENT_LENGTH);
+        let body = Json(ErrorResponse {
+            error: failed.error,
+            request_id: request_id.clone(),
+        });
+        let (body_parts, body) = body.into_response().into_parts();
+        parts.headers.extend(body_parts.headers);
+        resp = Response::from_parts(parts, body);
+    }
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        resp.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    resp
+}
+
+/// Middleware that records metrics for each request to a known route.
+pub async fn track_metrics(req: Request, next: Next) -> Response {
+    let method = req.method().clone();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_default();
+
+    let start = Instant::now();
+    let resp = next.run(req).await;
+    metrics::record_request(
+        method.as_str(),
+        &route,
+        resp.status().as_u16(),
+        start.elapsed(),
+    );
+
+    resp
+}
+
+fn generate_request_id() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Like `Json`, but rejects bad request bodies with our error format.
+pub struct ApiJson<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for ApiJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = HTTPHandlingError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::from_request(req, state).await?;
+        Ok(Self(value))
+    }
+}
+
+/// A request made with a valid API token.
+pub struct Authenticated {
+    /// The pipeline the token is allowed to record builds for
+    pipeline: String,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Authenticated {
+    type Rejection = HTTPHandlingError;
 
-        b.body(Body::empty()).unwrap()
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let secret = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or(HTTPHandlingError::Unauthorized)?;
+
+        let token = state
+            .store
+            .find_token(&hash_token(secret.trim()))
+            .await?
+            .ok_or(HTTPHandlingError::Unauthorized)?;
+
+        Ok(Self {
+            pipeline: token.pipeline_slug,
+        })
     }
 }
 
-// TODO: retrieve body from request
-pub async fn handle_get(
-    State(mut state): State<AppState>,
-    Json(body): Json<Vec<String>>,
+#[derive(Deserialize)]
+pub struct QueryParams {
+    /// Comma-separated list of hashes
+    #[serde(default)]
+    hashes: String,
+}
+
+async fn query(state: &AppState, hashes: &[String]) -> Result<Vec<BuildRecord>, HTTPHandlingError> {
+    if hashes.len() > MAX_QUERY_HASHES {
+        return Err(HTTPHandlingError::TooManyHashes(hashes.len()));
+    }
+
+    let records = state.store.query(hashes).await?;
+    metrics::record_query(hashes.len(), &records);
+
+    Ok(records)
+}
+
+/// Parse a comma-separated list of hashes.
+fn split_hashes(hashes: &str) -> Vec<String> {
+    hashes
+        .split(',')
+        .filter(|h| !h.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+pub async fn handle_query_params(
+    State(state): State<AppState>,
+    Query(params): Query<QueryParams>,
 ) -> Result<Json<Vec<BuildRecord>>, HTTPHandlingError> {
-    let results = state.store.query(&body).await?;
+    let hashes = split_hashes(&params.hashes);
+    let results = query(&state, &hashes).await?;
 
     Ok(Json(results))
 }
 
-pub async fn handle_post(
-    State(mut state): State<AppState>,
-    Json(body): Json<BuildRecord>,
-) -> Result<(), HTTPHandlingError> {
-    state.store.insert_start(&body).await?;
+#[derive(Deserialize)]
+pub struct WaitParams {
+    /// Comma-separated list of hashes
+    #[serde(default)]
+    hashes: String,
+    #[serde(default)]
+    since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    timeout_secs: Option<u64>,
+}
 
-    Ok(())
+pub async fn handle_wait(
+    State(state): State<AppState>,
+    Query(params): Query<WaitParams>,
+) -> Result<Json<Vec<BuildRecord>>, HTTPHandlingError> {
+    let hashes = split_hashes(&params.hashes);
+    if hashes.len() > MAX_QUERY_HASHES {
+        return Err(HTTPHandlingError::TooManyHashes(hashes.len()));
+    }
+    let since = params.since.unwrap_or(DateTime::<Utc>::MIN_UTC);
+    let timeout = params
+        .timeout_secs
+        .unwrap_or(DEFAULT_WAIT_SECS)
+        .min(MAX_WAIT_SECS);
+
+    let records = state
+        .store
+        .wait_for_finish(&hashes, since, Duration::from_secs(timeout))
+        .await?;
+
+    Ok(Json(records))
+}
+
+pub async fn handle_query(
+    State(state): State<AppState>,
+    ApiJson(body): ApiJson<QueryBody>,
+) -> Result<Json<Vec<BuildRecord>>, HTTPHandlingError> {
+    let results = query(&state, &body.hashes).await?;
+
+    Ok(Json(results))
+}
+
+#[derive(Deserialize)]
+pub struct HistoryParams {
+    #[serde(default)]
+    hash: Option<String>,
+    #[serde(default)]
+    tag: Option<String>,
+    #[serde(default)]
+    limit: Option<usize>,
 }
 
-pub async fn handle_put(
-    State(mut state): State<AppState>,
-    Json(body): Json<BuildRecord>,
+impl HistoryParams {
+    /// Which builds to find the history of, and how many to list.
+    pub(crate) fn into_filter(self) -> Result<(HistoryFilter, usize), HTTPHandlingError> {
+        let filter = match (self.hash, self.tag) {
+            (Some(hash), None) => HistoryFilter::Hash(hash),
+            (None, Some(tag)) => HistoryFilter::Tag(tag),
+            _ => return Err(HTTPHandlingError::InvalidHistoryQuery),
+        };
+        let limit = self
+            .limit
+            .unwrap_or(DEFAULT_HISTORY_LIMIT)
+            .min(MAX_HISTORY_LIMIT);
+
+        Ok((filter, limit))
+    }
+}
+
+pub async fn handle_history(
+    State(state): State<AppState>,
+    Query(params): Query<HistoryParams>,
+) -> Result<Json<BuildHistory>, HTTPHandlingError> {
+    let (filter, limit) = params.into_filter()?;
+    let records = state.store.history(&filter).await?;
+
+    Ok(Json(build_history(records, limit)))
+}
+
+pub async fn handle_create(
+    State(state): State<AppState>,
+    auth: Authenticated,
+    ApiJson(body): ApiJson<BuildRecord>,
+) -> Result<StatusCode, HTTPHandlingError> {
+    state.store.insert_start(&auth.pipeline, &body).await?;
+
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn handle_finish(
+    State(state): State<AppState>,
+    auth: Authenticated,
+    Path((hash, build_id)): Path<(String, String)>,
+    ApiJson(body): ApiJson<BuildResult>,
+) -> Result<StatusCode, HTTPHandlingError> {
+    state
+        .store
+        .mark_finish(&auth.pipeline, &hash, &build_id, &body)
+        .await?;
+    metrics::record_build(body.success);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn handle_claim_lease(
+    State(state): State<AppState>,
+    auth: Authenticated,
+    Path(hash): Path<String>,
+    ApiJson(claim): ApiJson<LeaseClaim>,
+) -> Result<Json<BuildLease>, HTTPHandlingError> {
+    if !(1..=MAX_LEASE_TTL_SECS).contains(&claim.ttl_secs) {
+        return Err(HTTPHandlingError::InvalidLeaseTtl(claim.ttl_secs));
+    }
+
+    let lease = state
+        .store
+        .claim_lease(&auth.pipeline, &hash, &claim)
+        .await?;
+
+    Ok(Json(lease))
+}
+
+pub async fn handle_get_lease(
+    State(state): State<AppState>,
+    Path(hash): Path<String>,
+) -> Result<Json<BuildLease>, HTTPHandlingError> {
+    let lease = state.store.find_lease(&hash).await?;
+
+    Ok(Json(lease.ok_or(HTTPHandlingError::NoLease)?))
+}
+
+pub async fn handle_release_lease(
+    State(state): State<AppState>,
+    auth: Authenticated,
+    Path((hash, build_id)): Path<(String, String)>,
+) -> Result<StatusCode, HTTPHandlingError> {
+    state
+        .store
+        .release_lease(&auth.pipeline, &hash, &build_id)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn handle_schedule_builds(
+    State(state): State<AppState>,
+    auth: Authenticated,
+    ApiJson(schedule): ApiJson<BuildSchedule>,
+) -> Result<StatusCode, HTTPHandlingError> {
+    state
+        .store
+        .schedule_builds(&auth.pipeline, &schedule)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn handle_record_build(
+    State(state): State<AppState>,
+    auth: Authenticated,
+    Path(build_id): Path<String>,
+    ApiJson(report): ApiJson<BuildReport>,
+) -> Result<StatusCode, HTTPHandlingError> {
+    state
+        .store
+        .record_build(&auth.pipeline, &build_id, &report)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn handle_record_step_run(
+    State(state): State<AppState>,
+    auth: Authenticated,
+    Path((build_id, step_key)): Path<(String, String)>,
+    ApiJson(result): ApiJson<StepResult>,
+) -> Result<StatusCode, HTTPHandlingError> {
+    state
+        .store
+        .record_step_run(&auth.pipeline, &build_id, &step_key, &result)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+pub struct BuildsParams {
+    #[serde(default)]
+    pipeline: Option<String>,
+    #[serde(default)]
+    branch: Option<String>,
+    #[serde(default)]
+    commit_sha: Option<String>,
+    #[serde(default)]
+    limit: Option<u32>,
+}
+
+impl BuildsParams {
+    /// Which builds to find, listing `default_limit` if no limit was given.
+    pub(crate) fn into_filter(self, default_limit: u32) -> BuildFilter {
+        BuildFilter {
+            pipeline: self.pipeline.filter(|p| !p.is_empty()),
+            branch: self.branch.filter(|b| !b.is_empty()),
+            commit_sha: self.commit_sha.filter(|c| !c.is_empty()),
+            limit: self.limit.unwrap_or(default_limit).min(MAX_BUILDS_LIMIT),
+        }
+    }
+}
+
+pub async fn handle_find_builds(
+    State(state): State<AppState>,
+    Query(params): Query<BuildsParams>,
+) -> Result<Json<Vec<BuildSteps>>, HTTPHandlingError> {
+    let filter = params.into_filter(DEFAULT_BUILDS_LIMIT);
+    let builds = find_builds(&state, &filter).await?;
+
+    Ok(Json(builds))
+}
+
+/// Find the builds matching `filter`, with their steps.
+pub(crate) async fn find_builds(
+    state: &AppState,
+    filter: &BuildFilter,
+) -> Result<Vec<BuildSteps>, HTTPHandlingError> {
+    let builds = state.store.find_builds(filter).await?;
+    let ids: Vec<_> = builds.iter().map(|b| b.id.clone()).collect();
+    let mut steps: HashMap<String, Vec<StepRun>> = HashMap::new();
+    for run in state.store.find_step_runs(&ids).await? {
+        steps.entry(run.build_id.clone()).or_default().push(run);
+    }
+
+    let builds = builds
+        .into_iter()
+        .map(|build| BuildSteps {
+            steps: steps.remove(&build.id).unwrap_or_default(),
+            build,
+        })
+        .collect();
+
+    Ok(builds)
+}
+
+/// Record the result of a finished job, if it built a scheduled derivation
+/// and the result hasn't already been recorded (e.g., by `collect`).
+async fn record_job(
+    state: &AppState,
+    build: &webhook::Build,
+    pipeline: Option<&str>,
+    job: &webhook::Job,
 ) -> Result<(), HTTPHandlingError> {
-    state.store.mark_finish(&body).await?;
+    let (Some(step_key), Some(success)) = (&job.step_key, job.success()) else {
+        return Ok(());
+    };
+    let Some(scheduled) = state
+        .store
+        .find_scheduled_build(&build.id, step_key)
+        .await?
+    else {
+        return Ok(());
+    };
+    if pipeline.is_some_and(|p| p != scheduled.pipeline_slug) {
+        return Ok(());
+    }
+
+    let records = state
+        .store
+        .query(std::slice::from_ref(&scheduled.hash))
+        .await?;
+    let existing = records.iter().find(|r| r.build_id == build.id);
+    match existing {
+        Some(r) if r.finished_at.is_some() => return Ok(()),
+        // A step that waited on another build's lease passes without
+        // building anything
+        None if success && records.iter().any(|r| r.success == Some(true)) => return Ok(()),
+        _ => {}
+    }
+
+    let finished_at = job.finished_at.unwrap_or_else(Utc::now);
+    let started_at = job.started_at.unwrap_or(finished_at);
+    if existing.is_none() {
+        let record = BuildRecord {
+            hash: scheduled.hash.clone(),
+            build_id: build.id.clone(),
+            build_url: build.web_url.clone(),
+            started_at,
+            finished_at: None,
+            success: None,
+            tag: scheduled.tag.clone(),
+        };
+        state
+            .store
+            .insert_start(&scheduled.pipeline_slug, &record)
+            .await?;
+    }
+
+    let result = BuildResult {
+        started_at: Some(started_at),
+        finished_at,
+        success,
+    };
+    state
+        .store
+        .mark_finish(
+            &scheduled.pipeline_slug,
+            &scheduled.hash,
+            &build.id,
+            &result,
+        )
+        .await?;
+    metrics::record_build(success);
 
     Ok(())
 }
 
-// TODO: handle_post, handle_put
+pub async fn handle_buildkite_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, HTTPHandlingError> {
+    let token = state.webhook_token.as_deref();
+    let token = token.ok_or(HTTPHandlingError::WebhooksDisabled)?;
+    webhook::verify(token, &headers, &body, Utc::now())?;
+
+    let event: webhook::Event =
+        serde_json::from_slice(&body).map_err(HTTPHandlingError::InvalidWebhookBody)?;
+    if let Some(build) = &event.build {
+        let pipeline = event.pipeline.as_ref().map(|p| p.slug.as_str());
+        for job in event.finished_jobs() {
+            record_job(&state, build, pipeline, job).await?;
+        }
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn handle_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let content_type = [(header::CONTENT_TYPE, "text/plain; version=0.0.4")];
+    (content_type, metrics::render(state.store.pool_state()))
+}
+
+pub async fn handle_healthz() -> &'static str {
+    "ok"
+}
+
+pub async fn handle_readyz(
+    State(state): State<AppState>,
+) -> Result<&'static str, HTTPHandlingError> {
+    // Reading the schema version needs a database connection, so this also
+    // checks the database is reachable
+    let version = state.store.schema_version().await?;
+    let pending = migrations::pending(version)?;
+    if !pending.is_empty() {
+        return Err(HTTPHandlingError::MigrationsPending(pending.len()));
+    }
+
+    Ok("ok")
+}
+
+pub async fn handle_not_found() -> HTTPHandlingError {
+    HTTPHandlingError::NoRoute
+}
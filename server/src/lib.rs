@@ -1,17 +1,37 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use crate::http::AppState;
 use crate::store::Store;
 
-use axum::Router;
-use http::{handle_get, handle_post, handle_put};
+use axum::routing::{delete, get, post, put};
+use axum::{middleware, Router};
+use http::{
+    handle_buildkite_webhook, handle_claim_lease, handle_create, handle_find_builds, handle_finish,
+    handle_get_lease, handle_healthz, handle_history, handle_metrics, handle_not_found,
+    handle_query, handle_query_params, handle_readyz, handle_record_build, handle_record_step_run,
+    handle_release_lease, handle_request_id, handle_schedule_builds, handle_wait, track_metrics,
+};
+use tokio::signal::unix::{signal, SignalKind};
 
+pub mod auth;
+mod badge;
+pub mod config;
+mod dashboard;
+mod history;
 mod http;
+mod metrics;
+pub mod migrations;
+mod webhook;
+
+pub use api::{MAX_LEASE_TTL_SECS, MAX_QUERY_HASHES};
+pub use http::REQUEST_ID_HEADER;
 pub mod store;
 
 pub struct Server {
-    port: u16,
-    store: Store,
+    addr: SocketAddr,
+    store: Arc<dyn Store>,
+    webhook_token: Option<Arc<str>>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -23,27 +43,98 @@ pub enum HTTPServeError {
 }
 
 impl Server {
-    pub fn new(port: u16, store: Store) -> Self {
-        Self { port, store }
+    pub fn new(addr: SocketAddr, store: Arc<dyn Store>) -> Self {
+        Self {
+            addr,
+            store,
+            webhook_token: None,
+        }
+    }
+
+    /// Accept Buildkite webhooks authenticated with the given token.
+    pub fn with_webhook_token(mut self, token: &str) -> Self {
+        self.webhook_token = Some(token.into());
+        self
+    }
+
+    pub fn router(&self) -> Router {
+        let state = AppState::new(self.store.clone(), self.webhook_token.clone());
+        let v1 = Router::new()
+            .route(
+                "/derivation-builds",
+                get(handle_query_params).post(handle_create),
+            )
+            .route("/derivation-builds/query", post(handle_query))
+            .route("/derivation-builds/history", get(handle_history))
+            .route("/derivation-builds/wait", get(handle_wait))
+            .route("/derivation-builds/:hash/:build_id", put(handle_finish))
+            .route(
+                "/build-leases/:hash",
+                get(handle_get_lease).post(handle_claim_lease),
+            )
+            .route(
+                "/build-leases/:hash/:build_id",
+                delete(handle_release_lease),
+            )
+            .route("/scheduled-builds", post(handle_schedule_builds))
+            .route("/builds", get(handle_find_builds))
+            .route("/builds/:build_id", put(handle_record_build))
+            .route(
+                "/builds/:build_id/steps/:step_key",
+                put(handle_record_step_run),
+            )
+            .route("/webhooks/buildkite", post(handle_buildkite_webhook));
+
+        Router::new()
+            .route("/", get(dashboard::handle_root))
+            .route("/dashboard", get(dashboard::handle_builds))
+            .route("/dashboard/history", get(dashboard::handle_history))
+            .route("/dashboard/failing", get(dashboard::handle_failing))
+            .route(
+                "/badges/pipelines/:pipeline",
+                get(badge::handle_pipeline_badge),
+            )
+            .route("/badges/derivations", get(badge::handle_derivation_badge))
+            .nest("/v1", v1)
+            .route("/metrics", get(handle_metrics))
+            .route("/healthz", get(handle_healthz))
+            .route("/readyz", get(handle_readyz))
+            .route_layer(middleware::from_fn(track_metrics))
+            .fallback(handle_not_found)
+            .layer(middleware::from_fn(handle_request_id))
+            .with_state(state)
     }
 
+    /// Serve HTTP until the process is asked to stop (by SIGTERM or Ctrl-C),
+    /// then wait for in-flight requests to finish before returning.
     pub async fn run_http_server(&self) -> Result<(), HTTPServeError> {
-        let state = AppState::new(self.store.clone());
-        // TODO: actually route, and (probably) take bodies out of requests
-        // here?
-        let app = Router::new()
-            .route("/", axum::routing::get(handle_get))
-            .route("/", axum::routing::post(handle_post))
-            .route("/", axum::routing::put(handle_put))
-            .with_state(state);
-
-        let addr: SocketAddr = ([127, 0, 0, 1], self.port).into();
-        let listener = tokio::net::TcpListener::bind(addr)
+        let app = self.router();
+
+        let listener = tokio::net::TcpListener::bind(self.addr)
             .await
             .map_err(HTTPServeError::CreatingTCPSocket)?;
 
         axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown_signal())
             .await
             .map_err(HTTPServeError::Serving)
     }
 }
+
+/// Resolves once the process receives SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("error listening for SIGTERM, only Ctrl-C will shut down cleanly: {e}");
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    eprintln!("shutting down, waiting for in-flight requests to finish");
+}
@@ -1,53 +1,255 @@
-use bb8::Pool;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use chrono::Utc;
+
 use bb8_postgres::PostgresConnectionManager;
-use server::{store::Store, Server};
+use server::{
+    auth,
+    config::{ConfigError, RetentionSettings, Settings, StoreKind},
+    migrations,
+    store::{MemoryStore, PostgresStore, SqliteStore, Store, StoreError},
+    Server,
+};
 
-use clap::Parser;
-use tokio_postgres::{Config, NoTls};
+use clap::{Parser, Subcommand};
+use tokio_postgres::NoTls;
 
 #[derive(Parser)]
 struct Args {
-    #[arg(short = 'u', long, env = "CI_SERVER_DB_USER")]
-    db_user: String,
+    /// TOML file to read settings from. Flags and environment variables take
+    /// precedence over the file.
+    #[arg(short = 'c', long, env = "CI_SERVER_CONFIG")]
+    config: Option<PathBuf>,
 
-    #[arg(short = 'p', env = "CI_SERVER_DB_PASSWORD")]
-    db_password: String,
+    #[command(flatten)]
+    settings: Settings,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-    #[arg(short = 'n', long, env = "CI_SERVER_DB_NAME")]
-    db_name: String,
+#[derive(Subcommand, Default)]
+enum Command {
+    /// Apply pending migrations, then serve the HTTP API (default)
+    #[default]
+    Serve,
+    /// Apply pending migrations and exit
+    Migrate,
+    /// Delete build records according to the retention settings, and exit
+    Prune {
+        /// Only report the records that would be deleted
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Manage API tokens
+    Token {
+        #[command(subcommand)]
+        command: TokenCommand,
+    },
+}
 
-    #[arg(short = 'a', long, env = "CI_SERVER_DB_ADDRESS")]
-    db_addr: String,
+#[derive(Subcommand)]
+enum TokenCommand {
+    /// Create a new token, printing it to stdout
+    Create {
+        /// Human-readable description of the token (e.g., where it's used)
+        #[arg(long)]
+        name: String,
+        /// Slug of the pipeline this token may record builds for
+        #[arg(long)]
+        pipeline: String,
+    },
+    /// List all tokens
+    List,
+    /// Revoke a token by ID
+    Revoke { id: i64 },
 }
 
 #[derive(thiserror::Error, Debug)]
 enum MainError {
+    #[error("Missing required option for this store: {0}")]
+    MissingOption(&'static str),
+    #[error("No retention policy set (see --retention-keep-per-hash and --retention-failure-max-age-days)")]
+    NoRetentionPolicy,
+    #[error("Loading settings: {0}")]
+    LoadingSettings(#[from] ConfigError),
     #[error("Creating pool: {0}")]
     CreatingPool(#[from] tokio_postgres::Error),
+    #[error("Opening store: {0}")]
+    OpeningStore(#[from] StoreError),
     #[error("Serving HTTP: {0}")]
     Serving(#[from] server::HTTPServeError),
 }
 
+/// Open the configured store. With `listen`, it's notified of finished builds
+/// (for long-running servers, rather than one-off commands).
+async fn open_store(settings: &Settings, listen: bool) -> Result<Arc<dyn Store>, MainError> {
+    let store: Arc<dyn Store> = match settings.store_kind() {
+        StoreKind::Postgres => {
+            let postgres = &settings.postgres;
+            let config = postgres.connection_config()?;
+
+            // TLS doesn't apply to unix sockets
+            if postgres.socket_dir.is_some() {
+                let mgr = PostgresConnectionManager::new(config.clone(), NoTls);
+                let store = PostgresStore::new(postgres.pool.builder().build(mgr).await?);
+                match listen {
+                    true => Arc::new(store.listen(config, NoTls)),
+                    false => Arc::new(store),
+                }
+            } else {
+                let tls = postgres.tls_connector()?;
+                let mgr = PostgresConnectionManager::new(config.clone(), tls.clone());
+                let store = PostgresStore::new(postgres.pool.builder().build(mgr).await?);
+                match listen {
+                    true => Arc::new(store.listen(config, tls)),
+                    false => Arc::new(store),
+                }
+            }
+        }
+        StoreKind::Sqlite => {
+            let path = settings.sqlite_path.as_ref();
+            let path = path.ok_or(MainError::MissingOption("--sqlite-path"))?;
+            Arc::new(SqliteStore::open(path)?)
+        }
+        StoreKind::Memory => Arc::new(MemoryStore::new()),
+    };
+
+    Ok(store)
+}
+
+async fn migrate(store: &dyn Store) -> Result<(), MainError> {
+    let applied = store.migrate().await?;
+    for migration in &applied {
+        eprintln!(
+            "applied migration {:04}_{}",
+            migration.version, migration.name
+        );
+    }
+
+    eprintln!(
+        "database is at schema version {}",
+        migrations::latest_version()
+    );
+    Ok(())
+}
+
+async fn prune(
+    store: &dyn Store,
+    retention: &RetentionSettings,
+    dry_run: bool,
+) -> Result<(), MainError> {
+    let policy = retention
+        .policy(Utc::now())
+        .ok_or(MainError::NoRetentionPolicy)?;
+
+    let pruned = store.prune(&policy, dry_run).await?;
+    for record in &pruned {
+        let outcome = match record.success {
+            Some(true) => "succeeded",
+            Some(false) => "failed",
+            None => "unfinished",
+        };
+        println!(
+            "{}\t{}\t{}\t{outcome}",
+            record.hash, record.build_id, record.started_at
+        );
+    }
+
+    match dry_run {
+        true => eprintln!("would delete {} build records", pruned.len()),
+        false => eprintln!("deleted {} build records", pruned.len()),
+    }
+    Ok(())
+}
+
+/// Prune build records in the background, for as long as the server runs.
+async fn prune_periodically(store: Arc<dyn Store>, retention: RetentionSettings) {
+    let mut interval = tokio::time::interval(retention.prune_interval());
+    loop {
+        interval.tick().await;
+        let Some(policy) = retention.policy(Utc::now()) else {
+            return;
+        };
+
+        match store.prune(&policy, false).await {
+            Ok(pruned) if pruned.is_empty() => {}
+            Ok(pruned) => eprintln!("pruned {} build records", pruned.len()),
+            Err(e) => eprintln!("error pruning build records: {e}"),
+        }
+    }
+}
+
+async fn token(store: &dyn Store, command: TokenCommand) -> Result<(), MainError> {
+    match command {
+        TokenCommand::Create { name, pipeline } => {
+            let generated = auth::generate_token();
+            let token = store
+                .create_token(&name, &pipeline, &generated.hash)
+                .await?;
+            eprintln!("created token {} for pipeline {pipeline}", token.id);
+            eprintln!("this is the only time the token will be shown:");
+            println!("{}", generated.secret);
+        }
+        TokenCommand::List => {
+            for token in store.list_tokens().await? {
+                let status = match token.revoked_at {
+                    Some(t) => format!("revoked {t}"),
+                    None => "active".to_string(),
+                };
+                println!(
+                    "{}\t{}\t{}\t{}\t{status}",
+                    token.id, token.pipeline_slug, token.name, token.created_at
+                );
+            }
+        }
+        TokenCommand::Revoke { id } => {
+            store.revoke_token(id).await?;
+            eprintln!("revoked token {id}");
+        }
+    }
+
+    Ok(())
+}
+
 async fn real_main() -> Result<(), MainError> {
     let args = Args::parse();
-    let mut config = Config::new();
-    config
-        .user(&args.db_user)
-        .password(&args.db_password)
-        .dbname(&args.db_name);
-
-    let mgr = PostgresConnectionManager::new(config, NoTls);
-    let pool = Pool::builder().build(mgr).await?;
-    let store = Store::new(pool);
-    let server = Server::new(1234, store);
-    server.run_http_server().await?;
+    let settings = match &args.config {
+        Some(path) => args.settings.or(Settings::from_file(path)?),
+        None => args.settings,
+    };
+    let command = args.command.unwrap_or_default();
+    let store = open_store(&settings, matches!(command, Command::Serve)).await?;
+
+    match command {
+        Command::Migrate => migrate(store.as_ref()).await?,
+        Command::Prune { dry_run } => prune(store.as_ref(), &settings.retention, dry_run).await?,
+        Command::Token { command } => token(store.as_ref(), command).await?,
+        Command::Serve => {
+            migrate(store.as_ref()).await?;
+            let mut server = Server::new(settings.listen_addr(), store.clone());
+            if let Some(token) = &settings.webhook_token {
+                server = server.with_webhook_token(token);
+            }
+            if settings.retention.policy(Utc::now()).is_some() {
+                tokio::spawn(prune_periodically(store, settings.retention));
+            }
+            server.run_http_server().await?;
+        }
+    }
 
     Ok(())
 }
 
 #[tokio::main]
-async fn main() {
-    if let Err(e) = real_main().await {
-        eprintln!("error: {e}");
+async fn main() -> ExitCode {
+    match real_main().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
     }
 }
@@ -0,0 +1,125 @@
+// Prometheus metrics, served in the text format at `/metrics`. They're kept
+// in the default registry, so they cover every server in the process.
+//
+// Route labels use the route's pattern (e.g. `/v1/build-leases/:hash`) rather
+// than the requested path, to keep the number of series bounded.
+
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+
+use crate::store::{BuildRecord, PoolState};
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "ci_http_requests_total",
+        "HTTP requests handled, by route and response status",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "ci_http_request_duration_seconds",
+        "Time taken to handle HTTP requests, by route",
+        &["method", "route"]
+    )
+    .unwrap();
+    static ref DB_POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "ci_db_pool_connections",
+        "Database connections held by the pool, by whether they're in use",
+        &["state"]
+    )
+    .unwrap();
+    static ref DB_CONNECTION_TIMEOUTS: IntCounter = register_int_counter!(
+        "ci_db_connection_timeouts_total",
+        "Requests that timed out waiting for a database connection"
+    )
+    .unwrap();
+    static ref QUERIED_HASHES: IntCounterVec = register_int_counter_vec!(
+        "ci_queried_hashes_total",
+        "Derivations queried, by whether a successful build was found",
+        &["result"]
+    )
+    .unwrap();
+    static ref CACHED_BUILD_SECONDS: IntCounter = register_int_counter!(
+        "ci_cached_build_seconds_total",
+        "Total duration of the previous builds found by queries, i.e. build time saved by the cache"
+    )
+    .unwrap();
+    static ref BUILDS_RECORDED: IntCounterVec = register_int_counter_vec!(
+        "ci_builds_recorded_total",
+        "Finished builds recorded, by whether they succeeded",
+        &["success"]
+    )
+    .unwrap();
+}
+
+pub fn record_request(method: &str, route: &str, status: u16, duration: Duration) {
+    HTTP_REQUESTS
+        .with_label_values(&[method, route, &status.to_string()])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[method, route])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn record_connection_timeout() {
+    DB_CONNECTION_TIMEOUTS.inc();
+}
+
+/// Count the hits and misses of a query for `n_hashes` derivations, which
+/// found `records`.
+pub fn record_query(n_hashes: usize, records: &[BuildRecord]) {
+    let mut hits: Vec<&BuildRecord> = records.iter().filter(|r| r.success == Some(true)).collect();
+    hits.sort_by(|a, b| a.hash.cmp(&b.hash));
+    hits.dedup_by(|a, b| a.hash == b.hash);
+
+    let saved: i64 = hits
+        .iter()
+        .filter_map(|r| Some((r.finished_at? - r.started_at).num_seconds()))
+        .filter(|secs| *secs > 0)
+        .sum();
+
+    QUERIED_HASHES
+        .with_label_values(&["hit"])
+        .inc_by(hits.len() as u64);
+    QUERIED_HASHES
+        .with_label_values(&["miss"])
+        .inc_by(n_hashes.saturating_sub(hits.len()) as u64);
+    CACHED_BUILD_SECONDS.inc_by(saved as u64);
+}
+
+pub fn record_build(success: bool) {
+    BUILDS_RECORDED
+        .with_label_values(&[&success.to_string()])
+        .inc();
+}
+
+/// Render all metrics, including the current state of the store's connection
+/// pool (if it has one).
+pub fn render(pool: Option<PoolState>) -> String {
+    // Metrics are only registered when first used, but should be reported
+    // (as zero) before then
+    lazy_static::initialize(&DB_CONNECTION_TIMEOUTS);
+    lazy_static::initialize(&QUERIED_HASHES);
+    lazy_static::initialize(&CACHED_BUILD_SECONDS);
+    lazy_static::initialize(&BUILDS_RECORDED);
+
+    if let Some(pool) = pool {
+        let idle = pool.idle_connections as i64;
+        DB_POOL_CONNECTIONS.with_label_values(&["idle"]).set(idle);
+        DB_POOL_CONNECTIONS
+            .with_label_values(&["in_use"])
+            .set(pool.connections as i64 - idle);
+    }
+
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buf)
+        .expect("metrics can always be encoded");
+
+    String::from_utf8(buf).expect("metrics are valid UTF-8")
+}
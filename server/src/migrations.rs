@@ -0,0 +1,83 @@
+// Schema migrations are embedded in the binary, and applied in order of
+// version. The versions applied to a database are tracked in the
+// `schema_migrations` table.
+//
+// Each migration has an equivalent for every SQL backend, so that versions
+// mean the same thing regardless of where data is stored.
+//
+// Migrations must never be edited once released, only added.
+
+use crate::store::StoreError;
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub postgres: &'static str,
+    pub sqlite: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        postgres: include_str!("../migrations/postgres/0001_initial.sql"),
+        sqlite: include_str!("../migrations/sqlite/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "api_tokens",
+        postgres: include_str!("../migrations/postgres/0002_api_tokens.sql"),
+        sqlite: include_str!("../migrations/sqlite/0002_api_tokens.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "build_leases",
+        postgres: include_str!("../migrations/postgres/0003_build_leases.sql"),
+        sqlite: include_str!("../migrations/sqlite/0003_build_leases.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "scheduled_builds",
+        postgres: include_str!("../migrations/postgres/0004_scheduled_builds.sql"),
+        sqlite: include_str!("../migrations/sqlite/0004_scheduled_builds.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "unique_build_records",
+        postgres: include_str!("../migrations/postgres/0005_unique_build_records.sql"),
+        sqlite: include_str!("../migrations/sqlite/0005_unique_build_records.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "build_tags",
+        postgres: include_str!("../migrations/postgres/0006_build_tags.sql"),
+        sqlite: include_str!("../migrations/sqlite/0006_build_tags.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "pipeline_builds",
+        postgres: include_str!("../migrations/postgres/0007_pipeline_builds.sql"),
+        sqlite: include_str!("../migrations/sqlite/0007_pipeline_builds.sql"),
+    },
+];
+
+/// The schema version this binary expects.
+pub fn latest_version() -> i32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// Migrations that have yet to be applied to a database at `current`.
+///
+/// Fails if the database has had migrations applied that this binary doesn't
+/// know about, as we can't safely use it.
+pub fn pending(current: i32) -> Result<Vec<&'static Migration>, StoreError> {
+    let supported = latest_version();
+    if current > supported {
+        return Err(StoreError::SchemaTooNew {
+            database: current,
+            supported,
+        });
+    }
+
+    Ok(MIGRATIONS.iter().filter(|m| m.version > current).collect())
+}
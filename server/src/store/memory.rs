@@ -0,0 +1,456 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use chrono::Utc;
+
+use super::{
+    lease_expires_at, ApiToken, BuildFilter, BuildLease, BuildRecord, BuildReport, BuildResult,
+    BuildSchedule, HistoryFilter, LeaseClaim, PipelineBuild, RetentionPolicy, ScheduledBuild,
+    StepResult, StepRun, Store, StoreError,
+};
+use crate::migrations::{self, Migration};
+
+struct StoredRecord {
+    pipeline: String,
+    record: BuildRecord,
+}
+
+struct StoredToken {
+    hash: String,
+    token: ApiToken,
+}
+
+/// A store that only keeps records in memory, for tests and local
+/// development. Everything is lost when the server exits.
+#[derive(Default)]
+pub struct MemoryStore {
+    records: Mutex<Vec<StoredRecord>>,
+    tokens: Mutex<Vec<StoredToken>>,
+    leases: Mutex<Vec<BuildLease>>,
+    scheduled: Mutex<Vec<ScheduledBuild>>,
+    builds: Mutex<Vec<PipelineBuild>>,
+    step_runs: Mutex<Vec<StepRun>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn schema_version(&self) -> Result<i32, StoreError> {
+        // There's no schema to get out of date
+        Ok(migrations::latest_version())
+    }
+
+    async fn migrate(&self) -> Result<Vec<&'static Migration>, StoreError> {
+        Ok(Vec::new())
+    }
+
+    async fn query(&self, derivs: &[String]) -> Result<Vec<BuildRecord>, StoreError> {
+        let records = self.records.lock().unwrap();
+        let found = records
+            .iter()
+            .filter(|r| derivs.contains(&r.record.hash))
+            .map(|r| r.record.clone())
+            .collect();
+
+        Ok(found)
+    }
+
+    async fn history(&self, filter: &HistoryFilter) -> Result<Vec<BuildRecord>, StoreError> {
+        let records = self.records.lock().unwrap();
+        let mut found: Vec<BuildRecord> = records
+            .iter()
+            .map(|r| &r.record)
+            .filter(|r| match filter {
+                HistoryFilter::Hash(hash) => &r.hash == hash,
+                HistoryFilter::Tag(tag) => r.tag.as_ref() == Some(tag),
+            })
+            .cloned()
+            .collect();
+        found.sort_by_key(|r| Reverse(r.started_at));
+
+        Ok(found)
+    }
+
+    async fn failing_builds(&self) -> Result<Vec<BuildRecord>, StoreError> {
+        let records = self.records.lock().unwrap();
+
+        // Walk each target's finished records from newest to oldest
+        let mut by_finish: Vec<&BuildRecord> = records
+            .iter()
+            .map(|r| &r.record)
+            .filter(|r| r.finished_at.is_some())
+            .collect();
+        by_finish.sort_by_key(|r| Reverse(r.finished_at));
+        let mut seen: HashSet<&str> = HashSet::new();
+        let failing = by_finish
+            .into_iter()
+            .filter(|r| seen.insert(r.tag.as_deref().unwrap_or(&r.hash)))
+            .filter(|r| r.success == Some(false))
+            .cloned()
+            .collect();
+
+        Ok(failing)
+    }
+
+    async fn insert_start(&self, pipeline: &str, record: &BuildRecord) -> Result<(), StoreError> {
+        let record = BuildRecord {
+            finished_at: None,
+            success: None,
+            ..record.clone()
+        };
+        let pipeline = pipeline.to_string();
+        let mut records = self.records.lock().unwrap();
+        let exists = records
+            .iter()
+            .any(|r| r.record.hash == record.hash && r.record.build_id == record.build_id);
+        if !exists {
+            records.push(StoredRecord { pipeline, record });
+        }
+
+        Ok(())
+    }
+
+    async fn mark_finish(
+        &self,
+        pipeline: &str,
+        hash: &str,
+        build_id: &str,
+        result: &BuildResult,
+    ) -> Result<(), StoreError> {
+        let mut records = self.records.lock().unwrap();
+        let mut updated = 0usize;
+        for StoredRecord { record: r, .. } in records.iter_mut().filter(|r| {
+            r.pipeline == pipeline && r.record.hash == hash && r.record.build_id == build_id
+        }) {
+            r.started_at = result.started_at.unwrap_or(r.started_at);
+            r.finished_at = Some(result.finished_at);
+            r.success = Some(result.success);
+            updated += 1;
+        }
+
+        match updated {
+            0 => Err(StoreError::UpdateMissingEntry),
+            1.. => Ok(()),
+        }
+    }
+
+    async fn prune(
+        &self,
+        policy: &RetentionPolicy,
+        dry_run: bool,
+    ) -> Result<Vec<BuildRecord>, StoreError> {
+        let mut records = self.records.lock().unwrap();
+
+        // Walk each derivation's records from newest to oldest
+        let mut by_age: Vec<&BuildRecord> = records.iter().map(|r| &r.record).collect();
+        by_age.sort_by_key(|r| Reverse(r.started_at));
+        let mut seen: HashMap<&str, u32> = HashMap::new();
+        let mut kept_success: HashSet<&str> = HashSet::new();
+        let mut pruned = Vec::new();
+        for r in by_age {
+            // Builds that haven't finished are never pruned (nor counted), so
+            // they can still record their results
+            if r.success.is_none() {
+                continue;
+            }
+            let rank = seen.entry(&r.hash).or_default();
+            *rank += 1;
+            if r.success == Some(true) && kept_success.insert(&r.hash) {
+                continue;
+            }
+
+            let too_many = policy.keep_per_hash.is_some_and(|n| *rank > n);
+            let old_failure = match (r.success, r.finished_at, policy.failures_before) {
+                (Some(false), Some(finished), Some(before)) => finished < before,
+                _ => false,
+            };
+            if too_many || old_failure {
+                pruned.push(r.clone());
+            }
+        }
+
+        if !dry_run {
+            let is_pruned = |hash: &str, build_id: &str| {
+                pruned
+                    .iter()
+                    .any(|p| p.hash == hash && p.build_id == build_id)
+            };
+            records.retain(|r| !is_pruned(&r.record.hash, &r.record.build_id));
+
+            let mut step_runs = self.step_runs.lock().unwrap();
+            for run in step_runs.iter_mut() {
+                if run
+                    .hash
+                    .as_deref()
+                    .is_some_and(|h| is_pruned(h, &run.build_id))
+                {
+                    run.hash = None;
+                }
+            }
+        }
+
+        Ok(pruned)
+    }
+
+    async fn create_token(
+        &self,
+        name: &str,
+        pipeline: &str,
+        token_hash: &str,
+    ) -> Result<ApiToken, StoreError> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let token = ApiToken {
+            id: tokens.len() as i64 + 1,
+            name: name.to_string(),
+            pipeline_slug: pipeline.to_string(),
+            created_at: Utc::now(),
+            revoked_at: None,
+        };
+        tokens.push(StoredToken {
+            hash: token_hash.to_string(),
+            token: token.clone(),
+        });
+
+        Ok(token)
+    }
+
+    async fn list_tokens(&self) -> Result<Vec<ApiToken>, StoreError> {
+        let tokens = self.tokens.lock().unwrap();
+        Ok(tokens.iter().map(|t| t.token.clone()).collect())
+    }
+
+    async fn revoke_token(&self, id: i64) -> Result<(), StoreError> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let token = tokens
+            .iter_mut()
+            .map(|t| &mut t.token)
+            .find(|t| t.id == id && t.revoked_at.is_none())
+            .ok_or(StoreError::UpdateMissingEntry)?;
+        token.revoked_at = Some(Utc::now());
+
+        Ok(())
+    }
+
+    async fn find_token(&self, token_hash: &str) -> Result<Option<ApiToken>, StoreError> {
+        let tokens = self.tokens.lock().unwrap();
+        let token = tokens
+            .iter()
+            .find(|t| t.hash == token_hash && t.token.revoked_at.is_none())
+            .map(|t| t.token.clone());
+
+        Ok(token)
+    }
+
+    async fn claim_lease(
+        &self,
+        pipeline: &str,
+        hash: &str,
+        claim: &LeaseClaim,
+    ) -> Result<BuildLease, StoreError> {
+        let now = Utc::now();
+        let expires_at = lease_expires_at(claim, now);
+        let mut leases = self.leases.lock().unwrap();
+
+        let new = BuildLease {
+            hash: hash.to_string(),
+            build_id: claim.build_id.clone(),
+            build_url: claim.build_url.clone(),
+            pipeline_slug: pipeline.to_string(),
+            acquired_at: now,
+            expires_at,
+        };
+        let Some(lease) = leases.iter_mut().find(|l| l.hash == hash) else {
+            leases.push(new.clone());
+            return Ok(new);
+        };
+
+        if lease.build_id == claim.build_id && lease.pipeline_slug == pipeline {
+            lease.expires_at = expires_at;
+        } else if lease.expires_at <= now {
+            *lease = new;
+        }
+
+        Ok(lease.clone())
+    }
+
+    async fn find_lease(&self, hash: &str) -> Result<Option<BuildLease>, StoreError> {
+        let leases = self.leases.lock().unwrap();
+        let now = Utc::now();
+        let lease = leases
+            .iter()
+            .find(|l| l.hash == hash && l.expires_at > now)
+            .cloned();
+
+        Ok(lease)
+    }
+
+    async fn release_lease(
+        &self,
+        pipeline: &str,
+        hash: &str,
+        build_id: &str,
+    ) -> Result<(), StoreError> {
+        let mut leases = self.leases.lock().unwrap();
+        let before = leases.len();
+        leases
+            .retain(|l| !(l.hash == hash && l.build_id == build_id && l.pipeline_slug == pipeline));
+
+        match before - leases.len() {
+            0 => Err(StoreError::UpdateMissingEntry),
+            1.. => Ok(()),
+        }
+    }
+
+    async fn schedule_builds(
+        &self,
+        pipeline: &str,
+        schedule: &BuildSchedule,
+    ) -> Result<(), StoreError> {
+        let mut scheduled = self.scheduled.lock().unwrap();
+        let now = Utc::now();
+        for step in &schedule.steps {
+            let new = ScheduledBuild {
+                build_id: schedule.build_id.clone(),
+                step_key: step.step_key.clone(),
+                hash: step.hash.clone(),
+                build_url: schedule.build_url.clone(),
+                pipeline_slug: pipeline.to_string(),
+                scheduled_at: now,
+                tag: step.tag.clone(),
+            };
+            let existing = scheduled
+                .iter_mut()
+                .find(|s| s.build_id == new.build_id && s.step_key == new.step_key);
+            match existing {
+                Some(s) if s.pipeline_slug == pipeline => *s = new,
+                Some(_) => {}
+                None => scheduled.push(new),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn find_scheduled_build(
+        &self,
+        build_id: &str,
+        step_key: &str,
+    ) -> Result<Option<ScheduledBuild>, StoreError> {
+        let scheduled = self.scheduled.lock().unwrap();
+        let build = scheduled
+            .iter()
+            .find(|s| s.build_id == build_id && s.step_key == step_key)
+            .cloned();
+
+        Ok(build)
+    }
+
+    async fn record_build(
+        &self,
+        pipeline: &str,
+        build_id: &str,
+        report: &BuildReport,
+    ) -> Result<(), StoreError> {
+        let mut builds = self.builds.lock().unwrap();
+        let existing = builds.iter_mut().find(|b| b.id == build_id);
+        let created_at = existing.as_ref().map_or_else(Utc::now, |b| b.created_at);
+        let new = PipelineBuild {
+            id: build_id.to_string(),
+            pipeline_slug: pipeline.to_string(),
+            url: report.url.clone(),
+            commit_sha: report.commit_sha.clone(),
+            branch: report.branch.clone(),
+            tag: report.tag.clone(),
+            created_at,
+        };
+        match existing {
+            Some(b) if b.pipeline_slug == pipeline => *b = new,
+            Some(_) => {}
+            None => builds.push(new),
+        }
+
+        Ok(())
+    }
+
+    async fn record_step_run(
+        &self,
+        pipeline: &str,
+        build_id: &str,
+        step_key: &str,
+        result: &StepResult,
+    ) -> Result<(), StoreError> {
+        let builds = self.builds.lock().unwrap();
+        if !builds
+            .iter()
+            .any(|b| b.id == build_id && b.pipeline_slug == pipeline)
+        {
+            return Err(StoreError::UpdateMissingEntry);
+        }
+
+        let records = self.records.lock().unwrap();
+        let hash = result.hash.clone().filter(|hash| {
+            records
+                .iter()
+                .any(|r| &r.record.hash == hash && r.record.build_id == build_id)
+        });
+        let new = StepRun {
+            build_id: build_id.to_string(),
+            step_key: step_key.to_string(),
+            label: result.label.clone(),
+            hash,
+            started_at: result.started_at,
+            finished_at: result.finished_at,
+            success: result.success,
+        };
+
+        let mut step_runs = self.step_runs.lock().unwrap();
+        match step_runs
+            .iter_mut()
+            .find(|r| r.build_id == build_id && r.step_key == step_key)
+        {
+            Some(run) => *run = new,
+            None => step_runs.push(new),
+        }
+
+        Ok(())
+    }
+
+    async fn find_builds(&self, filter: &BuildFilter) -> Result<Vec<PipelineBuild>, StoreError> {
+        let builds = self.builds.lock().unwrap();
+        let matches = |field: &Option<String>, value: Option<&String>| {
+            field.is_none() || field.as_ref() == value
+        };
+        let mut found: Vec<PipelineBuild> = builds
+            .iter()
+            .filter(|b| {
+                matches(&filter.pipeline, Some(&b.pipeline_slug))
+                    && matches(&filter.branch, b.branch.as_ref())
+                    && matches(&filter.commit_sha, Some(&b.commit_sha))
+            })
+            .cloned()
+            .collect();
+        found.sort_by_key(|b| Reverse(b.created_at));
+        found.truncate(filter.limit as usize);
+
+        Ok(found)
+    }
+
+    async fn find_step_runs(&self, build_ids: &[String]) -> Result<Vec<StepRun>, StoreError> {
+        let step_runs = self.step_runs.lock().unwrap();
+        let mut found: Vec<StepRun> = step_runs
+            .iter()
+            .filter(|r| build_ids.contains(&r.build_id))
+            .cloned()
+            .collect();
+        found.sort_by(|a, b| (&a.build_id, &a.step_key).cmp(&(&b.build_id, &b.step_key)));
+
+        Ok(found)
+    }
+}
@@ -0,0 +1,251 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use postgres_from_row::FromRow;
+use serde::Serialize;
+
+use crate::migrations::Migration;
+
+mod memory;
+mod postgres;
+mod sqlite;
+mod wait;
+
+pub use api::{
+    BuildLease, BuildRecord, BuildReport, BuildResult, BuildSchedule, LeaseClaim, PipelineBuild,
+    ScheduledStep, StepResult, StepRun,
+};
+pub use memory::MemoryStore;
+pub use postgres::PostgresStore;
+pub use sqlite::SqliteStore;
+
+/// When a lease expires, if claimed at `now`.
+fn lease_expires_at(claim: &LeaseClaim, now: DateTime<Utc>) -> DateTime<Utc> {
+    let ttl = i64::try_from(claim.ttl_secs)
+        .ok()
+        .and_then(Duration::try_seconds);
+    ttl.and_then(|ttl| now.checked_add_signed(ttl))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+/// A derivation build scheduled as a step of a CI build, so the step's result
+/// can be matched back to the derivation.
+#[derive(Clone, Debug, FromRow, Serialize)]
+pub struct ScheduledBuild {
+    pub build_id: String,
+    pub step_key: String,
+    pub hash: String,
+    pub build_url: String,
+    pub pipeline_slug: String,
+    pub scheduled_at: DateTime<Utc>,
+    pub tag: Option<String>,
+}
+
+/// Which CI builds to find. Unset fields match every build.
+#[derive(Clone, Debug, Default)]
+pub struct BuildFilter {
+    pub pipeline: Option<String>,
+    pub branch: Option<String>,
+    pub commit_sha: Option<String>,
+    /// How many of the latest matching builds to return
+    pub limit: u32,
+}
+
+/// Which builds to find the history of: those of a single derivation, or of
+/// every derivation built as a flake attribute.
+#[derive(Clone, Debug)]
+pub enum HistoryFilter {
+    Hash(String),
+    Tag(String),
+}
+
+/// An API token, as shown to administrators (i.e., without its hash).
+#[derive(Clone, FromRow, Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    /// The pipeline this token may record builds for
+    pub pipeline_slug: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Which build records to delete when pruning. The latest successful build
+/// of each derivation is always kept, so pruning never causes a rebuild, as
+/// are builds that haven't finished.
+#[derive(Clone, Debug, Default)]
+pub struct RetentionPolicy {
+    /// Keep only this many of the latest finished records of each derivation
+    pub keep_per_hash: Option<u32>,
+    /// Delete failed builds that finished before this
+    pub failures_before: Option<DateTime<Utc>>,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.keep_per_hash.is_none() && self.failures_before.is_none()
+    }
+}
+
+/// Usage of a store's database connection pool.
+#[derive(Clone, Copy, Debug)]
+pub struct PoolState {
+    pub connections: u32,
+    pub idle_connections: u32,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum StoreError {
+    #[error("timed out waiting for DB connection")]
+    ConnectionTimeout,
+    #[error("error interacting with DB: {0}")]
+    DatabaseError(#[from] tokio_postgres::Error),
+    #[error("error interacting with SQLite DB: {0}")]
+    SqliteError(#[from] rusqlite::Error),
+    #[error("error running DB task: {0}")]
+    TaskFailed(#[from] tokio::task::JoinError),
+    #[error("No matching entries were found to update")]
+    UpdateMissingEntry,
+    #[error("database schema version {database} is newer than this server supports ({supported})")]
+    SchemaTooNew { database: i32, supported: i32 },
+}
+
+/// Storage backend for build records.
+#[async_trait]
+pub trait Store: Send + Sync {
+    /// The version of the latest migration applied to the database.
+    async fn schema_version(&self) -> Result<i32, StoreError>;
+
+    /// Apply all pending migrations, returning the ones that were applied.
+    async fn migrate(&self) -> Result<Vec<&'static Migration>, StoreError>;
+
+    /// Usage of the store's connection pool, if it has one.
+    fn pool_state(&self) -> Option<PoolState> {
+        None
+    }
+
+    /// Find all recorded builds of the given derivation hashes.
+    async fn query(&self, derivs: &[String]) -> Result<Vec<BuildRecord>, StoreError>;
+
+    /// Wait until each of `derivs` has a build that finished after `since`,
+    /// or until `timeout` passes. Either way, returns the builds of `derivs`
+    /// that finished after `since`.
+    async fn wait_for_finish(
+        &self,
+        derivs: &[String],
+        since: DateTime<Utc>,
+        timeout: std::time::Duration,
+    ) -> Result<Vec<BuildRecord>, StoreError> {
+        wait::wait_for_finish(self, derivs, since, timeout, None).await
+    }
+
+    /// Find all recorded builds matching `filter`, latest first.
+    async fn history(&self, filter: &HistoryFilter) -> Result<Vec<BuildRecord>, StoreError>;
+
+    /// Find the latest finished build of each target (i.e., each tag, or
+    /// each derivation built without one) that failed, latest first.
+    async fn failing_builds(&self) -> Result<Vec<BuildRecord>, StoreError>;
+
+    /// Record the start of a new build for the given pipeline. Does nothing
+    /// if the build of this derivation has already been recorded.
+    async fn insert_start(&self, pipeline: &str, record: &BuildRecord) -> Result<(), StoreError>;
+
+    /// Record the result of a build previously recorded with `insert_start`
+    /// for the same pipeline.
+    async fn mark_finish(
+        &self,
+        pipeline: &str,
+        hash: &str,
+        build_id: &str,
+        result: &BuildResult,
+    ) -> Result<(), StoreError>;
+
+    /// Delete the build records that `policy` doesn't keep, returning them.
+    /// With `dry_run`, nothing is deleted, but the records that would be are
+    /// still returned.
+    async fn prune(
+        &self,
+        policy: &RetentionPolicy,
+        dry_run: bool,
+    ) -> Result<Vec<BuildRecord>, StoreError>;
+
+    /// Store a new API token by its hash.
+    async fn create_token(
+        &self,
+        name: &str,
+        pipeline: &str,
+        token_hash: &str,
+    ) -> Result<ApiToken, StoreError>;
+
+    /// List all tokens, including revoked ones.
+    async fn list_tokens(&self) -> Result<Vec<ApiToken>, StoreError>;
+
+    /// Revoke a token, so it can no longer be used.
+    async fn revoke_token(&self, id: i64) -> Result<(), StoreError>;
+
+    /// Find the (unrevoked) token with the given hash.
+    async fn find_token(&self, token_hash: &str) -> Result<Option<ApiToken>, StoreError>;
+
+    /// Claim the lease on building a derivation, if it's free or expired, or
+    /// renew it if `claim.build_id` already holds it. Returns the lease as it
+    /// stands afterwards, which belongs to another build if the claim failed.
+    async fn claim_lease(
+        &self,
+        pipeline: &str,
+        hash: &str,
+        claim: &LeaseClaim,
+    ) -> Result<BuildLease, StoreError>;
+
+    /// Find the unexpired lease on building a derivation, if any.
+    async fn find_lease(&self, hash: &str) -> Result<Option<BuildLease>, StoreError>;
+
+    /// Give up a lease held by a build of the given pipeline.
+    async fn release_lease(
+        &self,
+        pipeline: &str,
+        hash: &str,
+        build_id: &str,
+    ) -> Result<(), StoreError>;
+
+    /// Record which derivations the steps of a build of the given pipeline
+    /// are building. Steps the pipeline already scheduled are replaced, and other
+    /// pipelines' steps are left alone.
+    async fn schedule_builds(
+        &self,
+        pipeline: &str,
+        schedule: &BuildSchedule,
+    ) -> Result<(), StoreError>;
+
+    /// Find the derivation scheduled to be built by a step of a build.
+    async fn find_scheduled_build(
+        &self,
+        build_id: &str,
+        step_key: &str,
+    ) -> Result<Option<ScheduledBuild>, StoreError>;
+
+    /// Record a CI build of the given pipeline (and the pipeline itself, if
+    /// it's new), or update it if it was already recorded. Builds recorded by
+    /// other pipelines are left alone.
+    async fn record_build(
+        &self,
+        pipeline: &str,
+        build_id: &str,
+        report: &BuildReport,
+    ) -> Result<(), StoreError>;
+
+    /// Record (or replace) the outcome of a step of a build previously
+    /// recorded with `record_build` for the same pipeline. The step is only
+    /// linked to the derivation it built if that build has been recorded.
+    async fn record_step_run(
+        &self,
+        pipeline: &str,
+        build_id: &str,
+        step_key: &str,
+        result: &StepResult,
+    ) -> Result<(), StoreError>;
+
+    /// Find the latest CI builds matching `filter`, latest first.
+    async fn find_builds(&self, filter: &BuildFilter) -> Result<Vec<PipelineBuild>, StoreError>;
+
+    /// Find the steps of the given CI builds.
+    async fn find_step_runs(&self, build_ids: &[String]) -> Result<Vec<StepRun>, StoreError>;
+}
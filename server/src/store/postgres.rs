@@ -0,0 +1,993 @@
+use std::future::poll_fn;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bb8::{ManageConnection, Pool};
+use postgres_from_row::FromRow;
+use tokio::sync::broadcast;
+use tokio_postgres::tls::{MakeTlsConnect, TlsConnect};
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{AsyncMessage, Client, Config, Socket};
+
+use chrono::{DateTime, Utc};
+
+use super::{
+    wait, ApiToken, BuildFilter, BuildLease, BuildRecord, BuildReport, BuildResult, BuildSchedule,
+    HistoryFilter, LeaseClaim, PipelineBuild, PoolState, RetentionPolicy, ScheduledBuild,
+    StepResult, StepRun, Store, StoreError,
+};
+use crate::metrics;
+use crate::migrations::{self, Migration};
+
+// Arbitrary, but fixed, key to serialise migrations between servers sharing a
+// database.
+const MIGRATION_LOCK_ID: i64 = 0x6369_6d69_6772_6174;
+
+/// How long to wait before reconnecting to listen for finished builds.
+const LISTEN_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+const MIGRATION_LOCK_QUERY: &str = r#"
+SELECT pg_advisory_xact_lock($1::BIGINT);
+"#;
+
+const CREATE_MIGRATIONS_TABLE_QUERY: &str = r#"
+CREATE TABLE IF NOT EXISTS schema_migrations (
+    version INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    applied_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+"#;
+
+const MIGRATIONS_TABLE_EXISTS_QUERY: &str = r#"
+SELECT
+    to_regclass('schema_migrations') IS NOT NULL;
+"#;
+
+const SCHEMA_VERSION_QUERY: &str = r#"
+SELECT
+    COALESCE(MAX(version), 0)
+FROM
+    schema_migrations;
+"#;
+
+const INSERT_MIGRATION_QUERY: &str = r#"
+INSERT INTO schema_migrations (
+    version,
+    name
+)
+VALUES (
+    $1::INTEGER,
+    $2::TEXT
+);
+"#;
+
+const FIND_DERIV_QUERY: &str = r#"
+SELECT
+    hash,
+    build_id,
+    build_url,
+    started_at,
+    finished_at,
+    success,
+    tag
+FROM
+    build_records
+WHERE
+    hash = ANY($1::TEXT[]);
+"#;
+
+const FIND_HASH_HISTORY_QUERY: &str = r#"
+SELECT
+    hash,
+    build_id,
+    build_url,
+    started_at,
+    finished_at,
+    success,
+    tag
+FROM
+    build_records
+WHERE
+    hash = $1::TEXT
+ORDER BY
+    started_at DESC;
+"#;
+
+const FIND_TAG_HISTORY_QUERY: &str = r#"
+SELECT
+    hash,
+    build_id,
+    build_url,
+    started_at,
+    finished_at,
+    success,
+    tag
+FROM
+    build_records
+WHERE
+    tag = $1::TEXT
+ORDER BY
+    started_at DESC;
+"#;
+
+// The latest finished build of each target, if it failed. Records without a
+// tag are their own target.
+const FAILING_BUILDS_QUERY: &str = r#"
+SELECT
+    hash,
+    build_id,
+    build_url,
+    started_at,
+    finished_at,
+    success,
+    tag
+FROM (
+    SELECT
+        *,
+        ROW_NUMBER() OVER (
+            PARTITION BY COALESCE(tag, hash)
+            ORDER BY finished_at DESC
+        ) AS finish_rank
+    FROM
+        build_records
+    WHERE
+        finished_at IS NOT NULL
+) latest
+WHERE
+    finish_rank = 1
+    AND success IS FALSE
+ORDER BY
+    finished_at DESC;
+"#;
+
+const INSERT_DERIV_QUERY: &str = r#"
+INSERT INTO build_records (
+    hash,
+    build_id,
+    started_at,
+    build_url,
+    pipeline_slug,
+    tag
+)
+VALUES (
+    $1::TEXT,
+    $2::TEXT,
+    $3::TIMESTAMP WITH TIME ZONE,
+    $4::TEXT,
+    $5::TEXT,
+    $6::TEXT
+)
+ON CONFLICT (hash, build_id) DO NOTHING;
+"#;
+
+// Notifies anyone waiting for the derivation (see `listen`), once the update
+// commits.
+const UPDATE_DERIV_FINISHED_QUERY: &str = r#"
+WITH finished AS (
+    UPDATE build_records
+    SET
+        started_at = COALESCE($1::TIMESTAMP WITH TIME ZONE, started_at),
+        finished_at = $2::TIMESTAMP WITH TIME ZONE,
+        success = $3::BOOLEAN
+    WHERE
+        hash = $4::TEXT
+        AND build_id = $5::TEXT
+        AND pipeline_slug = $6::TEXT
+    RETURNING
+        hash
+)
+SELECT
+    pg_notify('derivation_builds_finished', hash)
+FROM
+    finished;
+"#;
+
+const LISTEN_FINISHED_QUERY: &str = r#"
+LISTEN derivation_builds_finished;
+"#;
+
+// Records not kept by a retention policy, given how many to keep of each
+// derivation ($1) and the time before which failures are dropped ($2). Either
+// may be NULL, to not apply that rule. Builds that haven't finished are never
+// pruned (nor counted), so they can still record their results.
+const PRUNABLE_RECORDS_QUERY: &str = r#"
+SELECT
+    hash,
+    build_id,
+    build_url,
+    started_at,
+    finished_at,
+    success,
+    tag
+FROM (
+    SELECT
+        *,
+        ROW_NUMBER() OVER (
+            PARTITION BY hash
+            ORDER BY started_at DESC
+        ) AS age_rank,
+        ROW_NUMBER() OVER (
+            PARTITION BY hash, success IS TRUE
+            ORDER BY started_at DESC
+        ) AS outcome_rank
+    FROM
+        build_records
+    WHERE
+        success IS NOT NULL
+) ranked
+WHERE
+    NOT (success IS TRUE AND outcome_rank = 1)
+    AND (
+        age_rank > $1::BIGINT
+        OR (success IS FALSE AND finished_at < $2::TIMESTAMP WITH TIME ZONE)
+    );
+"#;
+
+const DELETE_DERIV_QUERY: &str = r#"
+DELETE FROM build_records
+WHERE
+    hash = $1::TEXT
+    AND build_id = $2::TEXT;
+"#;
+
+// Step runs refer to the records of the derivations they built, so must let go
+// of them first.
+const UNLINK_STEP_RUNS_QUERY: &str = r#"
+UPDATE step_runs
+SET
+    hash = NULL
+WHERE
+    hash = $1::TEXT
+    AND build_id = $2::TEXT;
+"#;
+
+const INSERT_TOKEN_QUERY: &str = r#"
+INSERT INTO api_tokens (
+    name,
+    pipeline_slug,
+    token_hash,
+    created_at
+)
+VALUES (
+    $1::TEXT,
+    $2::TEXT,
+    $3::CHAR(64),
+    $4::TIMESTAMP WITH TIME ZONE
+)
+RETURNING
+    id,
+    name,
+    pipeline_slug,
+    created_at,
+    revoked_at;
+"#;
+
+const LIST_TOKENS_QUERY: &str = r#"
+SELECT
+    id,
+    name,
+    pipeline_slug,
+    created_at,
+    revoked_at
+FROM
+    api_tokens
+ORDER BY
+    id;
+"#;
+
+const REVOKE_TOKEN_QUERY: &str = r#"
+UPDATE api_tokens
+SET
+    revoked_at = $1::TIMESTAMP WITH TIME ZONE
+WHERE
+    id = $2::BIGINT
+    AND revoked_at IS NULL;
+"#;
+
+const FIND_TOKEN_QUERY: &str = r#"
+SELECT
+    id,
+    name,
+    pipeline_slug,
+    created_at,
+    revoked_at
+FROM
+    api_tokens
+WHERE
+    token_hash = $1::CHAR(64)
+    AND revoked_at IS NULL;
+"#;
+
+// Times are taken from the database rather than the server, so that they're
+// consistent between servers sharing a database.
+const CLAIM_LEASE_QUERY: &str = r#"
+INSERT INTO build_leases (
+    hash,
+    build_id,
+    build_url,
+    pipeline_slug,
+    acquired_at,
+    expires_at
+)
+VALUES ($1, $2, $3, $4, now(), now() + make_interval(secs => $5::FLOAT8))
+ON CONFLICT (hash) DO UPDATE
+SET
+    build_id = EXCLUDED.build_id,
+    build_url = EXCLUDED.build_url,
+    pipeline_slug = EXCLUDED.pipeline_slug,
+    acquired_at = CASE
+        WHEN build_leases.build_id = EXCLUDED.build_id THEN build_leases.acquired_at
+        ELSE EXCLUDED.acquired_at
+    END,
+    expires_at = EXCLUDED.expires_at
+WHERE
+    build_leases.expires_at <= now()
+    OR (
+        build_leases.build_id = EXCLUDED.build_id
+        AND build_leases.pipeline_slug = EXCLUDED.pipeline_slug
+    )
+RETURNING
+    hash,
+    build_id,
+    build_url,
+    pipeline_slug,
+    acquired_at,
+    expires_at;
+"#;
+
+const FIND_LEASE_QUERY: &str = r#"
+SELECT
+    hash,
+    build_id,
+    build_url,
+    pipeline_slug,
+    acquired_at,
+    expires_at
+FROM
+    build_leases
+WHERE
+    hash = $1
+    AND expires_at > now();
+"#;
+
+const RELEASE_LEASE_QUERY: &str = r#"
+DELETE FROM build_leases
+WHERE
+    hash = $1
+    AND build_id = $2
+    AND pipeline_slug = $3;
+"#;
+
+const SCHEDULE_BUILD_QUERY: &str = r#"
+INSERT INTO scheduled_builds (
+    build_id,
+    step_key,
+    hash,
+    build_url,
+    pipeline_slug,
+    scheduled_at,
+    tag
+)
+VALUES ($1, $2, $3, $4, $5, now(), $6)
+ON CONFLICT (build_id, step_key) DO UPDATE
+SET
+    hash = EXCLUDED.hash,
+    build_url = EXCLUDED.build_url,
+    tag = EXCLUDED.tag,
+    scheduled_at = EXCLUDED.scheduled_at
+WHERE
+    scheduled_builds.pipeline_slug = EXCLUDED.pipeline_slug;
+"#;
+
+const FIND_SCHEDULED_BUILD_QUERY: &str = r#"
+SELECT
+    build_id,
+    step_key,
+    hash,
+    build_url,
+    pipeline_slug,
+    scheduled_at,
+    tag
+FROM
+    scheduled_builds
+WHERE
+    build_id = $1
+    AND step_key = $2;
+"#;
+
+const RECORD_PIPELINE_QUERY: &str = r#"
+INSERT INTO pipelines (
+    slug,
+    buildkite_id,
+    repository,
+    created_at
+)
+VALUES ($1::TEXT, $2::TEXT, $3::TEXT, now())
+ON CONFLICT (slug) DO UPDATE
+SET
+    buildkite_id = COALESCE(EXCLUDED.buildkite_id, pipelines.buildkite_id),
+    repository = COALESCE(EXCLUDED.repository, pipelines.repository);
+"#;
+
+const RECORD_BUILD_QUERY: &str = r#"
+INSERT INTO builds (
+    id,
+    pipeline_slug,
+    url,
+    commit_sha,
+    branch,
+    tag,
+    created_at
+)
+VALUES ($1::TEXT, $2::TEXT, $3::TEXT, $4::TEXT, $5::TEXT, $6::TEXT, now())
+ON CONFLICT (id) DO UPDATE
+SET
+    url = EXCLUDED.url,
+    commit_sha = EXCLUDED.commit_sha,
+    branch = EXCLUDED.branch,
+    tag = EXCLUDED.tag
+WHERE
+    builds.pipeline_slug = EXCLUDED.pipeline_slug;
+"#;
+
+// Only links the step to its derivation's build record if there is one, and
+// inserts nothing if the build isn't recorded for this pipeline.
+const RECORD_STEP_RUN_QUERY: &str = r#"
+INSERT INTO step_runs (
+    build_id,
+    step_key,
+    label,
+    hash,
+    started_at,
+    finished_at,
+    success
+)
+SELECT
+    builds.id,
+    $2::TEXT,
+    $3::TEXT,
+    build_records.hash,
+    $5::TIMESTAMP WITH TIME ZONE,
+    $6::TIMESTAMP WITH TIME ZONE,
+    $7::BOOLEAN
+FROM
+    builds
+    LEFT JOIN build_records
+        ON build_records.hash = $4::TEXT
+        AND build_records.build_id = builds.id
+WHERE
+    builds.id = $1::TEXT
+    AND builds.pipeline_slug = $8::TEXT
+ON CONFLICT (build_id, step_key) DO UPDATE
+SET
+    label = EXCLUDED.label,
+    hash = EXCLUDED.hash,
+    started_at = EXCLUDED.started_at,
+    finished_at = EXCLUDED.finished_at,
+    success = EXCLUDED.success;
+"#;
+
+const FIND_BUILDS_QUERY: &str = r#"
+SELECT
+    id,
+    pipeline_slug,
+    url,
+    commit_sha,
+    branch,
+    tag,
+    created_at
+FROM
+    builds
+WHERE
+    ($1::TEXT IS NULL OR pipeline_slug = $1::TEXT)
+    AND ($2::TEXT IS NULL OR branch = $2::TEXT)
+    AND ($3::TEXT IS NULL OR commit_sha = $3::TEXT)
+ORDER BY
+    created_at DESC
+LIMIT $4::BIGINT;
+"#;
+
+const FIND_STEP_RUNS_QUERY: &str = r#"
+SELECT
+    build_id,
+    step_key,
+    label,
+    hash,
+    started_at,
+    finished_at,
+    success
+FROM
+    step_runs
+WHERE
+    build_id = ANY($1::TEXT[])
+ORDER BY
+    build_id,
+    step_key;
+"#;
+
+/// A store backed by a pool of Postgres connections. The connection manager
+/// is generic so the pool can be made with or without TLS.
+#[derive(Clone)]
+pub struct PostgresStore<M>
+where
+    M: ManageConnection<Connection = Client, Error = tokio_postgres::Error>,
+{
+    pool: Pool<M>,
+    /// Hashes of derivations whose builds just finished, if listening
+    finished: Option<broadcast::Sender<String>>,
+}
+
+impl From<bb8::RunError<tokio_postgres::Error>> for StoreError {
+    fn from(value: bb8::RunError<tokio_postgres::Error>) -> Self {
+        match value {
+            bb8::RunError::User(e) => StoreError::DatabaseError(e),
+            bb8::RunError::TimedOut => {
+                metrics::record_connection_timeout();
+                StoreError::ConnectionTimeout
+            }
+        }
+    }
+}
+
+impl<M> PostgresStore<M>
+where
+    M: ManageConnection<Connection = Client, Error = tokio_postgres::Error>,
+{
+    pub fn new(pool: Pool<M>) -> Self {
+        Self {
+            pool,
+            finished: None,
+        }
+    }
+
+    /// Listen for finished builds on a dedicated connection (as pooled ones
+    /// drop notifications), so waiting for them doesn't need to poll.
+    pub fn listen<T>(mut self, config: Config, tls: T) -> Self
+    where
+        T: MakeTlsConnect<Socket> + Clone + Send + Sync + 'static,
+        T::Stream: Send + Sync,
+        T::TlsConnect: Send,
+        <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+    {
+        // Waiters that fall this far behind just check the database
+        let (finished, _) = broadcast::channel(1024);
+        tokio::spawn(listen_for_finished(config, tls, finished.clone()));
+        self.finished = Some(finished);
+
+        self
+    }
+}
+
+/// Forward notifications of finished builds to `finished`, reconnecting
+/// whenever the connection is lost.
+async fn listen_for_finished<T>(config: Config, tls: T, finished: broadcast::Sender<String>)
+where
+    T: MakeTlsConnect<Socket> + Clone + Send + Sync + 'static,
+    T::Stream: Send + Sync,
+    T::TlsConnect: Send,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    loop {
+        match forward_notifications(&config, tls.clone(), &finished).await {
+            Ok(()) => eprintln!("lost connection listening for finished builds, reconnecting"),
+            Err(e) => eprintln!("error listening for finished builds: {e}"),
+        }
+        tokio::time::sleep(LISTEN_RETRY_DELAY).await;
+    }
+}
+
+/// Forward notifications of finished builds to `finished` until the
+/// connection closes.
+async fn forward_notifications<T>(
+    config: &Config,
+    tls: T,
+    finished: &broadcast::Sender<String>,
+) -> Result<(), StoreError>
+where
+    T: MakeTlsConnect<Socket>,
+    T::Stream: Send + 'static,
+{
+    let (client, mut connection) = config.connect(tls).await?;
+    let finished = finished.clone();
+    // Notifications only arrive while the connection is polled
+    let messages = tokio::spawn(async move {
+        while let Some(message) = poll_fn(|cx| connection.poll_message(cx)).await {
+            if let AsyncMessage::Notification(notification) = message? {
+                // Fails if nobody is waiting, which is fine
+                let _ = finished.send(notification.payload().to_string());
+            }
+        }
+
+        Ok::<_, tokio_postgres::Error>(())
+    });
+    client.batch_execute(LISTEN_FINISHED_QUERY).await?;
+
+    Ok(messages.await??)
+}
+
+#[async_trait]
+impl<M> Store for PostgresStore<M>
+where
+    M: ManageConnection<Connection = Client, Error = tokio_postgres::Error>,
+{
+    async fn schema_version(&self) -> Result<i32, StoreError> {
+        // Only reads, so readiness probes don't take locks. No table means no
+        // migrations have been applied.
+        let conn = self.pool.get().await?;
+        let exists: bool = conn
+            .query_one(MIGRATIONS_TABLE_EXISTS_QUERY, &[])
+            .await?
+            .get(0);
+        if !exists {
+            return Ok(0);
+        }
+        let version = conn.query_one(SCHEMA_VERSION_QUERY, &[]).await?.get(0);
+
+        Ok(version)
+    }
+
+    async fn migrate(&self) -> Result<Vec<&'static Migration>, StoreError> {
+        let mut conn = self.pool.get().await?;
+        let tx = conn.transaction().await?;
+        tx.execute(MIGRATION_LOCK_QUERY, &[&MIGRATION_LOCK_ID])
+            .await?;
+        tx.batch_execute(CREATE_MIGRATIONS_TABLE_QUERY).await?;
+
+        let current: i32 = tx.query_one(SCHEMA_VERSION_QUERY, &[]).await?.get(0);
+        let pending = migrations::pending(current)?;
+        for migration in &pending {
+            tx.batch_execute(migration.postgres).await?;
+            tx.execute(
+                INSERT_MIGRATION_QUERY,
+                &[&migration.version, &migration.name],
+            )
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(pending)
+    }
+
+    fn pool_state(&self) -> Option<PoolState> {
+        let state = self.pool.state();
+        Some(PoolState {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+        })
+    }
+
+    async fn wait_for_finish(
+        &self,
+        derivs: &[String],
+        since: DateTime<Utc>,
+        timeout: Duration,
+    ) -> Result<Vec<BuildRecord>, StoreError> {
+        // Subscribe first, so builds finishing after we've checked aren't missed
+        let finished = self.finished.as_ref().map(|f| f.subscribe());
+        wait::wait_for_finish(self, derivs, since, timeout, finished).await
+    }
+
+    // TODO: finish
+    async fn query(&self, derivs: &[String]) -> Result<Vec<BuildRecord>, StoreError> {
+        let conn = self.pool.get().await?;
+        let rows = conn.query(FIND_DERIV_QUERY, &[&derivs]).await?;
+
+        let records = rows.iter().map(BuildRecord::from_row).collect();
+        Ok(records)
+    }
+
+    async fn history(&self, filter: &HistoryFilter) -> Result<Vec<BuildRecord>, StoreError> {
+        let conn = self.pool.get().await?;
+        let rows = match filter {
+            HistoryFilter::Hash(hash) => conn.query(FIND_HASH_HISTORY_QUERY, &[hash]).await?,
+            HistoryFilter::Tag(tag) => conn.query(FIND_TAG_HISTORY_QUERY, &[tag]).await?,
+        };
+
+        Ok(rows.iter().map(BuildRecord::from_row).collect())
+    }
+
+    async fn failing_builds(&self) -> Result<Vec<BuildRecord>, StoreError> {
+        let conn = self.pool.get().await?;
+        let rows = conn.query(FAILING_BUILDS_QUERY, &[]).await?;
+
+        Ok(rows.iter().map(BuildRecord::from_row).collect())
+    }
+
+    async fn insert_start(&self, pipeline: &str, record: &BuildRecord) -> Result<(), StoreError> {
+        // TODO: insert en masse?
+        let conn = self.pool.get().await?;
+        conn.execute(
+            INSERT_DERIV_QUERY,
+            &[
+                &record.hash,
+                &record.build_id,
+                &record.started_at,
+                &record.build_url,
+                &pipeline,
+                &record.tag,
+            ],
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn mark_finish(
+        &self,
+        pipeline: &str,
+        hash: &str,
+        build_id: &str,
+        result: &BuildResult,
+    ) -> Result<(), StoreError> {
+        let conn = self.pool.get().await?;
+        let res = conn
+            .execute(
+                UPDATE_DERIV_FINISHED_QUERY,
+                &[
+                    &result.started_at,
+                    &result.finished_at,
+                    &result.success,
+                    &hash,
+                    &build_id,
+                    &pipeline,
+                ],
+            )
+            .await?;
+
+        match res {
+            0 => Err(StoreError::UpdateMissingEntry),
+            1.. => Ok(()),
+        }
+    }
+
+    async fn prune(
+        &self,
+        policy: &RetentionPolicy,
+        dry_run: bool,
+    ) -> Result<Vec<BuildRecord>, StoreError> {
+        let mut conn = self.pool.get().await?;
+        let tx = conn.transaction().await?;
+        let keep = policy.keep_per_hash.map(i64::from);
+        let rows = tx
+            .query(PRUNABLE_RECORDS_QUERY, &[&keep, &policy.failures_before])
+            .await?;
+        let pruned: Vec<_> = rows.iter().map(BuildRecord::from_row).collect();
+
+        if !dry_run {
+            let unlink = tx.prepare(UNLINK_STEP_RUNS_QUERY).await?;
+            let delete = tx.prepare(DELETE_DERIV_QUERY).await?;
+            for record in &pruned {
+                let params: [&(dyn ToSql + Sync); 2] = [&record.hash, &record.build_id];
+                tx.execute(&unlink, &params).await?;
+                tx.execute(&delete, &params).await?;
+            }
+            tx.commit().await?;
+        }
+
+        Ok(pruned)
+    }
+
+    async fn create_token(
+        &self,
+        name: &str,
+        pipeline: &str,
+        token_hash: &str,
+    ) -> Result<ApiToken, StoreError> {
+        let conn = self.pool.get().await?;
+        let row = conn
+            .query_one(
+                INSERT_TOKEN_QUERY,
+                &[&name, &pipeline, &token_hash, &Utc::now()],
+            )
+            .await?;
+
+        Ok(ApiToken::from_row(&row))
+    }
+
+    async fn list_tokens(&self) -> Result<Vec<ApiToken>, StoreError> {
+        let conn = self.pool.get().await?;
+        let rows = conn.query(LIST_TOKENS_QUERY, &[]).await?;
+
+        Ok(rows.iter().map(ApiToken::from_row).collect())
+    }
+
+    async fn revoke_token(&self, id: i64) -> Result<(), StoreError> {
+        let conn = self.pool.get().await?;
+        let res = conn
+            .execute(REVOKE_TOKEN_QUERY, &[&Utc::now(), &id])
+            .await?;
+
+        match res {
+            0 => Err(StoreError::UpdateMissingEntry),
+            1.. => Ok(()),
+        }
+    }
+
+    async fn find_token(&self, token_hash: &str) -> Result<Option<ApiToken>, StoreError> {
+        let conn = self.pool.get().await?;
+        let row = conn.query_opt(FIND_TOKEN_QUERY, &[&token_hash]).await?;
+
+        Ok(row.as_ref().map(ApiToken::from_row))
+    }
+
+    async fn claim_lease(
+        &self,
+        pipeline: &str,
+        hash: &str,
+        claim: &LeaseClaim,
+    ) -> Result<BuildLease, StoreError> {
+        let conn = self.pool.get().await?;
+        let ttl_secs = claim.ttl_secs as f64;
+        loop {
+            let claimed = conn
+                .query_opt(
+                    CLAIM_LEASE_QUERY,
+                    &[
+                        &hash,
+                        &claim.build_id,
+                        &claim.build_url,
+                        &pipeline,
+                        &ttl_secs,
+                    ],
+                )
+                .await?;
+            if let Some(row) = claimed {
+                return Ok(BuildLease::from_row(&row));
+            }
+
+            // The lease can be released between failing to claim it and
+            // finding its holder, in which case it's worth trying again
+            if let Some(row) = conn.query_opt(FIND_LEASE_QUERY, &[&hash]).await? {
+                return Ok(BuildLease::from_row(&row));
+            }
+        }
+    }
+
+    async fn find_lease(&self, hash: &str) -> Result<Option<BuildLease>, StoreError> {
+        let conn = self.pool.get().await?;
+        let row = conn.query_opt(FIND_LEASE_QUERY, &[&hash]).await?;
+
+        Ok(row.as_ref().map(BuildLease::from_row))
+    }
+
+    async fn release_lease(
+        &self,
+        pipeline: &str,
+        hash: &str,
+        build_id: &str,
+    ) -> Result<(), StoreError> {
+        let conn = self.pool.get().await?;
+        let res = conn
+            .execute(RELEASE_LEASE_QUERY, &[&hash, &build_id, &pipeline])
+            .await?;
+
+        match res {
+            0 => Err(StoreError::UpdateMissingEntry),
+            1.. => Ok(()),
+        }
+    }
+
+    async fn schedule_builds(
+        &self,
+        pipeline: &str,
+        schedule: &BuildSchedule,
+    ) -> Result<(), StoreError> {
+        let mut conn = self.pool.get().await?;
+        let tx = conn.transaction().await?;
+        let stmt = tx.prepare(SCHEDULE_BUILD_QUERY).await?;
+        for step in &schedule.steps {
+            tx.execute(
+                &stmt,
+                &[
+                    &schedule.build_id,
+                    &step.step_key,
+                    &step.hash,
+                    &schedule.build_url,
+                    &pipeline,
+                    &step.tag,
+                ],
+            )
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn find_scheduled_build(
+        &self,
+        build_id: &str,
+        step_key: &str,
+    ) -> Result<Option<ScheduledBuild>, StoreError> {
+        let conn = self.pool.get().await?;
+        let row = conn
+            .query_opt(FIND_SCHEDULED_BUILD_QUERY, &[&build_id, &step_key])
+            .await?;
+
+        Ok(row.as_ref().map(ScheduledBuild::from_row))
+    }
+
+    async fn record_build(
+        &self,
+        pipeline: &str,
+        build_id: &str,
+        report: &BuildReport,
+    ) -> Result<(), StoreError> {
+        let mut conn = self.pool.get().await?;
+        let tx = conn.transaction().await?;
+        tx.execute(
+            RECORD_PIPELINE_QUERY,
+            &[&pipeline, &report.pipeline_id, &report.repository],
+        )
+        .await?;
+        tx.execute(
+            RECORD_BUILD_QUERY,
+            &[
+                &build_id,
+                &pipeline,
+                &report.url,
+                &report.commit_sha,
+                &report.branch,
+                &report.tag,
+            ],
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn record_step_run(
+        &self,
+        pipeline: &str,
+        build_id: &str,
+        step_key: &str,
+        result: &StepResult,
+    ) -> Result<(), StoreError> {
+        let conn = self.pool.get().await?;
+        let res = conn
+            .execute(
+                RECORD_STEP_RUN_QUERY,
+                &[
+                    &build_id,
+                    &step_key,
+                    &result.label,
+                    &result.hash,
+                    &result.started_at,
+                    &result.finished_at,
+                    &result.success,
+                    &pipeline,
+                ],
+            )
+            .await?;
+
+        match res {
+            0 => Err(StoreError::UpdateMissingEntry),
+            1.. => Ok(()),
+        }
+    }
+
+    async fn find_builds(&self, filter: &BuildFilter) -> Result<Vec<PipelineBuild>, StoreError> {
+        let conn = self.pool.get().await?;
+        let rows = conn
+            .query(
+                FIND_BUILDS_QUERY,
+                &[
+                    &filter.pipeline,
+                    &filter.branch,
+                    &filter.commit_sha,
+                    &i64::from(filter.limit),
+                ],
+            )
+            .await?;
+
+        Ok(rows.iter().map(PipelineBuild::from_row).collect())
+    }
+
+    async fn find_step_runs(&self, build_ids: &[String]) -> Result<Vec<StepRun>, StoreError> {
+        let conn = self.pool.get().await?;
+        let rows = conn.query(FIND_STEP_RUNS_QUERY, &[&build_ids]).await?;
+
+        Ok(rows.iter().map(StepRun::from_row).collect())
+    }
+}
@@ -0,0 +1,991 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row, TransactionBehavior};
+
+use super::{
+    lease_expires_at, ApiToken, BuildFilter, BuildLease, BuildRecord, BuildReport, BuildResult,
+    BuildSchedule, HistoryFilter, LeaseClaim, PipelineBuild, RetentionPolicy, ScheduledBuild,
+    StepResult, StepRun, Store, StoreError,
+};
+use crate::migrations::{self, Migration};
+
+// SQLite limits the number of parameters in a single statement (to 999, in
+// older versions), so large queries are split up.
+const QUERY_CHUNK_SIZE: usize = 500;
+
+const CREATE_MIGRATIONS_TABLE_QUERY: &str = r#"
+CREATE TABLE IF NOT EXISTS schema_migrations (
+    version INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+"#;
+
+const MIGRATIONS_TABLE_EXISTS_QUERY: &str = r#"
+SELECT
+    1
+FROM
+    sqlite_master
+WHERE
+    type = 'table'
+    AND name = 'schema_migrations';
+"#;
+
+const SCHEMA_VERSION_QUERY: &str = r#"
+SELECT
+    MAX(version)
+FROM
+    schema_migrations;
+"#;
+
+const INSERT_MIGRATION_QUERY: &str = r#"
+INSERT INTO schema_migrations (
+    version,
+    name
+)
+VALUES (?1, ?2);
+"#;
+
+const FIND_HASH_HISTORY_QUERY: &str = r#"
+SELECT
+    hash,
+    build_id,
+    build_url,
+    started_at,
+    finished_at,
+    success,
+    tag
+FROM
+    build_records
+WHERE
+    hash = ?1
+ORDER BY
+    started_at DESC;
+"#;
+
+const FIND_TAG_HISTORY_QUERY: &str = r#"
+SELECT
+    hash,
+    build_id,
+    build_url,
+    started_at,
+    finished_at,
+    success,
+    tag
+FROM
+    build_records
+WHERE
+    tag = ?1
+ORDER BY
+    started_at DESC;
+"#;
+
+// The latest finished build of each target, if it failed. Records without a
+// tag are their own target.
+const FAILING_BUILDS_QUERY: &str = r#"
+SELECT
+    hash,
+    build_id,
+    build_url,
+    started_at,
+    finished_at,
+    success,
+    tag
+FROM (
+    SELECT
+        *,
+        ROW_NUMBER() OVER (
+            PARTITION BY COALESCE(tag, hash)
+            ORDER BY finished_at DESC
+        ) AS finish_rank
+    FROM
+        build_records
+    WHERE
+        finished_at IS NOT NULL
+) latest
+WHERE
+    finish_rank = 1
+    AND success IS FALSE
+ORDER BY
+    finished_at DESC;
+"#;
+
+const INSERT_DERIV_QUERY: &str = r#"
+INSERT INTO build_records (
+    hash,
+    build_id,
+    started_at,
+    build_url,
+    pipeline_slug,
+    tag
+)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+ON CONFLICT (hash, build_id) DO NOTHING;
+"#;
+
+const UPDATE_DERIV_FINISHED_QUERY: &str = r#"
+UPDATE build_records
+SET
+    started_at = COALESCE(?1, started_at),
+    finished_at = ?2,
+    success = ?3
+WHERE
+    hash = ?4
+    AND build_id = ?5
+    AND pipeline_slug = ?6;
+"#;
+
+// Records not kept by a retention policy, given how many to keep of each
+// derivation (?1) and the time before which failures are dropped (?2). Either
+// may be NULL, to not apply that rule. Builds that haven't finished are never
+// pruned (nor counted), so they can still record their results.
+const PRUNABLE_RECORDS_QUERY: &str = r#"
+SELECT
+    hash,
+    build_id,
+    build_url,
+    started_at,
+    finished_at,
+    success,
+    tag
+FROM (
+    SELECT
+        *,
+        ROW_NUMBER() OVER (
+            PARTITION BY hash
+            ORDER BY started_at DESC
+        ) AS age_rank,
+        ROW_NUMBER() OVER (
+            PARTITION BY hash, success IS TRUE
+            ORDER BY started_at DESC
+        ) AS outcome_rank
+    FROM
+        build_records
+    WHERE
+        success IS NOT NULL
+)
+WHERE
+    NOT (success IS TRUE AND outcome_rank = 1)
+    AND (
+        age_rank > ?1
+        OR (success IS FALSE AND finished_at < ?2)
+    );
+"#;
+
+const DELETE_DERIV_QUERY: &str = r#"
+DELETE FROM build_records
+WHERE
+    hash = ?1
+    AND build_id = ?2;
+"#;
+
+// Step runs refer to the records of the derivations they built, so must let go
+// of them first.
+const UNLINK_STEP_RUNS_QUERY: &str = r#"
+UPDATE step_runs
+SET
+    hash = NULL
+WHERE
+    hash = ?1
+    AND build_id = ?2;
+"#;
+
+const INSERT_TOKEN_QUERY: &str = r#"
+INSERT INTO api_tokens (
+    name,
+    pipeline_slug,
+    token_hash,
+    created_at
+)
+VALUES (?1, ?2, ?3, ?4)
+RETURNING
+    id,
+    name,
+    pipeline_slug,
+    created_at,
+    revoked_at;
+"#;
+
+const LIST_TOKENS_QUERY: &str = r#"
+SELECT
+    id,
+    name,
+    pipeline_slug,
+    created_at,
+    revoked_at
+FROM
+    api_tokens
+ORDER BY
+    id;
+"#;
+
+const REVOKE_TOKEN_QUERY: &str = r#"
+UPDATE api_tokens
+SET
+    revoked_at = ?1
+WHERE
+    id = ?2
+    AND revoked_at IS NULL;
+"#;
+
+const FIND_TOKEN_QUERY: &str = r#"
+SELECT
+    id,
+    name,
+    pipeline_slug,
+    created_at,
+    revoked_at
+FROM
+    api_tokens
+WHERE
+    token_hash = ?1
+    AND revoked_at IS NULL;
+"#;
+
+const CLAIM_LEASE_QUERY: &str = r#"
+INSERT INTO build_leases (
+    hash,
+    build_id,
+    build_url,
+    pipeline_slug,
+    acquired_at,
+    expires_at
+)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+ON CONFLICT (hash) DO UPDATE
+SET
+    build_id = excluded.build_id,
+    build_url = excluded.build_url,
+    pipeline_slug = excluded.pipeline_slug,
+    acquired_at = CASE
+        WHEN build_leases.build_id = excluded.build_id THEN build_leases.acquired_at
+        ELSE excluded.acquired_at
+    END,
+    expires_at = excluded.expires_at
+WHERE
+    build_leases.expires_at <= excluded.acquired_at
+    OR (
+        build_leases.build_id = excluded.build_id
+        AND build_leases.pipeline_slug = excluded.pipeline_slug
+    )
+RETURNING
+    hash,
+    build_id,
+    build_url,
+    pipeline_slug,
+    acquired_at,
+    expires_at;
+"#;
+
+const FIND_LEASE_QUERY: &str = r#"
+SELECT
+    hash,
+    build_id,
+    build_url,
+    pipeline_slug,
+    acquired_at,
+    expires_at
+FROM
+    build_leases
+WHERE
+    hash = ?1
+    AND expires_at > ?2;
+"#;
+
+const RELEASE_LEASE_QUERY: &str = r#"
+DELETE FROM build_leases
+WHERE
+    hash = ?1
+    AND build_id = ?2
+    AND pipeline_slug = ?3;
+"#;
+
+const SCHEDULE_BUILD_QUERY: &str = r#"
+INSERT INTO scheduled_builds (
+    build_id,
+    step_key,
+    hash,
+    build_url,
+    pipeline_slug,
+    scheduled_at,
+    tag
+)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+ON CONFLICT (build_id, step_key) DO UPDATE
+SET
+    hash = excluded.hash,
+    build_url = excluded.build_url,
+    tag = excluded.tag,
+    scheduled_at = excluded.scheduled_at
+WHERE
+    scheduled_builds.pipeline_slug = excluded.pipeline_slug;
+"#;
+
+const FIND_SCHEDULED_BUILD_QUERY: &str = r#"
+SELECT
+    build_id,
+    step_key,
+    hash,
+    build_url,
+    pipeline_slug,
+    scheduled_at,
+    tag
+FROM
+    scheduled_builds
+WHERE
+    build_id = ?1
+    AND step_key = ?2;
+"#;
+
+const RECORD_PIPELINE_QUERY: &str = r#"
+INSERT INTO pipelines (
+    slug,
+    buildkite_id,
+    repository,
+    created_at
+)
+VALUES (?1, ?2, ?3, ?4)
+ON CONFLICT (slug) DO UPDATE
+SET
+    buildkite_id = COALESCE(excluded.buildkite_id, pipelines.buildkite_id),
+    repository = COALESCE(excluded.repository, pipelines.repository);
+"#;
+
+const RECORD_BUILD_QUERY: &str = r#"
+INSERT INTO builds (
+    id,
+    pipeline_slug,
+    url,
+    commit_sha,
+    branch,
+    tag,
+    created_at
+)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+ON CONFLICT (id) DO UPDATE
+SET
+    url = excluded.url,
+    commit_sha = excluded.commit_sha,
+    branch = excluded.branch,
+    tag = excluded.tag
+WHERE
+    builds.pipeline_slug = excluded.pipeline_slug;
+"#;
+
+// Only links the step to its derivation's build record if there is one, and
+// inserts nothing if the build isn't recorded for this pipeline.
+const RECORD_STEP_RUN_QUERY: &str = r#"
+INSERT INTO step_runs (
+    build_id,
+    step_key,
+    label,
+    hash,
+    started_at,
+    finished_at,
+    success
+)
+SELECT
+    builds.id,
+    ?2,
+    ?3,
+    build_records.hash,
+    ?5,
+    ?6,
+    ?7
+FROM
+    builds
+    LEFT JOIN build_records
+        ON build_records.hash = ?4
+        AND build_records.build_id = builds.id
+WHERE
+    builds.id = ?1
+    AND builds.pipeline_slug = ?8
+ON CONFLICT (build_id, step_key) DO UPDATE
+SET
+    label = excluded.label,
+    hash = excluded.hash,
+    started_at = excluded.started_at,
+    finished_at = excluded.finished_at,
+    success = excluded.success;
+"#;
+
+const FIND_BUILDS_QUERY: &str = r#"
+SELECT
+    id,
+    pipeline_slug,
+    url,
+    commit_sha,
+    branch,
+    tag,
+    created_at
+FROM
+    builds
+WHERE
+    (?1 IS NULL OR pipeline_slug = ?1)
+    AND (?2 IS NULL OR branch = ?2)
+    AND (?3 IS NULL OR commit_sha = ?3)
+ORDER BY
+    created_at DESC
+LIMIT ?4;
+"#;
+
+fn find_step_runs_query(n_builds: usize) -> String {
+    let params = vec!["?"; n_builds].join(", ");
+    format!(
+        r#"
+SELECT
+    build_id,
+    step_key,
+    label,
+    hash,
+    started_at,
+    finished_at,
+    success
+FROM
+    step_runs
+WHERE
+    build_id IN ({params})
+ORDER BY
+    build_id,
+    step_key;
+"#
+    )
+}
+
+fn find_deriv_query(n_hashes: usize) -> String {
+    let params = vec!["?"; n_hashes].join(", ");
+    format!(
+        r#"
+SELECT
+    hash,
+    build_id,
+    build_url,
+    started_at,
+    finished_at,
+    success,
+    tag
+FROM
+    build_records
+WHERE
+    hash IN ({params});
+"#
+    )
+}
+
+fn record_from_row(row: &Row) -> Result<BuildRecord, rusqlite::Error> {
+    Ok(BuildRecord {
+        hash: row.get(0)?,
+        build_id: row.get(1)?,
+        build_url: row.get(2)?,
+        started_at: row.get(3)?,
+        finished_at: row.get(4)?,
+        success: row.get(5)?,
+        tag: row.get(6)?,
+    })
+}
+
+fn token_from_row(row: &Row) -> Result<ApiToken, rusqlite::Error> {
+    Ok(ApiToken {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        pipeline_slug: row.get(2)?,
+        created_at: row.get(3)?,
+        revoked_at: row.get(4)?,
+    })
+}
+
+fn lease_from_row(row: &Row) -> Result<BuildLease, rusqlite::Error> {
+    Ok(BuildLease {
+        hash: row.get(0)?,
+        build_id: row.get(1)?,
+        build_url: row.get(2)?,
+        pipeline_slug: row.get(3)?,
+        acquired_at: row.get(4)?,
+        expires_at: row.get(5)?,
+    })
+}
+
+fn scheduled_build_from_row(row: &Row) -> Result<ScheduledBuild, rusqlite::Error> {
+    Ok(ScheduledBuild {
+        build_id: row.get(0)?,
+        step_key: row.get(1)?,
+        hash: row.get(2)?,
+        build_url: row.get(3)?,
+        pipeline_slug: row.get(4)?,
+        scheduled_at: row.get(5)?,
+        tag: row.get(6)?,
+    })
+}
+
+fn build_from_row(row: &Row) -> Result<PipelineBuild, rusqlite::Error> {
+    Ok(PipelineBuild {
+        id: row.get(0)?,
+        pipeline_slug: row.get(1)?,
+        url: row.get(2)?,
+        commit_sha: row.get(3)?,
+        branch: row.get(4)?,
+        tag: row.get(5)?,
+        created_at: row.get(6)?,
+    })
+}
+
+fn step_run_from_row(row: &Row) -> Result<StepRun, rusqlite::Error> {
+    Ok(StepRun {
+        build_id: row.get(0)?,
+        step_key: row.get(1)?,
+        label: row.get(2)?,
+        hash: row.get(3)?,
+        started_at: row.get(4)?,
+        finished_at: row.get(5)?,
+        success: row.get(6)?,
+    })
+}
+
+/// The latest migration applied, without changing anything (there being no
+/// migrations table if none have been).
+fn schema_version(conn: &Connection) -> Result<i32, rusqlite::Error> {
+    let exists = conn
+        .query_row(MIGRATIONS_TABLE_EXISTS_QUERY, [], |_| Ok(()))
+        .optional()?
+        .is_some();
+    if !exists {
+        return Ok(0);
+    }
+    let version: Option<i32> = conn
+        .query_row(SCHEMA_VERSION_QUERY, [], |row| row.get(0))
+        .optional()?
+        .flatten();
+
+    Ok(version.unwrap_or(0))
+}
+
+/// A store kept in a single SQLite database file.
+#[derive(Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        let conn = Connection::open(path)?;
+        Ok(Self::new(conn))
+    }
+
+    pub fn new(conn: Connection) -> Self {
+        // Off by default, for backwards compatibility
+        conn.pragma_update(None, "foreign_keys", true)
+            .expect("foreign keys can always be enabled");
+        let conn = Arc::new(Mutex::new(conn));
+        Self { conn }
+    }
+
+    /// Run a (blocking) operation against the database on a worker thread.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, StoreError> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut conn)
+        })
+        .await?
+    }
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    async fn schema_version(&self) -> Result<i32, StoreError> {
+        self.with_conn(|conn| Ok(schema_version(conn)?)).await
+    }
+
+    async fn migrate(&self) -> Result<Vec<&'static Migration>, StoreError> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            tx.execute_batch(CREATE_MIGRATIONS_TABLE_QUERY)?;
+            let pending = migrations::pending(schema_version(&tx)?)?;
+            for migration in &pending {
+                tx.execute_batch(migration.sqlite)?;
+                tx.execute(
+                    INSERT_MIGRATION_QUERY,
+                    params![migration.version, migration.name],
+                )?;
+            }
+            tx.commit()?;
+
+            Ok(pending)
+        })
+        .await
+    }
+
+    async fn query(&self, derivs: &[String]) -> Result<Vec<BuildRecord>, StoreError> {
+        let derivs = derivs.to_vec();
+        self.with_conn(move |conn| {
+            let mut records = Vec::new();
+            for chunk in derivs.chunks(QUERY_CHUNK_SIZE) {
+                let mut stmt = conn.prepare_cached(&find_deriv_query(chunk.len()))?;
+                let rows = stmt.query_map(params_from_iter(chunk), record_from_row)?;
+
+                for row in rows {
+                    records.push(row?);
+                }
+            }
+
+            Ok(records)
+        })
+        .await
+    }
+
+    async fn history(&self, filter: &HistoryFilter) -> Result<Vec<BuildRecord>, StoreError> {
+        let (query, value) = match filter {
+            HistoryFilter::Hash(hash) => (FIND_HASH_HISTORY_QUERY, hash.clone()),
+            HistoryFilter::Tag(tag) => (FIND_TAG_HISTORY_QUERY, tag.clone()),
+        };
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(query)?;
+            let rows = stmt.query_map([value], record_from_row)?;
+
+            Ok(rows.collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+
+    async fn failing_builds(&self) -> Result<Vec<BuildRecord>, StoreError> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare_cached(FAILING_BUILDS_QUERY)?;
+            let rows = stmt.query_map([], record_from_row)?;
+
+            Ok(rows.collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+
+    async fn insert_start(&self, pipeline: &str, record: &BuildRecord) -> Result<(), StoreError> {
+        let params = (
+            record.hash.clone(),
+            record.build_id.clone(),
+            record.started_at,
+            record.build_url.clone(),
+            pipeline.to_string(),
+            record.tag.clone(),
+        );
+        self.with_conn(move |conn| {
+            conn.execute(INSERT_DERIV_QUERY, params)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn mark_finish(
+        &self,
+        pipeline: &str,
+        hash: &str,
+        build_id: &str,
+        result: &BuildResult,
+    ) -> Result<(), StoreError> {
+        let params = (
+            result.started_at,
+            result.finished_at,
+            result.success,
+            hash.to_string(),
+            build_id.to_string(),
+            pipeline.to_string(),
+        );
+        let res = self
+            .with_conn(move |conn| Ok(conn.execute(UPDATE_DERIV_FINISHED_QUERY, params)?))
+            .await?;
+
+        match res {
+            0 => Err(StoreError::UpdateMissingEntry),
+            1.. => Ok(()),
+        }
+    }
+
+    async fn prune(
+        &self,
+        policy: &RetentionPolicy,
+        dry_run: bool,
+    ) -> Result<Vec<BuildRecord>, StoreError> {
+        let params = (policy.keep_per_hash, policy.failures_before);
+        self.with_conn(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let pruned: Vec<_> = {
+                let mut stmt = tx.prepare(PRUNABLE_RECORDS_QUERY)?;
+                let rows = stmt.query_map(params, record_from_row)?;
+                rows.collect::<Result<_, _>>()?
+            };
+
+            if !dry_run {
+                let mut unlink = tx.prepare(UNLINK_STEP_RUNS_QUERY)?;
+                let mut delete = tx.prepare(DELETE_DERIV_QUERY)?;
+                for record in &pruned {
+                    unlink.execute((&record.hash, &record.build_id))?;
+                    delete.execute((&record.hash, &record.build_id))?;
+                }
+                drop((unlink, delete));
+                tx.commit()?;
+            }
+
+            Ok(pruned)
+        })
+        .await
+    }
+
+    async fn create_token(
+        &self,
+        name: &str,
+        pipeline: &str,
+        token_hash: &str,
+    ) -> Result<ApiToken, StoreError> {
+        let params = (
+            name.to_string(),
+            pipeline.to_string(),
+            token_hash.to_string(),
+            Utc::now(),
+        );
+        self.with_conn(move |conn| {
+            let token = conn.query_row(INSERT_TOKEN_QUERY, params, token_from_row)?;
+            Ok(token)
+        })
+        .await
+    }
+
+    async fn list_tokens(&self) -> Result<Vec<ApiToken>, StoreError> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(LIST_TOKENS_QUERY)?;
+            let tokens = stmt
+                .query_map([], token_from_row)?
+                .collect::<Result<_, _>>()?;
+            Ok(tokens)
+        })
+        .await
+    }
+
+    async fn revoke_token(&self, id: i64) -> Result<(), StoreError> {
+        let res = self
+            .with_conn(move |conn| Ok(conn.execute(REVOKE_TOKEN_QUERY, (Utc::now(), id))?))
+            .await?;
+
+        match res {
+            0 => Err(StoreError::UpdateMissingEntry),
+            1.. => Ok(()),
+        }
+    }
+
+    async fn find_token(&self, token_hash: &str) -> Result<Option<ApiToken>, StoreError> {
+        let token_hash = token_hash.to_string();
+        self.with_conn(move |conn| {
+            let token = conn
+                .query_row(FIND_TOKEN_QUERY, [token_hash], token_from_row)
+                .optional()?;
+            Ok(token)
+        })
+        .await
+    }
+
+    async fn claim_lease(
+        &self,
+        pipeline: &str,
+        hash: &str,
+        claim: &LeaseClaim,
+    ) -> Result<BuildLease, StoreError> {
+        let now = Utc::now();
+        let expires_at = lease_expires_at(claim, now);
+        let hash = hash.to_string();
+        let params = (
+            hash.clone(),
+            claim.build_id.clone(),
+            claim.build_url.clone(),
+            pipeline.to_string(),
+            now,
+            expires_at,
+        );
+        self.with_conn(move |conn| {
+            // Nothing else can use the connection in between, so if the claim
+            // fails the lease must be held by someone else
+            let claimed = conn
+                .query_row(CLAIM_LEASE_QUERY, params, lease_from_row)
+                .optional()?;
+            let lease = match claimed {
+                Some(lease) => lease,
+                None => conn.query_row(FIND_LEASE_QUERY, (hash, now), lease_from_row)?,
+            };
+            Ok(lease)
+        })
+        .await
+    }
+
+    async fn find_lease(&self, hash: &str) -> Result<Option<BuildLease>, StoreError> {
+        let hash = hash.to_string();
+        self.with_conn(move |conn| {
+            let lease = conn
+                .query_row(FIND_LEASE_QUERY, (hash, Utc::now()), lease_from_row)
+                .optional()?;
+            Ok(lease)
+        })
+        .await
+    }
+
+    async fn release_lease(
+        &self,
+        pipeline: &str,
+        hash: &str,
+        build_id: &str,
+    ) -> Result<(), StoreError> {
+        let params = (hash.to_string(), build_id.to_string(), pipeline.to_string());
+        let res = self
+            .with_conn(move |conn| Ok(conn.execute(RELEASE_LEASE_QUERY, params)?))
+            .await?;
+
+        match res {
+            0 => Err(StoreError::UpdateMissingEntry),
+            1.. => Ok(()),
+        }
+    }
+
+    async fn schedule_builds(
+        &self,
+        pipeline: &str,
+        schedule: &BuildSchedule,
+    ) -> Result<(), StoreError> {
+        let pipeline = pipeline.to_string();
+        let schedule = schedule.clone();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare_cached(SCHEDULE_BUILD_QUERY)?;
+                let now = Utc::now();
+                for step in &schedule.steps {
+                    stmt.execute((
+                        &schedule.build_id,
+                        &step.step_key,
+                        &step.hash,
+                        &schedule.build_url,
+                        &pipeline,
+                        now,
+                        &step.tag,
+                    ))?;
+                }
+            }
+            tx.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn find_scheduled_build(
+        &self,
+        build_id: &str,
+        step_key: &str,
+    ) -> Result<Option<ScheduledBuild>, StoreError> {
+        let params = (build_id.to_string(), step_key.to_string());
+        self.with_conn(move |conn| {
+            let build = conn
+                .query_row(FIND_SCHEDULED_BUILD_QUERY, params, scheduled_build_from_row)
+                .optional()?;
+            Ok(build)
+        })
+        .await
+    }
+
+    async fn record_build(
+        &self,
+        pipeline: &str,
+        build_id: &str,
+        report: &BuildReport,
+    ) -> Result<(), StoreError> {
+        let pipeline = pipeline.to_string();
+        let build_id = build_id.to_string();
+        let report = report.clone();
+        self.with_conn(move |conn| {
+            let now = Utc::now();
+            let tx = conn.transaction()?;
+            tx.execute(
+                RECORD_PIPELINE_QUERY,
+                (&pipeline, &report.pipeline_id, &report.repository, now),
+            )?;
+            tx.execute(
+                RECORD_BUILD_QUERY,
+                (
+                    &build_id,
+                    &pipeline,
+                    &report.url,
+                    &report.commit_sha,
+                    &report.branch,
+                    &report.tag,
+                    now,
+                ),
+            )?;
+            tx.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn record_step_run(
+        &self,
+        pipeline: &str,
+        build_id: &str,
+        step_key: &str,
+        result: &StepResult,
+    ) -> Result<(), StoreError> {
+        let params = (
+            build_id.to_string(),
+            step_key.to_string(),
+            result.label.clone(),
+            result.hash.clone(),
+            result.started_at,
+            result.finished_at,
+            result.success,
+            pipeline.to_string(),
+        );
+        let res = self
+            .with_conn(move |conn| Ok(conn.execute(RECORD_STEP_RUN_QUERY, params)?))
+            .await?;
+
+        match res {
+            0 => Err(StoreError::UpdateMissingEntry),
+            1.. => Ok(()),
+        }
+    }
+
+    async fn find_builds(&self, filter: &BuildFilter) -> Result<Vec<PipelineBuild>, StoreError> {
+        let params = (
+            filter.pipeline.clone(),
+            filter.branch.clone(),
+            filter.commit_sha.clone(),
+            filter.limit,
+        );
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(FIND_BUILDS_QUERY)?;
+            let rows = stmt.query_map(params, build_from_row)?;
+
+            Ok(rows.collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+
+    async fn find_step_runs(&self, build_ids: &[String]) -> Result<Vec<StepRun>, StoreError> {
+        let build_ids = build_ids.to_vec();
+        self.with_conn(move |conn| {
+            let mut runs = Vec::new();
+            for chunk in build_ids.chunks(QUERY_CHUNK_SIZE) {
+                let mut stmt = conn.prepare_cached(&find_step_runs_query(chunk.len()))?;
+                let rows = stmt.query_map(params_from_iter(chunk), step_run_from_row)?;
+
+                for row in rows {
+                    runs.push(row?);
+                }
+            }
+
+            Ok(runs)
+        })
+        .await
+    }
+}
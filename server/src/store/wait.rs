@@ -0,0 +1,70 @@
+// Waiting for derivation builds to finish.
+//
+// Stores that are told when builds finish pass in a receiver of the finished
+// derivations' hashes, and only check the database again when one we're
+// waiting on finishes (or once in a while, in case a notification was lost).
+// Other stores just poll.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::Instant;
+
+use super::{BuildRecord, Store, StoreError};
+
+/// How often to check for finished builds without notifications.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often to check for finished builds with notifications.
+const NOTIFIED_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+pub(super) async fn wait_for_finish<S: Store + ?Sized>(
+    store: &S,
+    derivs: &[String],
+    since: DateTime<Utc>,
+    timeout: Duration,
+    mut finished: Option<broadcast::Receiver<String>>,
+) -> Result<Vec<BuildRecord>, StoreError> {
+    let deadline = Instant::now() + timeout;
+    let interval = match finished {
+        Some(_) => NOTIFIED_POLL_INTERVAL,
+        None => POLL_INTERVAL,
+    };
+
+    loop {
+        let records: Vec<_> = store
+            .query(derivs)
+            .await?
+            .into_iter()
+            .filter(|r| r.finished_at.is_some_and(|t| t > since))
+            .collect();
+        let done = derivs.iter().all(|d| records.iter().any(|r| r.hash == *d));
+
+        let now = Instant::now();
+        if done || now >= deadline {
+            return Ok(records);
+        }
+
+        let next_check = deadline.min(now + interval);
+        match &mut finished {
+            Some(finished) => notified(finished, derivs, next_check).await,
+            None => tokio::time::sleep_until(next_check).await,
+        }
+    }
+}
+
+/// Wait until one of `derivs` is reported finished, or until `until`.
+async fn notified(finished: &mut broadcast::Receiver<String>, derivs: &[String], until: Instant) {
+    let wait = async {
+        loop {
+            match finished.recv().await {
+                Ok(hash) if derivs.contains(&hash) => return,
+                Ok(_) => {}
+                // Any of the notifications we missed could have been ours
+                Err(RecvError::Lagged(_)) => return,
+                Err(RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    };
+    let _ = tokio::time::timeout_at(until, wait).await;
+}
@@ -0,0 +1,183 @@
+// Buildkite can notify us when jobs and builds finish, so build results are
+// recorded even if a build's `collect` step never runs (e.g., because the
+// build was cancelled, or its agent was lost).
+//
+// Webhooks are authenticated with a token shared with Buildkite, which is
+// either sent as-is in `X-Buildkite-Token`, or used to sign the request in
+// `X-Buildkite-Signature` (https://buildkite.com/docs/apis/webhooks).
+//
+// Steps are matched back to derivations through the builds scheduled by
+// `evaluate`, so only steps we generated are recorded.
+
+use axum::http::HeaderMap;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Deserializer};
+use sha2::Sha256;
+
+use crate::auth::hash_token;
+
+const TOKEN_HEADER: &str = "x-buildkite-token";
+const SIGNATURE_HEADER: &str = "x-buildkite-signature";
+/// How old a signature can be before the request is rejected as a replay.
+const MAX_SIGNATURE_AGE_SECS: i64 = 5 * 60;
+/// Format of timestamps in webhook payloads, e.g. `2024-04-01 12:00:00 UTC`.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f UTC";
+
+#[derive(thiserror::Error, Debug)]
+pub enum WebhookError {
+    #[error("missing webhook token or signature")]
+    Missing,
+    #[error("invalid webhook token")]
+    InvalidToken,
+    #[error("malformed webhook signature header")]
+    MalformedSignature,
+    #[error("invalid webhook signature")]
+    InvalidSignature,
+    #[error("webhook signature has expired")]
+    ExpiredSignature,
+}
+
+/// Check that a webhook request came from Buildkite, by either its token or
+/// its signature.
+pub fn verify(
+    token: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    now: DateTime<Utc>,
+) -> Result<(), WebhookError> {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+
+    if let Some(signature) = header(SIGNATURE_HEADER) {
+        return verify_signature(token, signature, body, now);
+    }
+
+    // Compare hashes, so the comparison takes the same time however much of
+    // the token is right
+    let given = header(TOKEN_HEADER).ok_or(WebhookError::Missing)?;
+    match hash_token(given.trim()) == hash_token(token) {
+        true => Ok(()),
+        false => Err(WebhookError::InvalidToken),
+    }
+}
+
+/// Check a signature header of the form `timestamp=<unix time>,signature=<hex
+/// HMAC-SHA256 of "<timestamp>.<body>">`.
+fn verify_signature(
+    token: &str,
+    header: &str,
+    body: &[u8],
+    now: DateTime<Utc>,
+) -> Result<(), WebhookError> {
+    let (mut timestamp, mut signature) = (None, None);
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("timestamp", v)) => timestamp = Some(v),
+            Some(("signature", v)) => signature = Some(v),
+            _ => {}
+        }
+    }
+    let (Some(timestamp), Some(signature)) = (timestamp, signature) else {
+        return Err(WebhookError::MalformedSignature);
+    };
+    let signature = hex::decode(signature).map_err(|_| WebhookError::MalformedSignature)?;
+    let signed_at: i64 = timestamp
+        .parse()
+        .map_err(|_| WebhookError::MalformedSignature)?;
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac.verify_slice(&signature)
+        .map_err(|_| WebhookError::InvalidSignature)?;
+
+    if (now.timestamp() - signed_at).abs() > MAX_SIGNATURE_AGE_SECS {
+        return Err(WebhookError::ExpiredSignature);
+    }
+
+    Ok(())
+}
+
+/// The parts of a webhook payload we use.
+#[derive(Deserialize)]
+pub struct Event {
+    pub event: String,
+    #[serde(default)]
+    pub build: Option<Build>,
+    /// Set for `job.*` events
+    #[serde(default)]
+    pub job: Option<Job>,
+    #[serde(default)]
+    pub pipeline: Option<Pipeline>,
+}
+
+impl Event {
+    /// The finished jobs this event tells us about.
+    pub fn finished_jobs(&self) -> Vec<&Job> {
+        match (self.event.as_str(), &self.build) {
+            ("job.finished", _) => self.job.iter().collect(),
+            ("build.finished", Some(build)) => build.jobs.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Build {
+    pub id: String,
+    pub web_url: String,
+    #[serde(default)]
+    pub jobs: Vec<Job>,
+}
+
+#[derive(Deserialize)]
+pub struct Job {
+    /// Only set for jobs of steps with keys (e.g., not for wait steps)
+    #[serde(default)]
+    pub step_key: Option<String>,
+    #[serde(default)]
+    pub state: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl Job {
+    /// Whether the job built its derivation, or `None` if it didn't finish
+    /// building (e.g., it was cancelled or skipped).
+    pub fn success(&self) -> Option<bool> {
+        match self.state.as_deref()? {
+            "passed" => Some(true),
+            "failed" | "timed_out" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Pipeline {
+    pub slug: String,
+}
+
+/// Webhooks use their own timestamp format, but accept RFC 3339 as well (as
+/// used by the REST API).
+fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(s) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+
+    let time = match DateTime::parse_from_rfc3339(&s) {
+        Ok(time) => time.with_timezone(&Utc),
+        Err(_) => NaiveDateTime::parse_from_str(&s, TIMESTAMP_FORMAT)
+            .map_err(serde::de::Error::custom)?
+            .and_utc(),
+    };
+
+    Ok(Some(time))
+}
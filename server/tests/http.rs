@@ -0,0 +1,1428 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::http::{header, Method, Request, StatusCode};
+use axum::Router;
+use hmac::{Hmac, Mac};
+use http_body_util::BodyExt;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tempfile::NamedTempFile;
+use tower::ServiceExt;
+
+use server::auth::generate_token;
+use server::store::{MemoryStore, RetentionPolicy, SqliteStore, Store};
+use server::{Server, MAX_LEASE_TTL_SECS, MAX_QUERY_HASHES, REQUEST_ID_HEADER};
+
+const HASH: &str = "0c6kzph7l0dcbfmjap64f0czdafn3b7x";
+const OTHER_HASH: &str = "1rx3xf1f2cngg6frnwhyrr3hlzvd2i2d";
+const BUILD_ID: &str = "018e9c2f-3f9a-4a7c-9a0e-8b1f2f6f1e2d";
+const PIPELINE: &str = "my-pipeline";
+const WEBHOOK_TOKEN: &str = "webhook-secret";
+
+const BUILDS_URI: &str = "/v1/derivation-builds";
+const QUERY_URI: &str = "/v1/derivation-builds/query";
+
+/// The stores every test is run against.
+#[derive(Clone, Copy)]
+enum Backend {
+    Memory,
+    /// In a new database file for each test
+    Sqlite,
+}
+
+/// A migrated store, and the file it's kept in (removed when it's dropped).
+struct TestStore {
+    store: Arc<dyn Store>,
+    _file: Option<NamedTempFile>,
+}
+
+impl Backend {
+    async fn store(self) -> TestStore {
+        let (store, file): (Arc<dyn Store>, _) = match self {
+            Self::Memory => (Arc::new(MemoryStore::new()), None),
+            Self::Sqlite => {
+                let file = NamedTempFile::new().unwrap();
+                let store = SqliteStore::open(file.path()).unwrap();
+                (Arc::new(store), Some(file))
+            }
+        };
+        store.migrate().await.unwrap();
+
+        TestStore { store, _file: file }
+    }
+}
+
+/// Define a test per backend for each of the named test functions, which
+/// take the backend to use.
+macro_rules! store_tests {
+    ($($name:ident),* $(,)?) => {
+        mod memory {
+            $(
+                #[tokio::test]
+                async fn $name() {
+                    super::$name(super::Backend::Memory).await;
+                }
+            )*
+        }
+
+        mod sqlite {
+            $(
+                #[tokio::test]
+                async fn $name() {
+                    super::$name(super::Backend::Sqlite).await;
+                }
+            )*
+        }
+    };
+}
+
+struct TestApp {
+    router: Router,
+    store: Arc<dyn Store>,
+    /// Token for `PIPELINE`
+    token: String,
+    _file: Option<NamedTempFile>,
+}
+
+impl TestApp {
+    async fn new(backend: Backend) -> Self {
+        let TestStore { store, _file } = backend.store().await;
+        let router = Server::new(([127, 0, 0, 1], 0).into(), store.clone())
+            .with_webhook_token(WEBHOOK_TOKEN)
+            .router();
+        let token = create_token(store.as_ref(), PIPELINE).await;
+
+        Self {
+            router,
+            store,
+            token,
+            _file,
+        }
+    }
+}
+
+async fn create_token(store: &dyn Store, pipeline: &str) -> String {
+    let generated = generate_token();
+    store
+        .create_token("test", pipeline, &generated.hash)
+        .await
+        .unwrap();
+    generated.secret
+}
+
+fn record(hash: &str) -> Value {
+    json!({
+        "hash": hash,
+        "build_id": BUILD_ID,
+        "build_url": "https://buildkite.com/org/pipeline/builds/1",
+        "started_at": "2024-04-01T12:00:00Z",
+    })
+}
+
+fn result(success: bool) -> Value {
+    json!({
+        "finished_at": "2024-04-01T12:05:00Z",
+        "success": success,
+    })
+}
+
+fn finish_uri(hash: &str) -> String {
+    format!("/v1/derivation-builds/{hash}/{BUILD_ID}")
+}
+
+async fn send(
+    app: &TestApp,
+    method: Method,
+    uri: &str,
+    body: Option<&Value>,
+) -> (StatusCode, Vec<u8>) {
+    send_as(app, Some(&app.token), method, uri, body).await
+}
+
+async fn send_as(
+    app: &TestApp,
+    token: Option<&str>,
+    method: Method,
+    uri: &str,
+    body: Option<&Value>,
+) -> (StatusCode, Vec<u8>) {
+    let mut req = Request::builder().method(method).uri(uri);
+    if let Some(token) = token {
+        req = req.header(header::AUTHORIZATION, format!("Bearer {token}"));
+    }
+    let req = match body {
+        Some(body) => req
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string())),
+        None => req.body(Body::empty()),
+    };
+
+    let resp = app.router.clone().oneshot(req.unwrap()).await.unwrap();
+    let status = resp.status();
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    (status, body.to_vec())
+}
+
+fn error_code(body: &[u8]) -> String {
+    let body: Value = serde_json::from_slice(body).unwrap();
+    body["error"]["code"].as_str().unwrap().to_string()
+}
+
+async fn create(app: &TestApp, hash: &str) {
+    create_record(app, &record(hash)).await;
+}
+
+async fn create_record(app: &TestApp, record: &Value) {
+    let (status, _) = send(app, Method::POST, BUILDS_URI, Some(record)).await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+async fn query(app: &TestApp, hashes: &[&str]) -> Vec<Value> {
+    let body = json!({ "hashes": hashes });
+    let (status, body) = send(app, Method::POST, QUERY_URI, Some(&body)).await;
+    assert_eq!(status, StatusCode::OK);
+    serde_json::from_slice(&body).unwrap()
+}
+
+async fn query_unknown_hash_is_empty(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    assert!(query(&app, &[HASH]).await.is_empty());
+}
+
+async fn insert_then_finish(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    create(&app, HASH).await;
+
+    let records = query(&app, &[HASH]).await;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["build_id"], BUILD_ID);
+    assert_eq!(records[0]["success"], Value::Null);
+
+    let (status, _) = send(&app, Method::PUT, &finish_uri(HASH), Some(&result(true))).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let records = query(&app, &[HASH]).await;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["success"], true);
+    assert_eq!(records[0]["finished_at"], "2024-04-01T12:05:00Z");
+}
+
+async fn query_only_returns_requested_hashes(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    create(&app, HASH).await;
+    create(&app, OTHER_HASH).await;
+
+    let records = query(&app, &[OTHER_HASH]).await;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["hash"], OTHER_HASH);
+
+    assert_eq!(query(&app, &[HASH, OTHER_HASH]).await.len(), 2);
+}
+
+async fn query_string(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    create(&app, HASH).await;
+    create(&app, OTHER_HASH).await;
+
+    let uri = format!("{BUILDS_URI}?hashes={HASH},{OTHER_HASH}");
+    let (status, body) = send(&app, Method::GET, &uri, None).await;
+    assert_eq!(status, StatusCode::OK);
+    let records: Vec<Value> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(records.len(), 2);
+
+    let (status, body) = send(&app, Method::GET, BUILDS_URI, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"[]");
+}
+
+async fn bulk_query(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    create(&app, HASH).await;
+    create(&app, OTHER_HASH).await;
+
+    // Enough to be split into several queries, with records in the first
+    // and last
+    let mut hashes: Vec<_> = (2..MAX_QUERY_HASHES).map(|i| format!("{i:032}")).collect();
+    hashes.insert(0, HASH.to_string());
+    hashes.push(OTHER_HASH.to_string());
+    let hashes: Vec<_> = hashes.iter().map(String::as_str).collect();
+    let records = query(&app, &hashes).await;
+    let mut found: Vec<_> = records.iter().map(|r| r["hash"].clone()).collect();
+    found.sort_by_key(|h| h.to_string());
+    assert_eq!(found, [HASH, OTHER_HASH]);
+
+    let hashes: Vec<_> = (0..=MAX_QUERY_HASHES).map(|i| format!("{i:032}")).collect();
+    let body = json!({ "hashes": hashes });
+    let (status, body) = send(&app, Method::POST, QUERY_URI, Some(&body)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(&body), "too_many_hashes");
+}
+
+async fn finish_missing_entry(backend: Backend) {
+    let app = TestApp::new(backend).await;
+
+    // StoreError::UpdateMissingEntry
+    let (status, body) = send(&app, Method::PUT, &finish_uri(HASH), Some(&result(false))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(error_code(&body), "not_found");
+    assert!(query(&app, &[HASH]).await.is_empty());
+}
+
+async fn writes_require_token(backend: Backend) {
+    let app = TestApp::new(backend).await;
+
+    let body = record(HASH);
+    let (status, resp) = send_as(&app, None, Method::POST, BUILDS_URI, Some(&body)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(&resp), "unauthorized");
+
+    let token = Some("cit_notarealtoken");
+    let (status, _) = send_as(&app, token, Method::POST, BUILDS_URI, Some(&body)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    create(&app, HASH).await;
+    let (status, _) = send_as(
+        &app,
+        None,
+        Method::PUT,
+        &finish_uri(HASH),
+        Some(&result(true)),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Reads stay open
+    assert_eq!(query(&app, &[HASH]).await[0]["success"], Value::Null);
+    let uri = format!("{BUILDS_URI}?hashes={HASH}");
+    let (status, _) = send_as(&app, None, Method::GET, &uri, None).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+async fn revoked_token(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let token = app.store.list_tokens().await.unwrap().remove(0);
+    app.store.revoke_token(token.id).await.unwrap();
+
+    let (status, _) = send(&app, Method::POST, BUILDS_URI, Some(&record(HASH))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+async fn tokens_are_scoped_to_pipeline(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let other = create_token(app.store.as_ref(), "other-pipeline").await;
+    create(&app, HASH).await;
+
+    // Can't finish another pipeline's build
+    let other = Some(other.as_str());
+    let (status, _) = send_as(
+        &app,
+        other,
+        Method::PUT,
+        &finish_uri(HASH),
+        Some(&result(true)),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(query(&app, &[HASH]).await[0]["success"], Value::Null);
+
+    let (status, _) = send(&app, Method::PUT, &finish_uri(HASH), Some(&result(true))).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+}
+
+async fn malformed_json(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(BUILDS_URI)
+        .header(header::AUTHORIZATION, format!("Bearer {}", app.token))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from("{\"hash\": "))
+        .unwrap();
+
+    let resp = app.router.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(error_code(&body), "invalid_request");
+}
+
+async fn missing_fields(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let body = json!({ "hash": HASH });
+    let (status, body) = send(&app, Method::POST, BUILDS_URI, Some(&body)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error_code(&body), "invalid_request");
+
+    let body = json!([HASH]);
+    let (status, _) = send(&app, Method::POST, QUERY_URI, Some(&body)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let body = json!({ "success": true });
+    let (status, _) = send(&app, Method::PUT, &finish_uri(HASH), Some(&body)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+async fn missing_content_type(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(BUILDS_URI)
+        .header(header::AUTHORIZATION, format!("Bearer {}", app.token))
+        .body(Body::from(record(HASH).to_string()))
+        .unwrap();
+
+    let resp = app.router.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+async fn error_body(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let req = Request::builder()
+        .method(Method::PUT)
+        .uri(finish_uri(HASH))
+        .header(header::AUTHORIZATION, format!("Bearer {}", app.token))
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(REQUEST_ID_HEADER, "my-request")
+        .body(Body::from(result(true).to_string()))
+        .unwrap();
+
+    let resp = app.router.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(resp.headers()[REQUEST_ID_HEADER], "my-request");
+    assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+    let content_length = resp.headers().get(header::CONTENT_LENGTH).cloned();
+
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    if let Some(content_length) = content_length {
+        assert_eq!(content_length, body.len().to_string().as_str());
+    }
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"]["code"], "not_found");
+    assert!(body["error"]["message"].is_string());
+    assert_eq!(body["request_id"], "my-request");
+}
+
+async fn request_id_is_generated(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let req = Request::builder()
+        .uri("/v1/no-such-route")
+        .body(Body::empty())
+        .unwrap();
+
+    let resp = app.router.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let request_id = resp.headers()[REQUEST_ID_HEADER]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(!request_id.is_empty());
+
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"]["code"], "not_found");
+    assert_eq!(body["request_id"], request_id);
+}
+
+fn lease_uri(hash: &str) -> String {
+    format!("/v1/build-leases/{hash}")
+}
+
+fn claim(build_id: &str, ttl_secs: u64) -> Value {
+    json!({
+        "build_id": build_id,
+        "build_url": format!("https://buildkite.com/org/pipeline/builds/{build_id}"),
+        "ttl_secs": ttl_secs,
+    })
+}
+
+async fn claim_as(app: &TestApp, token: &str, build_id: &str, ttl_secs: u64) -> Value {
+    let body = claim(build_id, ttl_secs);
+    let uri = lease_uri(HASH);
+    let (status, body) = send_as(app, Some(token), Method::POST, &uri, Some(&body)).await;
+    assert_eq!(status, StatusCode::OK);
+    serde_json::from_slice(&body).unwrap()
+}
+
+async fn lease_claim_and_release(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let other = create_token(app.store.as_ref(), "other-pipeline").await;
+
+    let (status, body) = send(&app, Method::GET, &lease_uri(HASH), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(error_code(&body), "not_found");
+
+    let lease = claim_as(&app, &app.token, BUILD_ID, 60).await;
+    assert_eq!(lease["build_id"], BUILD_ID);
+    assert_eq!(lease["pipeline_slug"], PIPELINE);
+
+    // Another build sees who's building it
+    let held = claim_as(&app, &other, "other-build", 60).await;
+    assert_eq!(held["build_id"], BUILD_ID);
+    let (status, body) = send(&app, Method::GET, &lease_uri(HASH), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let found: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(found["build_id"], BUILD_ID);
+
+    // Renewing keeps the original acquisition time
+    let renewed = claim_as(&app, &app.token, BUILD_ID, 120).await;
+    assert_eq!(renewed["acquired_at"], lease["acquired_at"]);
+    assert_ne!(renewed["expires_at"], lease["expires_at"]);
+
+    // Only the holder can release it
+    let uri = format!("{}/{BUILD_ID}", lease_uri(HASH));
+    let (status, _) = send_as(&app, Some(&other), Method::DELETE, &uri, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = send(&app, Method::DELETE, &uri, None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let lease = claim_as(&app, &other, "other-build", 60).await;
+    assert_eq!(lease["build_id"], "other-build");
+}
+
+async fn expired_lease_is_taken_over(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let other = create_token(app.store.as_ref(), "other-pipeline").await;
+
+    claim_as(&app, &app.token, BUILD_ID, 1).await;
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+
+    let (status, _) = send(&app, Method::GET, &lease_uri(HASH), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let lease = claim_as(&app, &other, "other-build", 60).await;
+    assert_eq!(lease["build_id"], "other-build");
+}
+
+async fn lease_ttl_is_limited(backend: Backend) {
+    let app = TestApp::new(backend).await;
+
+    for ttl_secs in [0, MAX_LEASE_TTL_SECS + 1] {
+        let body = claim(BUILD_ID, ttl_secs);
+        let (status, body) = send(&app, Method::POST, &lease_uri(HASH), Some(&body)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(&body), "invalid_request");
+    }
+
+    let body = claim(BUILD_ID, 60);
+    let (status, _) = send_as(&app, None, Method::POST, &lease_uri(HASH), Some(&body)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+const BUILD_URL: &str = "https://buildkite.com/org/my-pipeline/builds/1";
+
+async fn schedule(app: &TestApp, step_key: &str, hash: &str) {
+    let body = json!({
+        "build_id": BUILD_ID,
+        "build_url": BUILD_URL,
+        "steps": [{"step_key": step_key, "hash": hash}],
+    });
+    let uri = "/v1/scheduled-builds";
+    let (status, _) = send(app, Method::POST, uri, Some(&body)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+}
+
+fn job(step_key: &str, state: &str) -> Value {
+    json!({
+        "step_key": step_key,
+        "state": state,
+        "started_at": "2024-04-01 12:00:00 UTC",
+        "finished_at": "2024-04-01 12:05:00 UTC",
+    })
+}
+
+fn job_finished(job: Value) -> Value {
+    json!({
+        "event": "job.finished",
+        "job": job,
+        "build": {"id": BUILD_ID, "web_url": BUILD_URL},
+        "pipeline": {"slug": PIPELINE},
+    })
+}
+
+async fn send_webhook(app: &TestApp, headers: &[(&str, String)], body: &Value) -> StatusCode {
+    let mut req = Request::builder()
+        .method(Method::POST)
+        .uri("/v1/webhooks/buildkite")
+        .header(header::CONTENT_TYPE, "application/json");
+    for (name, value) in headers {
+        req = req.header(*name, value);
+    }
+    let req = req.body(Body::from(body.to_string())).unwrap();
+
+    app.router.clone().oneshot(req).await.unwrap().status()
+}
+
+async fn webhook(app: &TestApp, body: &Value) {
+    let token = [("x-buildkite-token", WEBHOOK_TOKEN.to_string())];
+    assert_eq!(
+        send_webhook(app, &token, body).await,
+        StatusCode::NO_CONTENT
+    );
+}
+
+fn signature(token: &str, timestamp: i64, body: &Value) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(token.as_bytes()).unwrap();
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+    let signature = hex::encode(mac.finalize().into_bytes());
+    format!("timestamp={timestamp},signature={signature}")
+}
+
+async fn webhook_records_scheduled_builds(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    schedule(&app, "build-hello", HASH).await;
+
+    // Steps we didn't schedule are ignored
+    webhook(&app, &job_finished(job("build-other", "passed"))).await;
+    webhook(&app, &job_finished(job("build-hello", "passed"))).await;
+
+    let records = query(&app, &[HASH]).await;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["build_id"], BUILD_ID);
+    assert_eq!(records[0]["build_url"], BUILD_URL);
+    assert_eq!(records[0]["started_at"], "2024-04-01T12:00:00Z");
+    assert_eq!(records[0]["finished_at"], "2024-04-01T12:05:00Z");
+    assert_eq!(records[0]["success"], true);
+
+    // Already recorded, e.g. by `collect`
+    webhook(&app, &job_finished(job("build-hello", "failed"))).await;
+    let records = query(&app, &[HASH]).await;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["success"], true);
+}
+
+async fn webhook_build_finished(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    schedule(&app, "build-hello", HASH).await;
+    schedule(&app, "build-other", OTHER_HASH).await;
+
+    let body = json!({
+        "event": "build.finished",
+        "build": {
+            "id": BUILD_ID,
+            "web_url": BUILD_URL,
+            "jobs": [
+                job("build-hello", "failed"),
+                job("build-other", "canceled"),
+                {"type": "waiter"},
+            ],
+        },
+        "pipeline": {"slug": PIPELINE},
+    });
+    webhook(&app, &body).await;
+
+    let records = query(&app, &[HASH, OTHER_HASH]).await;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["hash"], HASH);
+    assert_eq!(records[0]["success"], false);
+}
+
+async fn webhook_skips_builds_done_elsewhere(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    schedule(&app, "build-hello", HASH).await;
+
+    // Another build held the lease and built it while this step waited
+    let other = create_token(app.store.as_ref(), "other-pipeline").await;
+    let mut body = record(HASH);
+    body["build_id"] = json!("other-build");
+    let (status, _) = send_as(&app, Some(&other), Method::POST, BUILDS_URI, Some(&body)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let uri = format!("/v1/derivation-builds/{HASH}/other-build");
+    let (status, _) = send_as(&app, Some(&other), Method::PUT, &uri, Some(&result(true))).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    webhook(&app, &job_finished(job("build-hello", "passed"))).await;
+    let records = query(&app, &[HASH]).await;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["build_id"], "other-build");
+}
+
+async fn webhook_authentication(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    schedule(&app, "build-hello", HASH).await;
+    let body = job_finished(job("build-hello", "passed"));
+    let now = chrono::Utc::now().timestamp();
+
+    let rejected = [
+        vec![],
+        vec![("x-buildkite-token", "wrong".to_string())],
+        vec![("x-buildkite-signature", signature("wrong", now, &body))],
+        vec![(
+            "x-buildkite-signature",
+            signature(WEBHOOK_TOKEN, now - 3600, &body),
+        )],
+        vec![("x-buildkite-signature", "signature=abc".to_string())],
+    ];
+    for headers in rejected {
+        let status = send_webhook(&app, &headers, &body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    assert!(query(&app, &[HASH]).await.is_empty());
+
+    let headers = [(
+        "x-buildkite-signature",
+        signature(WEBHOOK_TOKEN, now, &body),
+    )];
+    let status = send_webhook(&app, &headers, &body).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(query(&app, &[HASH]).await.len(), 1);
+}
+
+async fn webhooks_disabled_without_token(backend: Backend) {
+    let store = backend.store().await;
+    let router = Server::new(([127, 0, 0, 1], 0).into(), store.store.clone()).router();
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri("/v1/webhooks/buildkite")
+        .header("x-buildkite-token", WEBHOOK_TOKEN)
+        .body(Body::from(
+            job_finished(job("build-hello", "passed")).to_string(),
+        ))
+        .unwrap();
+
+    let resp = router.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+async fn metrics(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    create(&app, HASH).await;
+    let (status, _) = send(&app, Method::PUT, &finish_uri(HASH), Some(&result(true))).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    query(&app, &[HASH, OTHER_HASH]).await;
+
+    let (status, body) = send(&app, Method::GET, "/metrics", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let body = String::from_utf8(body).unwrap();
+
+    // Metrics are shared between tests, so only check that series exist
+    for series in [
+        r#"ci_http_requests_total{method="POST",route="/v1/derivation-builds/query",status="200"}"#,
+        r#"ci_http_requests_total{method="PUT",route="/v1/derivation-builds/:hash/:build_id",status="204"}"#,
+        r#"ci_http_request_duration_seconds_count{method="POST",route="/v1/derivation-builds"}"#,
+        r#"ci_queried_hashes_total{result="hit"}"#,
+        r#"ci_queried_hashes_total{result="miss"}"#,
+        r#"ci_builds_recorded_total{success="true"}"#,
+    ] {
+        assert!(body.contains(series), "missing {series} in:\n{body}");
+    }
+}
+
+async fn health_and_readiness(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    for uri in ["/healthz", "/readyz"] {
+        let (status, body) = send_as(&app, None, Method::GET, uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"ok");
+    }
+}
+
+#[tokio::test]
+async fn not_ready_until_migrated() {
+    let file = NamedTempFile::new().unwrap();
+    let store = Arc::new(SqliteStore::open(file.path()).unwrap());
+    let router = Server::new(([127, 0, 0, 1], 0).into(), store.clone()).router();
+    let get = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+    let resp = router.clone().oneshot(get("/healthz")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = router.clone().oneshot(get("/readyz")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(error_code(&body), "unavailable");
+    // Checking readiness doesn't change the database
+    let conn = rusqlite::Connection::open(file.path()).unwrap();
+    let tables: i64 = conn
+        .query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(tables, 0);
+
+    store.migrate().await.unwrap();
+    let resp = router.oneshot(get("/readyz")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+async fn starting_twice_records_once(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    create(&app, HASH).await;
+    create(&app, HASH).await;
+
+    assert_eq!(query(&app, &[HASH]).await.len(), 1);
+}
+
+async fn timestamps_are_stored_in_utc(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let mut body = record(HASH);
+    body["started_at"] = json!("2024-04-01T14:00:00.25+02:00");
+    create_record(&app, &body).await;
+    // Later, though it's earlier in local time
+    let mut body = record(HASH);
+    body["build_id"] = json!("b2");
+    body["started_at"] = json!("2024-04-01T11:30:00-01:00");
+    create_record(&app, &body).await;
+
+    let result = json!({ "finished_at": "2024-04-01T14:05:00+02:00", "success": true });
+    let (status, _) = send(&app, Method::PUT, &finish_uri(HASH), Some(&result)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let history = history(&app, &format!("hash={HASH}")).await;
+    let builds = history["builds"].as_array().unwrap();
+    assert_eq!(builds[0]["build_id"], "b2");
+    assert_eq!(builds[0]["started_at"], "2024-04-01T12:30:00Z");
+    assert_eq!(builds[1]["build_id"], BUILD_ID);
+    assert_eq!(builds[1]["started_at"], "2024-04-01T12:00:00.250Z");
+    assert_eq!(builds[1]["finished_at"], "2024-04-01T12:05:00Z");
+    assert_eq!(builds[1]["duration_secs"], 299);
+}
+
+async fn prune(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let now = chrono::Utc::now();
+    let days_ago = |days| now - chrono::Duration::try_days(days).unwrap();
+    let builds = [
+        ("b1", 100, Some(true)),
+        ("b2", 50, Some(false)),
+        ("b3", 5, Some(false)),
+        ("b4", 1, Some(true)),
+        ("b5", 0, None),
+    ];
+    for (build_id, age, success) in builds {
+        let mut body = record(HASH);
+        body["build_id"] = json!(build_id);
+        body["started_at"] = json!(days_ago(age));
+        send(&app, Method::POST, BUILDS_URI, Some(&body)).await;
+        if let Some(success) = success {
+            let uri = format!("/v1/derivation-builds/{HASH}/{build_id}");
+            let result = json!({ "finished_at": days_ago(age), "success": success });
+            send(&app, Method::PUT, &uri, Some(&result)).await;
+        }
+    }
+
+    let pruned_ids = |pruned: Vec<server::store::BuildRecord>| {
+        let mut ids: Vec<_> = pruned.into_iter().map(|r| r.build_id).collect();
+        ids.sort();
+        ids
+    };
+
+    let old_failures = RetentionPolicy {
+        failures_before: Some(days_ago(30)),
+        ..Default::default()
+    };
+    let pruned = app.store.prune(&old_failures, true).await.unwrap();
+    assert_eq!(pruned_ids(pruned), ["b2"]);
+
+    // The latest success is kept, however many newer records there are
+    let latest_only = RetentionPolicy {
+        keep_per_hash: Some(1),
+        ..Default::default()
+    };
+    let pruned = app.store.prune(&latest_only, true).await.unwrap();
+    assert_eq!(pruned_ids(pruned), ["b1", "b2", "b3"]);
+    assert_eq!(query(&app, &[HASH]).await.len(), 5);
+
+    app.store.prune(&latest_only, false).await.unwrap();
+    let records = query(&app, &[HASH]).await;
+    let mut kept: Vec<_> = records.iter().map(|r| r["build_id"].clone()).collect();
+    kept.sort_by_key(|id| id.to_string());
+    assert_eq!(kept, ["b4", "b5"]);
+}
+
+async fn pruning_keeps_running_builds(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let now = chrono::Utc::now();
+    let hours_ago = |hours| now - chrono::Duration::try_hours(hours).unwrap();
+    // A long build, started before builds of the same derivation that have
+    // since failed, and one that just started
+    let builds = [
+        ("b1", 3, None),
+        ("b2", 2, Some(false)),
+        ("b3", 1, Some(false)),
+        ("b4", 0, None),
+    ];
+    for (build_id, age, success) in builds {
+        let mut body = record(HASH);
+        body["build_id"] = json!(build_id);
+        body["started_at"] = json!(hours_ago(age));
+        send(&app, Method::POST, BUILDS_URI, Some(&body)).await;
+        if let Some(success) = success {
+            let uri = format!("/v1/derivation-builds/{HASH}/{build_id}");
+            let result = json!({ "finished_at": hours_ago(age), "success": success });
+            send(&app, Method::PUT, &uri, Some(&result)).await;
+        }
+    }
+
+    let latest_only = RetentionPolicy {
+        keep_per_hash: Some(1),
+        ..Default::default()
+    };
+    let pruned = app.store.prune(&latest_only, false).await.unwrap();
+    let pruned: Vec<_> = pruned.iter().map(|r| r.build_id.as_str()).collect();
+    assert_eq!(pruned, ["b2"]);
+
+    // The running builds can still record their results
+    for build_id in ["b1", "b4"] {
+        let uri = format!("/v1/derivation-builds/{HASH}/{build_id}");
+        let (status, _) = send(&app, Method::PUT, &uri, Some(&result(true))).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+}
+
+async fn history(app: &TestApp, params: &str) -> Value {
+    let uri = format!("/v1/derivation-builds/history?{params}");
+    let (status, body) = send(app, Method::GET, &uri, None).await;
+    assert_eq!(status, StatusCode::OK);
+    serde_json::from_slice(&body).unwrap()
+}
+
+async fn build_history(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let start = chrono::Utc::now() - chrono::Duration::try_days(1).unwrap();
+    let builds = [
+        ("b1", HASH, 0, Some((100, true))),
+        ("b2", HASH, 1, Some((10, false))),
+        ("b3", HASH, 2, Some((300, true))),
+        ("b4", HASH, 3, None),
+        ("b5", OTHER_HASH, 4, Some((200, true))),
+    ];
+    for (build_id, hash, hours, result) in builds {
+        let started_at = start + chrono::Duration::try_hours(hours).unwrap();
+        let mut body = record(hash);
+        body["build_id"] = json!(build_id);
+        body["started_at"] = json!(started_at);
+        body["tag"] = json!("hello");
+        send(&app, Method::POST, BUILDS_URI, Some(&body)).await;
+        if let Some((secs, success)) = result {
+            let uri = format!("/v1/derivation-builds/{hash}/{build_id}");
+            let finished_at = started_at + chrono::Duration::try_seconds(secs).unwrap();
+            let result = json!({ "finished_at": finished_at, "success": success });
+            send(&app, Method::PUT, &uri, Some(&result)).await;
+        }
+    }
+
+    let by_hash = history(&app, &format!("hash={HASH}&limit=2")).await;
+    let ids: Vec<_> = by_hash["builds"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|b| b["build_id"].clone())
+        .collect();
+    assert_eq!(ids, ["b4", "b3"]);
+    assert_eq!(by_hash["builds"][0]["duration_secs"], Value::Null);
+    assert_eq!(by_hash["builds"][1]["duration_secs"], 300);
+    assert_eq!(by_hash["builds"][1]["tag"], "hello");
+
+    // Stats cover every build, not just those listed
+    let stats = &by_hash["stats"];
+    assert_eq!(stats["total"], 4);
+    assert_eq!(stats["finished"], 3);
+    assert_eq!(stats["succeeded"], 2);
+    assert!((stats["success_rate"].as_f64().unwrap() - 2.0 / 3.0).abs() < 1e-9);
+    assert_eq!(stats["last_success"]["build_id"], "b3");
+    assert_eq!(stats["last_failure"]["build_id"], "b2");
+    // Failures don't count towards durations
+    assert_eq!(stats["p50_duration_secs"], 100);
+    assert_eq!(stats["p95_duration_secs"], 300);
+
+    // A tag covers every derivation built as it
+    let by_tag = history(&app, "tag=hello").await;
+    assert_eq!(by_tag["builds"].as_array().unwrap().len(), 5);
+    assert_eq!(by_tag["stats"]["total"], 5);
+    assert_eq!(by_tag["stats"]["last_success"]["build_id"], "b5");
+    assert_eq!(by_tag["stats"]["p50_duration_secs"], 200);
+    assert_eq!(by_tag["stats"]["p95_duration_secs"], 300);
+
+    let unknown = history(&app, "tag=goodbye").await;
+    assert_eq!(unknown["builds"], json!([]));
+    assert_eq!(unknown["stats"]["total"], 0);
+    assert_eq!(unknown["stats"]["success_rate"], Value::Null);
+    assert_eq!(unknown["stats"]["p50_duration_secs"], Value::Null);
+}
+
+async fn build_history_needs_hash_or_tag(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    for params in ["", &format!("hash={HASH}&tag=hello")] {
+        let uri = format!("/v1/derivation-builds/history?{params}");
+        let (status, body) = send(&app, Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(&body), "invalid_request");
+    }
+}
+
+async fn webhook_records_tags(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let body = json!({
+        "build_id": BUILD_ID,
+        "build_url": BUILD_URL,
+        "steps": [{"step_key": "build-hello", "hash": HASH, "tag": "hello"}],
+    });
+    let (status, _) = send(&app, Method::POST, "/v1/scheduled-builds", Some(&body)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    webhook(&app, &job_finished(job("build-hello", "passed"))).await;
+
+    let records = query(&app, &[HASH]).await;
+    assert_eq!(records[0]["tag"], "hello");
+}
+
+fn build_uri(build_id: &str) -> String {
+    format!("/v1/builds/{build_id}")
+}
+
+fn step_uri(build_id: &str, step_key: &str) -> String {
+    format!("/v1/builds/{build_id}/steps/{step_key}")
+}
+
+fn build_report(commit_sha: &str) -> Value {
+    json!({
+        "url": BUILD_URL,
+        "commit_sha": commit_sha,
+        "branch": "main",
+        "pipeline_id": "0190f1e2-0000-4000-8000-000000000000",
+        "repository": "git@github.com:org/repo.git",
+    })
+}
+
+async fn builds_and_steps(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let (status, _) = send(
+        &app,
+        Method::PUT,
+        &build_uri(BUILD_ID),
+        Some(&build_report("abc123")),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    create(&app, HASH).await;
+
+    let steps = [
+        ("build-hello", json!({"hash": HASH, "success": true})),
+        // Its build wasn't recorded, so there's nothing to link to
+        ("build-other", json!({"hash": OTHER_HASH, "success": true})),
+        ("rustfmt", json!({"label": ":rust: fmt", "success": false})),
+    ];
+    for (key, result) in steps {
+        let (status, _) = send(&app, Method::PUT, &step_uri(BUILD_ID, key), Some(&result)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+
+    let uri = "/v1/builds?branch=main&commit_sha=abc123";
+    let (status, body) = send(&app, Method::GET, uri, None).await;
+    assert_eq!(status, StatusCode::OK);
+    let builds: Vec<Value> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(builds.len(), 1);
+    assert_eq!(builds[0]["id"], BUILD_ID);
+    assert_eq!(builds[0]["pipeline_slug"], PIPELINE);
+    assert_eq!(builds[0]["commit_sha"], "abc123");
+
+    let steps = builds[0]["steps"].as_array().unwrap();
+    let keys: Vec<_> = steps.iter().map(|s| s["step_key"].clone()).collect();
+    assert_eq!(keys, ["build-hello", "build-other", "rustfmt"]);
+    assert_eq!(steps[0]["hash"], HASH);
+    assert_eq!(steps[1]["hash"], Value::Null);
+    assert_eq!(steps[2]["label"], ":rust: fmt");
+    assert_eq!(steps[2]["success"], false);
+
+    for uri in ["/v1/builds?branch=other", "/v1/builds?commit_sha=def456"] {
+        let (_, body) = send(&app, Method::GET, uri, None).await;
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), json!([]));
+    }
+}
+
+async fn steps_need_a_recorded_build(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let result = json!({"success": true});
+    let (status, _) = send(
+        &app,
+        Method::PUT,
+        &step_uri(BUILD_ID, "lint"),
+        Some(&result),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Nor can other pipelines record steps of our builds
+    send(
+        &app,
+        Method::PUT,
+        &build_uri(BUILD_ID),
+        Some(&build_report("abc123")),
+    )
+    .await;
+    let other = create_token(app.store.as_ref(), "other-pipeline").await;
+    let uri = step_uri(BUILD_ID, "lint");
+    let (status, _) = send_as(&app, Some(&other), Method::PUT, &uri, Some(&result)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+async fn pruning_unlinks_step_runs(backend: Backend) {
+    let test_store = backend.store().await;
+    let store = &test_store.store;
+
+    let record: server::store::BuildRecord = serde_json::from_value(record(HASH)).unwrap();
+    store.insert_start(PIPELINE, &record).await.unwrap();
+    let finished = serde_json::from_value(result(false)).unwrap();
+    store
+        .mark_finish(PIPELINE, HASH, BUILD_ID, &finished)
+        .await
+        .unwrap();
+    let report = serde_json::from_value(build_report("abc123")).unwrap();
+    store
+        .record_build(PIPELINE, BUILD_ID, &report)
+        .await
+        .unwrap();
+    let result = serde_json::from_value(json!({"hash": HASH})).unwrap();
+    store
+        .record_step_run(PIPELINE, BUILD_ID, "build-hello", &result)
+        .await
+        .unwrap();
+
+    let policy = RetentionPolicy {
+        keep_per_hash: Some(0),
+        ..Default::default()
+    };
+    let pruned = store.prune(&policy, false).await.unwrap();
+    assert_eq!(pruned.len(), 1);
+
+    let runs = store.find_step_runs(&[BUILD_ID.to_string()]).await.unwrap();
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0].hash, None);
+}
+
+async fn get_page(app: &TestApp, uri: &str) -> String {
+    let resp = app
+        .router
+        .clone()
+        .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let content_type = resp.headers()[header::CONTENT_TYPE].to_str().unwrap();
+    assert!(content_type.starts_with("text/html"), "{content_type}");
+
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+async fn dashboard(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let mut report = build_report("abc123def4567890");
+    report["branch"] = json!("<main>");
+    send(&app, Method::PUT, &build_uri(BUILD_ID), Some(&report)).await;
+    let mut body = record(HASH);
+    body["tag"] = json!("hello");
+    send(&app, Method::POST, BUILDS_URI, Some(&body)).await;
+    send(&app, Method::PUT, &finish_uri(HASH), Some(&result(false))).await;
+    let step = json!({"label": "hello", "hash": HASH, "success": false});
+    let uri = step_uri(BUILD_ID, "build-hello");
+    send(&app, Method::PUT, &uri, Some(&step)).await;
+
+    let page = get_page(&app, "/dashboard").await;
+    assert!(
+        page.contains(&format!("{PIPELINE} / &lt;main&gt;")),
+        "{page}"
+    );
+    assert!(page.contains("abc123def456<"), "{page}");
+    assert!(page.contains("1 failed"), "{page}");
+    assert!(
+        page.contains(&format!("/dashboard/history?hash={HASH}")),
+        "{page}"
+    );
+
+    let page = get_page(&app, "/dashboard?branch=other").await;
+    assert!(page.contains("No builds recorded yet."), "{page}");
+
+    let page = get_page(&app, "/dashboard/history?tag=hello").await;
+    assert!(page.contains("History of hello"), "{page}");
+    assert!(page.contains("0%"), "{page}");
+    assert!(page.contains("5m 0s"), "{page}");
+
+    let page = get_page(&app, "/dashboard/failing").await;
+    assert!(page.contains("/dashboard/history?tag=hello"), "{page}");
+}
+
+async fn failing_targets(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let builds = [
+        // hello failed, then was fixed
+        ("b1", HASH, "hello", "2024-04-01T12:00:00Z", false),
+        ("b2", OTHER_HASH, "hello", "2024-04-02T12:00:00Z", true),
+        // goodbye passed, then broke
+        ("b3", HASH, "goodbye", "2024-04-01T12:00:00Z", true),
+        ("b4", OTHER_HASH, "goodbye", "2024-04-02T12:00:00Z", false),
+    ];
+    for (build_id, hash, tag, finished_at, success) in builds {
+        let mut body = record(hash);
+        body["build_id"] = json!(build_id);
+        body["tag"] = json!(tag);
+        send(&app, Method::POST, BUILDS_URI, Some(&body)).await;
+        let uri = format!("/v1/derivation-builds/{hash}/{build_id}");
+        let result = json!({ "finished_at": finished_at, "success": success });
+        send(&app, Method::PUT, &uri, Some(&result)).await;
+    }
+
+    let failing = app.store.failing_builds().await.unwrap();
+    let failing: Vec<_> = failing.iter().map(|r| r.build_id.as_str()).collect();
+    assert_eq!(failing, ["b4"]);
+
+    let page = get_page(&app, "/dashboard/failing").await;
+    assert!(page.contains("tag=goodbye"), "{page}");
+    assert!(!page.contains("tag=hello"), "{page}");
+}
+
+async fn root_redirects_to_dashboard(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let resp = app
+        .router
+        .clone()
+        .oneshot(Request::get("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    assert_eq!(resp.headers()[header::LOCATION], "/dashboard");
+}
+
+async fn get_badge(app: &TestApp, uri: &str) -> String {
+    let resp = app
+        .router
+        .clone()
+        .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()[header::CONTENT_TYPE], "image/svg+xml");
+    assert_eq!(resp.headers()[header::CACHE_CONTROL], "no-cache");
+
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+async fn pipeline_badge(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let uri = format!("/badges/pipelines/{PIPELINE}?branch=main");
+    let badge = get_badge(&app, &uri).await;
+    assert!(badge.contains(r#"aria-label="build: unknown""#), "{badge}");
+
+    send(
+        &app,
+        Method::PUT,
+        &build_uri(BUILD_ID),
+        Some(&build_report("abc123")),
+    )
+    .await;
+    for (key, success) in [("build-hello", true), ("rustfmt", false)] {
+        let result = json!({ "success": success });
+        send(&app, Method::PUT, &step_uri(BUILD_ID, key), Some(&result)).await;
+    }
+    let badge = get_badge(&app, &uri).await;
+    assert!(badge.contains(r#"aria-label="build: failing""#), "{badge}");
+
+    // A build that hasn't been collected yet doesn't count
+    let next_build = "018e9c2f-3f9a-4a7c-9a0e-8b1f2f6f1e2e";
+    send(
+        &app,
+        Method::PUT,
+        &build_uri(next_build),
+        Some(&build_report("def456")),
+    )
+    .await;
+    let badge = get_badge(&app, &uri).await;
+    assert!(badge.contains(r#"aria-label="build: failing""#), "{badge}");
+
+    let result = json!({"success": true});
+    send(
+        &app,
+        Method::PUT,
+        &step_uri(next_build, "rustfmt"),
+        Some(&result),
+    )
+    .await;
+    let badge = get_badge(&app, &format!("{uri}&label=CI")).await;
+    assert!(badge.contains(r#"aria-label="CI: passing""#), "{badge}");
+
+    let uri = format!("/badges/pipelines/{PIPELINE}?branch=other");
+    let badge = get_badge(&app, &uri).await;
+    assert!(badge.contains(r#"aria-label="build: unknown""#), "{badge}");
+}
+
+async fn derivation_badge(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    let tag = "packages.x86_64-linux.tool";
+    let uri = format!("/badges/derivations?tag={tag}");
+    let badge = get_badge(&app, &uri).await;
+    assert!(
+        badge.contains(&format!(r#"aria-label="{tag}: unknown""#)),
+        "{badge}"
+    );
+
+    let mut body = record(HASH);
+    body["tag"] = json!(tag);
+    send(&app, Method::POST, BUILDS_URI, Some(&body)).await;
+    send(&app, Method::PUT, &finish_uri(HASH), Some(&result(true))).await;
+    let badge = get_badge(&app, &uri).await;
+    assert!(
+        badge.contains(&format!(r#"aria-label="{tag}: passing""#)),
+        "{badge}"
+    );
+
+    let badge = get_badge(
+        &app,
+        &format!("/badges/derivations?hash={HASH}&label=%3Ctool%3E"),
+    )
+    .await;
+    assert!(
+        badge.contains(r#"aria-label="&lt;tool&gt;: passing""#),
+        "{badge}"
+    );
+
+    let (status, body) = send(&app, Method::GET, "/badges/derivations", None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(&body), "invalid_request");
+}
+
+async fn wait_for(router: Router, uri: String) -> Vec<Value> {
+    let req = Request::get(uri).body(Body::empty()).unwrap();
+    let resp = router.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+async fn wait_for_finished_builds(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    create(&app, HASH).await;
+    send(&app, Method::PUT, &finish_uri(HASH), Some(&result(true))).await;
+
+    let uri = format!("/v1/derivation-builds/wait?hashes={HASH}");
+    let records = wait_for(app.router.clone(), uri).await;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["success"], true);
+
+    // Nothing has finished since, so this times out
+    let started = Instant::now();
+    let uri = format!(
+        "/v1/derivation-builds/wait?hashes={HASH},{OTHER_HASH}&since=2024-04-01T12:05:00Z&timeout_secs=1"
+    );
+    assert_eq!(wait_for(app.router.clone(), uri).await, Vec::<Value>::new());
+    assert!(started.elapsed() >= Duration::from_secs(1));
+}
+
+async fn wait_until_builds_finish(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    create(&app, HASH).await;
+    create(&app, OTHER_HASH).await;
+
+    let uri = format!("/v1/derivation-builds/wait?hashes={HASH},{OTHER_HASH}&timeout_secs=30");
+    let waiting = tokio::spawn(wait_for(app.router.clone(), uri));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    send(&app, Method::PUT, &finish_uri(HASH), Some(&result(true))).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!waiting.is_finished());
+
+    let started = Instant::now();
+    send(
+        &app,
+        Method::PUT,
+        &finish_uri(OTHER_HASH),
+        Some(&result(false)),
+    )
+    .await;
+    let records = waiting.await.unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    let mut hashes: Vec<_> = records.iter().map(|r| r["hash"].clone()).collect();
+    hashes.sort_by_key(|h| h.to_string());
+    assert_eq!(hashes, [HASH, OTHER_HASH]);
+}
+
+async fn responses_match_api_types(backend: Backend) {
+    let app = TestApp::new(backend).await;
+    create(&app, HASH).await;
+    send(&app, Method::PUT, &finish_uri(HASH), Some(&result(true))).await;
+    send(
+        &app,
+        Method::PUT,
+        &build_uri(BUILD_ID),
+        Some(&build_report("abc123")),
+    )
+    .await;
+    let step = json!({"hash": HASH, "success": true});
+    send(
+        &app,
+        Method::PUT,
+        &step_uri(BUILD_ID, "build-hello"),
+        Some(&step),
+    )
+    .await;
+
+    let (_, body) = send(
+        &app,
+        Method::POST,
+        QUERY_URI,
+        Some(&json!({"hashes": [HASH]})),
+    )
+    .await;
+    let records: Vec<api::BuildRecord> = serde_json::from_slice(&body).unwrap();
+    assert!(records[0].succeeded());
+
+    let uri = format!("/v1/derivation-builds/history?hash={HASH}");
+    let (_, body) = send(&app, Method::GET, &uri, None).await;
+    let history: api::BuildHistory = serde_json::from_slice(&body).unwrap();
+    assert_eq!(history.stats.succeeded, 1);
+    assert_eq!(history.builds[0].duration_secs, Some(300));
+
+    let (_, body) = send(&app, Method::GET, "/v1/builds", None).await;
+    let builds: Vec<api::BuildSteps> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(builds[0].build.commit_sha, "abc123");
+    assert_eq!(builds[0].steps[0].hash.as_deref(), Some(HASH));
+
+    let body = claim(BUILD_ID, 60);
+    let (_, body) = send(&app, Method::POST, &lease_uri(HASH), Some(&body)).await;
+    let lease: api::BuildLease = serde_json::from_slice(&body).unwrap();
+    assert_eq!(lease.pipeline_slug, PIPELINE);
+
+    let (_, body) = send(&app, Method::GET, "/v1/nowhere", None).await;
+    let error: api::ErrorResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error.error.code, api::ErrorCode::NotFound);
+}
+
+store_tests!(
+    query_unknown_hash_is_empty,
+    insert_then_finish,
+    query_only_returns_requested_hashes,
+    query_string,
+    bulk_query,
+    finish_missing_entry,
+    writes_require_token,
+    revoked_token,
+    tokens_are_scoped_to_pipeline,
+    malformed_json,
+    missing_fields,
+    missing_content_type,
+    error_body,
+    request_id_is_generated,
+    lease_claim_and_release,
+    expired_lease_is_taken_over,
+    lease_ttl_is_limited,
+    webhook_records_scheduled_builds,
+    webhook_build_finished,
+    webhook_skips_builds_done_elsewhere,
+    webhook_authentication,
+    webhooks_disabled_without_token,
+    metrics,
+    health_and_readiness,
+    starting_twice_records_once,
+    timestamps_are_stored_in_utc,
+    prune,
+    pruning_keeps_running_builds,
+    build_history,
+    build_history_needs_hash_or_tag,
+    webhook_records_tags,
+    builds_and_steps,
+    steps_need_a_recorded_build,
+    pruning_unlinks_step_runs,
+    dashboard,
+    failing_targets,
+    root_redirects_to_dashboard,
+    pipeline_badge,
+    derivation_badge,
+    wait_for_finished_builds,
+    wait_until_builds_finish,
+    responses_match_api_types,
+);
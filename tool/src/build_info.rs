@@ -2,23 +2,25 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::process::Output;
 
+use api::BuildReport;
 use serde::{Deserialize, Serialize};
 
 use crate::buildkite::Step;
 use crate::flags::BuildkiteArgs;
 
 #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
-const SYSTEM: &str = "aarch64-darwin";
+pub const SYSTEM: &str = "aarch64-darwin";
 
 #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
-const SYSTEM: &str = "aarch64-linux";
+pub const SYSTEM: &str = "aarch64-linux";
 
 #[cfg(all(target_os = "macos", target_arch = "x86_64"))]
-const SYSTEM: &str = "x86_64-darwin";
+pub const SYSTEM: &str = "x86_64-darwin";
 
 #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
-const SYSTEM: &str = "x86_64-linux";
+pub const SYSTEM: &str = "x86_64-linux";
 
 #[derive(Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -43,16 +45,35 @@ pub struct FoundDerivationBuild {
 }
 
 impl FoundDerivationBuild {
-    pub fn label(&self) -> String {
-        let emoji = match self.build_type {
+    fn emoji(&self) -> &'static str {
+        match self.build_type {
             BuildTargetType::Package => "package",
             BuildTargetType::NixDarwinConfiguration => "mac",
             BuildTargetType::HomeManagerConfiguration => "house_with_garden",
             BuildTargetType::NixOSConfiguration => "nix",
             BuildTargetType::DevShell => "terminal",
-        };
+        }
+    }
+
+    pub fn label(&self) -> String {
+        format!(":hammer_and_wrench: :{}: {}", self.emoji(), self.name)
+    }
 
-        format!(":hammer_and_wrench: :{emoji}: {}", self.name)
+    pub fn cached_label(&self) -> String {
+        format!(
+            ":white_check_mark: :{}: {} (cached)",
+            self.emoji(),
+            self.name
+        )
+    }
+
+    /// The hash component of this derivation's output path
+    /// (i.e., `<hash>` in `/nix/store/<hash>-<name>`).
+    pub fn output_hash(&self) -> Option<String> {
+        let file_name = self.path.file_name()?.to_str()?;
+        let (hash, _) = file_name.split_once('-')?;
+
+        Some(hash.to_string())
     }
 }
 
@@ -62,6 +83,39 @@ struct PipelineInfo {
     slug: String,
 }
 
+/// The flake attribute of `path` within the CI config for `system`.
+pub fn config_attribute(system: &str, path: &str) -> String {
+    format!("ci.{system}.config.{path}")
+}
+
+/// The systems the flake's CI config is declared for.
+pub fn ci_systems(path: &Path) -> Result<Vec<String>, EvaluationError> {
+    let data = std::process::Command::new("nix")
+        .args(["eval", "--json", ".#ci", "--apply", "builtins.attrNames"])
+        .current_dir(path)
+        .output()
+        .map_err(EvaluationError::LaunchingNix)?;
+
+    Ok(serde_json::from_slice(&checked_output(data)?)?)
+}
+
+/// The stdout of a finished `nix eval`, passing its stderr on, or an error if
+/// it failed.
+fn checked_output(data: Output) -> Result<Vec<u8>, EvaluationError> {
+    let stderr = String::from_utf8_lossy(&data.stderr);
+    if !data.status.success() {
+        return Err(EvaluationError::NixStatus {
+            code: data.status.code(),
+            stderr: stderr.to_string(),
+        });
+    }
+    if !stderr.is_empty() {
+        eprintln!("{stderr}");
+    }
+
+    Ok(data.stdout)
+}
+
 #[derive(Deserialize)]
 pub struct BuildEvaluation {
     pub builds: HashMap<String, FoundDerivationBuild>,
@@ -72,27 +126,24 @@ pub struct BuildEvaluation {
 pub enum EvaluationError {
     #[error("Error running `nix eval`: {0}")]
     LaunchingNix(std::io::Error),
+    #[error("`nix eval` exited with {code:?}: {stderr}")]
+    NixStatus { code: Option<i32>, stderr: String },
     #[error("Error parsing JSON from nix: {0}")]
     ParsingJSON(#[from] serde_json::Error),
 }
 
 impl BuildEvaluation {
-    pub fn from_env(path: &Path) -> Result<Self, EvaluationError> {
-        let target = format!(".#ci.{SYSTEM}.config.evaluation");
+    pub fn from_env(path: &Path, system: &str) -> Result<Self, EvaluationError> {
+        let target = format!(".#{}", config_attribute(system, "evaluation"));
         let data = std::process::Command::new("nix")
             .args(["eval", "--json", &target])
             .current_dir(path)
             .output()
             .map_err(EvaluationError::LaunchingNix)?;
 
-        if !data.stderr.is_empty() {
-            let stderr = String::from_utf8(data.stderr).unwrap();
-            eprintln!("{stderr}");
-        }
-
         // TODO: will we need to dedupe in the future for (e.g.) default
         // targets?
-        let eval: Self = serde_json::from_slice(&data.stdout)?;
+        let eval: Self = serde_json::from_slice(&checked_output(data)?)?;
         Ok(eval)
     }
 }
@@ -133,6 +184,18 @@ impl CIRunState {
         }
     }
 
+    /// Describe this run as a build at `url`, for the cache server.
+    pub fn build_report(&self, url: String) -> BuildReport {
+        BuildReport {
+            url,
+            commit_sha: self.commit.clone(),
+            branch: self.branch.clone(),
+            tag: self.tag.clone(),
+            pipeline_id: Some(self.pipeline.id.clone()),
+            repository: Some(self.repo.clone()),
+        }
+    }
+
     pub fn write_to_file(&self, path: &Path) -> Result<(), CIRunStateWriteToFileError> {
         {
             let json_val = serde_json::to_value(self).unwrap();
@@ -26,7 +26,7 @@ pub struct Cli;
 impl Cli {
     fn run(self, args: &[&str], input: Option<&[u8]>) -> Result<Output, RunError> {
         let mut cmd = Command::new("buildkite-agent");
-        cmd.args(args);
+        cmd.args(args).stdout(Stdio::piped()).stderr(Stdio::piped());
         if input.is_some() {
             cmd.stdin(Stdio::piped());
         }
@@ -80,6 +80,32 @@ impl Cli {
         Ok(())
     }
 
+    pub fn meta_data_set(self, key: &str, value: &str) -> Result<(), RunError> {
+        log::debug!("setting buildkite meta-data `{key}`");
+        self.run(&["meta-data", "set", key], Some(value.as_bytes()))?;
+
+        Ok(())
+    }
+
+    /// Fetch a meta-data value from the current build, returning `None` if it
+    /// hasn't been set.
+    pub fn meta_data_get(self, key: &str) -> Result<Option<String>, RunError> {
+        log::debug!("getting buildkite meta-data `{key}`");
+        let output = self.run(&["meta-data", "get", key, "--default", ""], None)?;
+        let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+        Ok(Some(value).filter(|v| !v.is_empty()))
+    }
+
+    /// Fetch an attribute (e.g., `outcome`) of the step with the given key in
+    /// the current build.
+    pub fn step_get(self, attribute: &str, step_key: &str) -> Result<String, RunError> {
+        log::debug!("getting `{attribute}` of buildkite step `{step_key}`");
+        let output = self.run(&["step", "get", attribute, "--step", step_key], None)?;
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
     pub fn pipeline_upload_bytes(self, data: &[u8]) -> Result<(), RunError> {
         log::debug!("Uploading buildkite pipeline {} bytes", data.len());
         self.run(&["pipeline", "upload"], Some(data))?;
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 use serde::{Deserialize, Serialize};
 
@@ -10,15 +10,18 @@ fn default_timeout() -> u16 {
 
 #[derive(Deserialize, Serialize)]
 pub struct CommandStep {
+    /// Tags of the agents the step can run on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agents: Option<BTreeMap<String, String>>,
     #[serde(default)]
-    allow_dependency_failure: bool,
+    pub allow_dependency_failure: bool,
     pub command: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub concurrency_group: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub depends_on: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub env: Option<HashMap<String, String>>,
+    pub env: Option<BTreeMap<String, String>>,
     pub key: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub label: Option<String>,
@@ -34,15 +37,23 @@ impl CommandStep {
 
 #[derive(Default)]
 pub struct CommandStepBuilder {
+    agents: Option<BTreeMap<String, String>>,
     allow_dependency_failure: bool,
     concurrency_group: Option<String>,
     depends_on: Option<Vec<String>>,
-    env: Option<HashMap<String, String>>,
+    env: Option<BTreeMap<String, String>>,
     label: Option<String>,
     timeout_in_minutes: Option<u16>,
 }
 
 impl CommandStepBuilder {
+    pub fn set_agent(&mut self, key: String, val: String) -> &mut Self {
+        self.agents
+            .get_or_insert_with(BTreeMap::new)
+            .insert(key, val);
+        self
+    }
+
     pub fn set_allow_dependency_failure(&mut self, val: bool) -> &mut Self {
         self.allow_dependency_failure = val;
         self
@@ -60,7 +71,7 @@ impl CommandStepBuilder {
 
     pub fn set_env(&mut self, key: String, val: String) -> &mut Self {
         if self.env.is_none() {
-            self.env = Some(HashMap::new());
+            self.env = Some(BTreeMap::new());
         }
 
         self.env.as_mut().unwrap().insert(key, val);
@@ -81,6 +92,7 @@ impl CommandStepBuilder {
         CommandStep {
             key,
             command,
+            agents: self.agents,
             allow_dependency_failure: self.allow_dependency_failure,
             concurrency_group: self.concurrency_group,
             depends_on: self.depends_on,
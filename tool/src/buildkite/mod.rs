@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 use serde::{de::Visitor, Deserialize, Serialize};
 
@@ -22,6 +22,43 @@ pub enum Step {
     Wait(WaitStep),
 }
 
+impl Step {
+    /// Block steps don't have keys here
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Self::Block(_) => None,
+            Self::Command(s) => Some(&s.key),
+            Self::Trigger(s) => Some(&s.build.key),
+            Self::Wait(s) => Some(&s.key),
+        }
+    }
+
+    pub fn depends_on(&self) -> &[String] {
+        let depends_on = match self {
+            Self::Block(s) => &s.depends_on,
+            Self::Command(s) => &s.depends_on,
+            Self::Trigger(s) => &s.build.depends_on,
+            Self::Wait(s) => &s.depends_on,
+        };
+        depends_on.as_deref().unwrap_or_default()
+    }
+
+    pub fn depends_on_mut(&mut self) -> &mut Option<Vec<String>> {
+        match self {
+            Self::Block(s) => &mut s.depends_on,
+            Self::Command(s) => &mut s.depends_on,
+            Self::Trigger(s) => &mut s.build.depends_on,
+            Self::Wait(s) => &mut s.depends_on,
+        }
+    }
+
+    /// Whether this step waits for every step before it, and every step after
+    /// it waits for it.
+    pub fn is_barrier(&self) -> bool {
+        matches!(self, Self::Block(_) | Self::Wait(_))
+    }
+}
+
 pub enum BlockState {
     Passed,
     Failed,
@@ -77,22 +114,22 @@ impl<'de> Deserialize<'de> for BlockState {
 #[derive(Deserialize, Serialize)]
 pub struct BlockStep {
     /// Label of the block step
-    block: String,
+    pub block: String,
     blocked_state: BlockState,
     #[serde(skip_serializing_if = "Option::is_none")]
-    depends_on: Option<Vec<String>>,
+    pub depends_on: Option<Vec<String>>,
     // TODO: fields?
     #[serde(skip_serializing_if = "Option::is_none")]
-    label: Option<String>,
+    pub label: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct WaitStep {
-    allow_dependency_failure: bool,
-    continue_on_failure: bool,
+    pub allow_dependency_failure: bool,
+    pub continue_on_failure: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
-    depends_on: Option<Vec<String>>,
-    key: String,
+    pub depends_on: Option<Vec<String>>,
+    pub key: String,
 }
 
 impl WaitStep {
@@ -138,5 +175,5 @@ impl WaitStepBuilder {
 pub struct Pipeline {
     steps: Vec<Step>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    env: Option<HashMap<String, String>>,
+    env: Option<BTreeMap<String, String>>,
 }
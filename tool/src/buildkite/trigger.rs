@@ -1,30 +1,30 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Serialize)]
 pub struct TriggerStep {
     r#async: bool,
-    build: TriggerBuildSpec,
+    pub build: TriggerBuildSpec,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct TriggerBuildSpec {
-    key: String,
+    pub key: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     branch: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     commit: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    env: Option<HashMap<String, String>>,
+    env: Option<BTreeMap<String, String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    label: Option<String>,
+    pub label: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     message: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    meta_data: Option<HashMap<String, String>>,
+    meta_data: Option<BTreeMap<String, String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    depends_on: Option<Vec<String>>,
+    pub depends_on: Option<Vec<String>>,
 }
 
 impl TriggerBuildSpec {
@@ -37,10 +37,10 @@ impl TriggerBuildSpec {
 pub struct TriggerBuildSpecBuilder {
     branch: Option<String>,
     commit: Option<String>,
-    env: Option<HashMap<String, String>>,
+    env: Option<BTreeMap<String, String>>,
     label: Option<String>,
     message: Option<String>,
-    meta_data: Option<HashMap<String, String>>,
+    meta_data: Option<BTreeMap<String, String>>,
     depends_on: Option<Vec<String>>,
 }
 
@@ -57,7 +57,7 @@ impl TriggerBuildSpecBuilder {
 
     pub fn set_env(&mut self, key: String, val: String) -> &mut Self {
         if self.env.is_none() {
-            self.env = Some(HashMap::new());
+            self.env = Some(BTreeMap::new());
         }
 
         self.env.as_mut().unwrap().insert(key, val);
@@ -74,7 +74,7 @@ impl TriggerBuildSpecBuilder {
 
     pub fn set_meta_data(&mut self, key: String, val: String) -> &mut Self {
         if self.meta_data.is_none() {
-            self.meta_data = Some(HashMap::new());
+            self.meta_data = Some(BTreeMap::new());
         }
 
         self.meta_data.as_mut().unwrap().insert(key, val);
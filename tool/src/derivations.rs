@@ -0,0 +1,220 @@
+// Working out which of the derivations we build each CI command needs, from
+// the derivation graph, so its step can start as soon as those are built
+// instead of waiting for every build.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::process::Command;
+
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+
+use crate::build_info::{config_attribute, FoundDerivationBuild};
+
+#[derive(thiserror::Error, Debug)]
+pub enum DerivationGraphError {
+    #[error("error running `nix`: {0}")]
+    LaunchingNix(std::io::Error),
+    #[error("`nix {command}` exited with {code:?}: {stderr}")]
+    NixStatus {
+        command: &'static str,
+        code: Option<i32>,
+        stderr: String,
+    },
+    #[error("error parsing JSON from nix: {0}")]
+    ParsingJSON(#[from] serde_json::Error),
+}
+
+/// A derivation, as printed by `nix derivation show`.
+#[derive(Deserialize)]
+struct ShownDerivation {
+    #[serde(default)]
+    outputs: HashMap<String, ShownOutput>,
+    /// Keyed by derivation path (older versions of nix list the outputs
+    /// used, newer ones an object)
+    #[serde(default, rename = "inputDrvs")]
+    input_drvs: HashMap<String, IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+struct ShownOutput {
+    /// Unset for content-addressed outputs
+    #[serde(default)]
+    path: Option<String>,
+}
+
+/// The name of a store path (i.e., `<hash>-<name>` in
+/// `/nix/store/<hash>-<name>`). Newer versions of nix print store paths
+/// without the store directory.
+fn store_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+fn run_nix(
+    path: &Path,
+    command: &'static str,
+    args: &[&str],
+) -> Result<Vec<u8>, DerivationGraphError> {
+    let output = Command::new("nix")
+        .args(command.split(' '))
+        .args(args)
+        .current_dir(path)
+        .output()
+        .map_err(DerivationGraphError::LaunchingNix)?;
+
+    if !output.status.success() {
+        return Err(DerivationGraphError::NixStatus {
+            command,
+            code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        });
+    }
+
+    Ok(output.stdout)
+}
+
+/// For each of the command targets in `keys` (in the CI config for `system`),
+/// the keys of the `builds` whose outputs it depends on. Targets that aren't
+/// in the CI config are left out.
+pub fn command_dependencies(
+    path: &Path,
+    system: &str,
+    keys: &[&str],
+    builds: &HashMap<String, FoundDerivationBuild>,
+) -> Result<HashMap<String, Vec<String>>, DerivationGraphError> {
+    let target = format!(".#{}", config_attribute(system, "commandTargets"));
+    let data = run_nix(
+        path,
+        "eval",
+        &[
+            "--json",
+            &target,
+            "--apply",
+            "builtins.mapAttrs (_: t: t.drvPath)",
+        ],
+    )?;
+    let mut target_drvs: HashMap<String, String> = serde_json::from_slice(&data)?;
+    target_drvs.retain(|key, _| keys.contains(&key.as_str()));
+    if target_drvs.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let installables: Vec<_> = target_drvs.values().map(|d| format!("{d}^*")).collect();
+    let installables: Vec<_> = installables.iter().map(String::as_str).collect();
+    let data = run_nix(
+        path,
+        "derivation show",
+        &[&["-r"], &installables[..]].concat(),
+    )?;
+    let shown: HashMap<String, ShownDerivation> = serde_json::from_slice(&data)?;
+
+    Ok(find_needs(target_drvs, &shown, builds))
+}
+
+/// For each target (keyed, with the path of its derivation), the keys of the
+/// `builds` whose outputs are in the derivation's closure, going by the
+/// derivations in `shown`.
+fn find_needs(
+    target_drvs: HashMap<String, String>,
+    shown: &HashMap<String, ShownDerivation>,
+    builds: &HashMap<String, FoundDerivationBuild>,
+) -> HashMap<String, Vec<String>> {
+    let drvs: HashMap<_, _> = shown
+        .iter()
+        .map(|(drv, info)| (store_name(drv), info))
+        .collect();
+
+    let by_output: HashMap<_, _> = builds
+        .iter()
+        .filter_map(|(k, b)| Some((b.output_hash()?, k)))
+        .collect();
+
+    let mut needs = HashMap::new();
+    for (key, root) in target_drvs {
+        let mut found = HashSet::new();
+        let mut seen = HashSet::new();
+        let mut queue = vec![store_name(&root)];
+        while let Some(drv) = queue.pop() {
+            if !seen.insert(drv) {
+                continue;
+            }
+            let Some(info) = drvs.get(drv) else { continue };
+            for output in info.outputs.values() {
+                let Some(path) = &output.path else { continue };
+                let hash = store_name(path).split_once('-').map(|(h, _)| h);
+                if let Some(build) = hash.and_then(|h| by_output.get(h)) {
+                    found.insert((*build).clone());
+                }
+            }
+            queue.extend(info.input_drvs.keys().map(|d| store_name(d)));
+        }
+
+        let mut found: Vec<_> = found.into_iter().collect();
+        found.sort();
+        needs.insert(key, found);
+    }
+
+    needs
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn build(path: &str) -> FoundDerivationBuild {
+        serde_json::from_value(json!({
+            "name": "build",
+            "build_type": "package",
+            "path": path,
+            "tag": "build",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn finds_builds_in_each_targets_closure() {
+        // Older versions of nix print full store paths, and inputDrvs with the
+        // outputs used, newer ones neither
+        let shown = serde_json::from_value(json!({
+            "/nix/store/d1-app.drv": {
+                "outputs": {"out": {"path": "/nix/store/o1-app"}},
+                "inputDrvs": {"/nix/store/d2-hello.drv": ["out"]},
+            },
+            "d2-hello.drv": {
+                "outputs": {"out": {"path": "o2-hello"}, "doc": {"path": "o3-hello-doc"}},
+            },
+            "d3-tool.drv": {
+                "outputs": {"out": {"path": "o4-tool"}},
+                "inputDrvs": {"d2-hello.drv": {"outputs": ["out"], "dynamicOutputs": {}}},
+            },
+            "d4-tests.drv": {
+                "outputs": {"out": {}},
+                "inputDrvs": {
+                    "d1-app.drv": {"outputs": ["out"], "dynamicOutputs": {}},
+                    "d3-tool.drv": {"outputs": ["out"], "dynamicOutputs": {}},
+                },
+            },
+        }))
+        .unwrap();
+        let builds = HashMap::from([
+            ("app".to_string(), build("/nix/store/o1-app")),
+            ("hello".to_string(), build("/nix/store/o2-hello")),
+            ("tool".to_string(), build("/nix/store/o4-tool")),
+            ("unused".to_string(), build("/nix/store/o5-unused")),
+        ]);
+        let targets = HashMap::from([
+            ("deploy".to_string(), "/nix/store/d1-app.drv".to_string()),
+            ("test".to_string(), "/nix/store/d4-tests.drv".to_string()),
+            ("docs".to_string(), "/nix/store/d5-docs.drv".to_string()),
+        ]);
+
+        let needs = find_needs(targets, &shown, &builds);
+
+        assert_eq!(needs.len(), 3);
+        assert_eq!(needs["deploy"], ["app", "hello"]);
+        assert_eq!(needs["test"], ["app", "hello", "tool"]);
+        assert!(needs["docs"].is_empty());
+    }
+}
@@ -21,7 +21,6 @@ pub fn print_cmd(name: &'static str, cmd: &Command) {
         .join(" ");
     let pwd = cmd
         .get_current_dir()
-        .take()
         .map(|p| p.to_path_buf())
         .unwrap_or_else(|| std::env::current_dir().unwrap());
     eprintln!("\n---");
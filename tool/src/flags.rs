@@ -1,8 +1,11 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use log::LevelFilter;
 
+use crate::git::{local_repo, ReadingRepoError};
+
 lazy_static::lazy_static! {
     static ref DEFAULT_LOG_LEVEL: LevelFilter = {
         let ci_str = std::env::var("CI");
@@ -23,22 +26,44 @@ pub struct BuildkiteArgs {
 
     pub pipeline_id: String,
     pub pipeline_slug: String,
+
+    pub build_id: Option<String>,
+    pub build_url: Option<String>,
+
+    /// Buildkite queue of the agents that build for each system
+    pub queues: HashMap<String, String>,
+
+    /// Running under `run-local` rather than Buildkite
+    pub local: bool,
 }
 
+#[derive(Clone)]
+pub struct ServerArgs {
+    pub url: Option<String>,
+    pub token: Option<String>,
+}
+
+/// Outside of Buildkite, the commit, repository, path and branch are taken
+/// from the git checkout in the current directory.
 #[derive(Parser)]
 pub struct CliArgs {
     #[arg(long, env = "BUILDKITE_COMMIT")]
-    pub commit: String,
+    pub commit: Option<String>,
     #[arg(long, env = "BUILDKITE_REPO")]
-    pub repository: String,
+    pub repository: Option<String>,
     #[arg(long, env = "BUILDKITE_BUILD_CHECKOUT_PATH")]
-    pub path: PathBuf,
+    pub path: Option<PathBuf>,
 
-    #[arg(long, env = "BUILDKITE_PIPELINE_ID")]
+    #[arg(long, env = "BUILDKITE_PIPELINE_ID", default_value = "local")]
     pub pipeline_id: String,
-    #[arg(long, env = "BUILDKITE_PIPELINE_SLUG")]
+    #[arg(long, env = "BUILDKITE_PIPELINE_SLUG", default_value = "local")]
     pub pipeline_slug: String,
 
+    #[arg(long, env = "BUILDKITE_BUILD_ID")]
+    pub build_id: Option<String>,
+    #[arg(long, env = "BUILDKITE_BUILD_URL")]
+    pub build_url: Option<String>,
+
     #[arg(long, env = "BUILDKITE_BRANCH")]
     pub branch: Option<String>,
     #[arg(long, env = "BUILDKITE_TAG")]
@@ -47,31 +72,96 @@ pub struct CliArgs {
     #[arg(long, env = "CI_COMMAND", default_value = "ci")]
     pub ci_cmd: String,
 
+    /// Buildkite queue of the agents that build for a system, as
+    /// `<system>=<queue>`. Only systems with a queue, and the system we're
+    /// running on, are built.
+    #[arg(long = "queue", env = "CI_QUEUES", value_delimiter = ',', value_parser = parse_queue)]
+    pub queues: Vec<(String, String)>,
+
+    /// Base URL of the build cache server. Caching is disabled if unset.
+    #[arg(long, env = "CI_SERVER_URL")]
+    pub server_url: Option<String>,
+    /// API token for recording builds with the cache server
+    #[arg(long, env = "CI_SERVER_TOKEN", hide_env_values = true)]
+    pub server_token: Option<String>,
+
     #[arg(long, env = "LOG_LEVEL", default_value_t = *DEFAULT_LOG_LEVEL)]
     pub log_level: LevelFilter,
 
+    /// Set for steps run by `run-local`, which have no `buildkite-agent` to
+    /// talk to
+    #[arg(long, env = "CI_LOCAL", hide = true)]
+    pub local: bool,
+
     #[command(subcommand)]
     pub action: Action,
 }
 impl CliArgs {
-    pub fn into_parts(self) -> (String, LevelFilter, Action, BuildkiteArgs) {
-        (
+    pub fn into_parts(
+        self,
+    ) -> Result<(String, ServerArgs, LevelFilter, Action, BuildkiteArgs), ReadingRepoError> {
+        let (commit, repository, path, branch) = match (self.commit, self.repository, self.path) {
+            (Some(commit), Some(repository), Some(path)) => (commit, repository, path, self.branch),
+            (commit, repository, path) => {
+                let dir = path.as_deref().unwrap_or(Path::new("."));
+                let repo = local_repo(dir)?;
+                // A branch only makes sense alongside the commit it's for
+                let branch = match commit {
+                    Some(_) => self.branch,
+                    None => self.branch.or(repo.branch),
+                };
+                let fallback = repo
+                    .remote
+                    .unwrap_or_else(|| repo.path.display().to_string());
+                (
+                    commit.unwrap_or(repo.commit),
+                    repository.unwrap_or(fallback),
+                    path.unwrap_or(repo.path),
+                    branch,
+                )
+            }
+        };
+
+        Ok((
             self.ci_cmd,
+            ServerArgs {
+                url: self.server_url,
+                token: self.server_token,
+            },
             self.log_level,
             self.action,
             BuildkiteArgs {
-                commit: self.commit,
-                branch: self.branch,
+                commit,
+                branch,
                 tag: self.tag,
-                repository: self.repository,
-                path: self.path,
+                repository,
+                path,
                 pipeline_id: self.pipeline_id,
                 pipeline_slug: self.pipeline_slug,
+                build_id: self.build_id,
+                build_url: self.build_url,
+                queues: self.queues.into_iter().collect(),
+                local: self.local,
             },
-        )
+        ))
     }
 }
 
+fn parse_queue(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((system, queue)) if !system.is_empty() && !queue.is_empty() => {
+            Ok((system.to_string(), queue.to_string()))
+        }
+        _ => Err(format!("expected `<system>=<queue>`, got {value:?}")),
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    Json,
+    Yaml,
+}
+
 #[derive(Subcommand)]
 pub enum Action {
     /// Collect all results at the end of a CI run.
@@ -82,4 +172,17 @@ pub enum Action {
     Execute { target: String },
     /// Build a derivation
     Build { target: String },
+    /// Evaluate the pipeline, and run its steps on this machine instead of
+    /// uploading them to Buildkite
+    RunLocal {
+        /// How many steps to run at once [default: number of CPUs]
+        #[arg(long, short)]
+        jobs: Option<usize>,
+    },
+    /// Evaluate the pipeline and print it, without uploading it or recording
+    /// anything
+    Render {
+        #[arg(long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+    },
 }
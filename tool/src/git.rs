@@ -1,5 +1,6 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::{io::Write, path::Path};
 
 use crate::buildkite::{Cli, RunError};
 #[cfg(debug_assertions)]
@@ -165,3 +166,59 @@ pub fn apply_patch(repo_path: &Path) -> Result<(), ApplyPatchError> {
 
     Ok(())
 }
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReadingRepoError {
+    #[error("failed to invoke `git`: {0}")]
+    InvokingGit(std::io::Error),
+    #[error("`git {args}` exited with {code:?}: {stderr}")]
+    GitStatus {
+        args: String,
+        code: Option<i32>,
+        stderr: String,
+    },
+}
+
+/// Run a read-only git command in `dir`, returning what it prints.
+fn read_git(dir: &Path, args: &[&str]) -> Result<String, ReadingRepoError> {
+    let output = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .map_err(ReadingRepoError::InvokingGit)?;
+
+    if !output.status.success() {
+        return Err(ReadingRepoError::GitStatus {
+            args: args.join(" "),
+            code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        });
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The state of a local checkout, standing in for what Buildkite tells us
+/// when we're not running under it.
+pub struct LocalRepo {
+    pub path: PathBuf,
+    pub commit: String,
+    /// Unset if `HEAD` is detached
+    pub branch: Option<String>,
+    /// The URL of `origin`, if there is one
+    pub remote: Option<String>,
+}
+
+pub fn local_repo(dir: &Path) -> Result<LocalRepo, ReadingRepoError> {
+    let path = read_git(dir, &["rev-parse", "--show-toplevel"])?.into();
+    let commit = read_git(dir, &["rev-parse", "HEAD"])?;
+    let branch = read_git(dir, &["symbolic-ref", "--short", "--quiet", "HEAD"]).ok();
+    let remote = read_git(dir, &["remote", "get-url", "origin"]).ok();
+
+    Ok(LocalRepo {
+        path,
+        commit,
+        branch,
+        remote,
+    })
+}
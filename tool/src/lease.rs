@@ -0,0 +1,140 @@
+// Leases stop pipelines that run at the same time from building the same
+// derivation twice. A `build` step claims the lease on its derivation before
+// building it, and keeps renewing it until it's done. Any other build of the
+// same derivation waits for the lease to be released (or to expire, if its
+// holder died) and then either uses the result or takes over the build.
+//
+// Waiting is a long poll on the cache server, which answers as soon as the
+// holder records its result.
+
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use api::{BuildRecord, CacheClient, CacheError};
+use chrono::{DateTime, Utc};
+
+/// How long a lease lasts without being renewed.
+const LEASE_TTL: Duration = Duration::from_secs(5 * 60);
+/// How often held leases are renewed.
+const RENEW_INTERVAL: Duration = Duration::from_secs(60);
+/// How long to wait for a build that's held by someone else before checking
+/// on its lease again.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub enum Acquired {
+    /// We hold the lease, and should build the derivation.
+    Held(LeaseGuard),
+    /// Another build built the derivation while we were waiting.
+    BuiltElsewhere(BuildRecord),
+}
+
+/// A held lease, which is renewed in the background until dropped, then
+/// released.
+pub struct LeaseGuard {
+    cache: CacheClient,
+    hash: String,
+    build_id: String,
+    stop: Option<Sender<()>>,
+    renewer: Option<JoinHandle<()>>,
+}
+
+impl LeaseGuard {
+    fn new(cache: CacheClient, hash: String, build_id: String, build_url: String) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let renewer = {
+            let (cache, hash, build_id) = (cache.clone(), hash.clone(), build_id.clone());
+            std::thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(RENEW_INTERVAL) {
+                    match cache.claim_lease(&hash, &build_id, &build_url, LEASE_TTL) {
+                        Ok(lease) if lease.build_id == build_id => {}
+                        Ok(lease) => {
+                            log::warn!("lost build lease on {hash} to {}", lease.build_url);
+                        }
+                        Err(e) => log::warn!("error renewing build lease on {hash}: {e}"),
+                    }
+                }
+            })
+        };
+
+        Self {
+            cache,
+            hash,
+            build_id,
+            stop: Some(stop),
+            renewer: Some(renewer),
+        }
+    }
+}
+
+impl Drop for LeaseGuard {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(renewer) = self.renewer.take() {
+            let _ = renewer.join();
+        }
+
+        if let Err(e) = self.cache.release_lease(&self.hash, &self.build_id) {
+            log::warn!("error releasing build lease on {}: {e}", self.hash);
+        }
+    }
+}
+
+/// A successful build of `hash`, if one has been recorded.
+fn find_success(cache: &CacheClient, hash: &str) -> Result<Option<BuildRecord>, CacheError> {
+    let records = cache.query(&[hash.to_string()])?;
+    Ok(records.into_iter().find(BuildRecord::succeeded))
+}
+
+/// Wait until we hold the lease on building `hash`, or until someone else has
+/// built it.
+pub fn acquire(
+    cache: &CacheClient,
+    hash: &str,
+    build_id: &str,
+    build_url: &str,
+) -> Result<Acquired, CacheError> {
+    let mut waited = false;
+    // Only builds that finished after this can be the result we're waiting for
+    let mut since = DateTime::<Utc>::MIN_UTC;
+    loop {
+        let lease = cache.claim_lease(hash, build_id, build_url, LEASE_TTL)?;
+        if lease.build_id == build_id {
+            // The previous holder may have succeeded just before releasing it
+            if waited {
+                if let Some(record) = find_success(cache, hash)? {
+                    cache.release_lease(hash, build_id)?;
+                    return Ok(Acquired::BuiltElsewhere(record));
+                }
+            }
+
+            let guard = LeaseGuard::new(
+                cache.clone(),
+                hash.to_string(),
+                build_id.to_string(),
+                build_url.to_string(),
+            );
+            return Ok(Acquired::Held(guard));
+        }
+
+        if !waited {
+            log::info!(
+                "{hash} is being built by {} (lease expires {}), waiting for it",
+                lease.build_url,
+                lease.expires_at
+            );
+            waited = true;
+        }
+
+        since = since.max(lease.acquired_at);
+        let finished = cache.wait_for_builds(&[hash.to_string()], since, WAIT_TIMEOUT)?;
+        // If the holder failed, it should give up the lease, but don't wake up
+        // for the same failure again in case it hasn't yet
+        if let Some(last) = finished.iter().filter_map(|r| r.finished_at).max() {
+            since = since.max(last);
+        }
+        if let Some(record) = finished.into_iter().find(BuildRecord::succeeded) {
+            return Ok(Acquired::BuiltElsewhere(record));
+        }
+    }
+}
@@ -0,0 +1,645 @@
+// Running a pipeline on this machine instead of Buildkite, to try out step
+// configuration before pushing it.
+//
+// Steps are ordered the way Buildkite orders them: each step waits for the
+// wait (or block) step before it and anything it `depends_on`, and each wait
+// step waits for every step since the one before it. Steps are skipped if
+// their dependencies failed, unless they `allow_dependency_failure` (or, for
+// wait steps, `continue_on_failure`). Steps that only make sense on Buildkite
+// (block and trigger steps, and anything the caller names) aren't run, but
+// don't hold up the steps after them either.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::buildkite::{CommandStep, Step};
+use crate::flags::BuildkiteArgs;
+use crate::validate::StepGraph;
+
+/// How often to check whether running steps have timed out.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Outcome {
+    Passed,
+    /// With the exit code, unless the step was killed by a signal
+    Failed(Option<i32>),
+    TimedOut,
+    /// Not run because a dependency failed
+    Skipped,
+    /// Not run because it needs Buildkite
+    NotRun,
+}
+
+impl Outcome {
+    /// Whether steps that depend on this one can run.
+    fn passed(self) -> bool {
+        matches!(self, Self::Passed | Self::NotRun)
+    }
+
+    fn failed(self) -> bool {
+        matches!(self, Self::Failed(_) | Self::TimedOut)
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Passed => f.write_str("passed"),
+            Self::Failed(Some(code)) => write!(f, "failed ({code})"),
+            Self::Failed(None) => f.write_str("failed (killed)"),
+            Self::TimedOut => f.write_str("timed out"),
+            Self::Skipped => f.write_str("skipped"),
+            Self::NotRun => f.write_str("not run"),
+        }
+    }
+}
+
+enum Action<'a> {
+    Run(&'a CommandStep),
+    Wait { continue_on_failure: bool },
+    NotRun,
+}
+
+struct Node<'a> {
+    key: String,
+    label: Option<String>,
+    action: Action<'a>,
+    allow_dependency_failure: bool,
+    /// Indices of the steps this one waits for
+    deps: Vec<usize>,
+}
+
+impl Node<'_> {
+    fn name(&self) -> &str {
+        self.label.as_deref().unwrap_or(&self.key)
+    }
+}
+
+/// Work out how to run each step. Steps keyed in `not_local` won't be run.
+fn build_nodes<'a>(steps: &'a [Step], graph: &StepGraph, not_local: &[&str]) -> Vec<Node<'a>> {
+    steps
+        .iter()
+        .zip(&graph.deps)
+        .enumerate()
+        .map(|(i, (step, deps))| {
+            let (key, label, action, allow_dependency_failure) = match step {
+                Step::Command(s) => {
+                    let action = match not_local.contains(&s.key.as_str()) {
+                        true => Action::NotRun,
+                        false => Action::Run(s),
+                    };
+                    (
+                        s.key.clone(),
+                        s.label.clone(),
+                        action,
+                        s.allow_dependency_failure,
+                    )
+                }
+                Step::Wait(s) => {
+                    let action = Action::Wait {
+                        continue_on_failure: s.continue_on_failure,
+                    };
+                    (s.key.clone(), None, action, s.allow_dependency_failure)
+                }
+                Step::Block(s) => {
+                    let label = s.label.clone().unwrap_or_else(|| s.block.clone());
+                    (format!("block-{i}"), Some(label), Action::NotRun, false)
+                }
+                Step::Trigger(s) => {
+                    let (key, label) = (s.build.key.clone(), s.build.label.clone());
+                    (key, label, Action::NotRun, false)
+                }
+            };
+
+            Node {
+                key,
+                label,
+                action,
+                allow_dependency_failure,
+                deps: deps.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Environment for steps, standing in for what Buildkite would set. `ci`
+/// commands in steps run `exe`, and read the build details from here.
+fn step_env(args: &BuildkiteArgs, exe: &Path) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("CI_COMMAND", exe.to_string_lossy().to_string()),
+        ("CI_LOCAL", "true".to_string()),
+        ("BUILDKITE_COMMIT", args.commit.clone()),
+        ("BUILDKITE_REPO", args.repository.clone()),
+        (
+            "BUILDKITE_BUILD_CHECKOUT_PATH",
+            args.path.to_string_lossy().to_string(),
+        ),
+        ("BUILDKITE_PIPELINE_ID", args.pipeline_id.clone()),
+        ("BUILDKITE_PIPELINE_SLUG", args.pipeline_slug.clone()),
+    ];
+    if let Some(branch) = &args.branch {
+        env.push(("BUILDKITE_BRANCH", branch.clone()));
+    }
+    if let Some(tag) = &args.tag {
+        env.push(("BUILDKITE_TAG", tag.clone()));
+    }
+
+    env
+}
+
+/// Copy each line of `output` to stdout, prefixed with the step's key.
+fn forward_output(key: String, output: impl Read + Send + 'static) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for line in BufReader::new(output).split(b'\n') {
+            let Ok(line) = line else { break };
+            let mut stdout = std::io::stdout().lock();
+            let _ = write!(stdout, "{key} | ");
+            let _ = stdout.write_all(&line);
+            let _ = writeln!(stdout);
+        }
+    })
+}
+
+/// Wait for a step to finish, killing it (and anything it started) if it runs
+/// past `timeout`.
+fn wait_for_step(mut child: Child, timeout: Duration) -> Outcome {
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => return Outcome::Passed,
+            Ok(Some(status)) => return Outcome::Failed(status.code()),
+            Ok(None) if started.elapsed() >= timeout => {
+                // Steps lead their own process group, so this gets anything
+                // still holding their output open too
+                let pgid = child.id() as libc::pid_t;
+                unsafe { libc::killpg(pgid, libc::SIGKILL) };
+                let _ = child.wait();
+                return Outcome::TimedOut;
+            }
+            Ok(None) => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                println!("error waiting for step: {e}");
+                return Outcome::Failed(None);
+            }
+        }
+    }
+}
+
+struct StepRunner {
+    dir: PathBuf,
+    env: Vec<(&'static str, String)>,
+    done: Sender<(usize, Outcome, Duration)>,
+}
+
+impl StepRunner {
+    /// Run a step in the background, reporting its outcome when it's done.
+    fn start(&self, index: usize, step: &CommandStep) {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", &step.command])
+            .current_dir(&self.dir)
+            .envs(self.env.iter().map(|(k, v)| (k, v)))
+            .envs(step.env.iter().flatten())
+            .env("BUILDKITE_STEP_KEY", &step.key)
+            .env("BUILDKITE_LABEL", step.label.as_deref().unwrap_or_default())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0);
+
+        let key = step.key.clone();
+        let timeout = Duration::from_secs(u64::from(step.timeout_in_minutes) * 60);
+        let done = self.done.clone();
+        thread::spawn(move || {
+            let started = Instant::now();
+            let outcome = match cmd.spawn() {
+                Ok(mut child) => {
+                    let forwarders = [
+                        child.stdout.take().map(|o| forward_output(key.clone(), o)),
+                        child.stderr.take().map(|o| forward_output(key.clone(), o)),
+                    ];
+                    let outcome = wait_for_step(child, timeout);
+                    for forwarder in forwarders.into_iter().flatten() {
+                        let _ = forwarder.join();
+                    }
+                    outcome
+                }
+                Err(e) => {
+                    println!("{key} | error starting step: {e}");
+                    Outcome::Failed(None)
+                }
+            };
+
+            let _ = done.send((index, outcome, started.elapsed()));
+        });
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// A change in a step's progress, from [`Scheduler::advance`].
+#[derive(Debug, PartialEq)]
+enum Update {
+    /// The step should be started now
+    Start(usize),
+    /// The step is done without being run
+    Settled(usize, Outcome),
+}
+
+/// Decides when each step can start, and what happens to the ones that don't
+/// need running, as the steps before them finish.
+struct Scheduler<'n, 'a> {
+    nodes: &'n [Node<'a>],
+    jobs: usize,
+    outcomes: Vec<Option<Outcome>>,
+    started: Vec<bool>,
+    busy_groups: HashSet<&'a str>,
+    running: usize,
+}
+
+impl<'n, 'a> Scheduler<'n, 'a> {
+    fn new(nodes: &'n [Node<'a>], jobs: usize) -> Self {
+        Self {
+            nodes,
+            jobs,
+            outcomes: vec![None; nodes.len()],
+            started: vec![false; nodes.len()],
+            busy_groups: HashSet::new(),
+            running: 0,
+        }
+    }
+
+    /// Settle everything that doesn't need running, and start what we can.
+    fn advance(&mut self) -> Vec<Update> {
+        let mut updates = Vec::new();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, node) in self.nodes.iter().enumerate() {
+                if self.outcomes[i].is_some() || self.started[i] {
+                    continue;
+                }
+                let Some(deps) = node
+                    .deps
+                    .iter()
+                    .map(|&d| self.outcomes[d])
+                    .collect::<Option<Vec<_>>>()
+                else {
+                    continue;
+                };
+                let deps_passed = deps.iter().all(|o| o.passed());
+                let blocked = !deps_passed && !node.allow_dependency_failure;
+
+                let outcome = match &node.action {
+                    Action::Wait {
+                        continue_on_failure,
+                    } => match blocked && !continue_on_failure {
+                        true => Outcome::Skipped,
+                        false => Outcome::Passed,
+                    },
+                    _ if blocked => Outcome::Skipped,
+                    Action::NotRun => Outcome::NotRun,
+                    Action::Run(step) => {
+                        let group = step.concurrency_group.as_deref();
+                        if self.running >= self.jobs
+                            || group.is_some_and(|g| self.busy_groups.contains(g))
+                        {
+                            continue;
+                        }
+                        self.busy_groups.extend(group);
+                        self.started[i] = true;
+                        self.running += 1;
+                        updates.push(Update::Start(i));
+                        changed = true;
+                        continue;
+                    }
+                };
+
+                self.outcomes[i] = Some(outcome);
+                updates.push(Update::Settled(i, outcome));
+                changed = true;
+            }
+        }
+
+        updates
+    }
+
+    /// Record the outcome of a step that was started.
+    fn finish(&mut self, index: usize, outcome: Outcome) {
+        if let Action::Run(step) = &self.nodes[index].action {
+            if let Some(group) = &step.concurrency_group {
+                self.busy_groups.remove(group.as_str());
+            }
+        }
+        self.outcomes[index] = Some(outcome);
+        self.running -= 1;
+    }
+}
+
+/// Run the steps of a pipeline, at most `jobs` at a time, and print a
+/// summary. Returns whether every step that ran passed.
+pub fn run(
+    steps: &[Step],
+    graph: &StepGraph,
+    not_local: &[&str],
+    args: &BuildkiteArgs,
+    exe: &Path,
+    jobs: usize,
+) -> bool {
+    let nodes = build_nodes(steps, graph, not_local);
+    let (done, finished) = mpsc::channel();
+    let runner = StepRunner {
+        dir: args.path.clone(),
+        env: step_env(args, exe),
+        done,
+    };
+
+    let mut scheduler = Scheduler::new(&nodes, jobs);
+    let mut durations = vec![None; nodes.len()];
+    loop {
+        for update in scheduler.advance() {
+            match update {
+                Update::Start(i) => {
+                    let Action::Run(step) = nodes[i].action else {
+                        unreachable!("only command steps are started")
+                    };
+                    println!("--- starting {}", nodes[i].name());
+                    runner.start(i, step);
+                }
+                Update::Settled(i, outcome) => {
+                    if let Action::NotRun | Action::Run(_) = nodes[i].action {
+                        println!("--- {outcome}: {}", nodes[i].name());
+                    }
+                }
+            }
+        }
+
+        if scheduler.running == 0 {
+            break;
+        }
+        let (i, outcome, duration) = finished.recv().expect("step runners never hang up");
+        scheduler.finish(i, outcome);
+        println!(
+            "--- {outcome}: {} ({})",
+            nodes[i].name(),
+            format_duration(duration)
+        );
+        durations[i] = Some(duration);
+    }
+    println!();
+    println!("Summary:");
+    let mut summary = Vec::new();
+    for (i, node) in nodes.iter().enumerate() {
+        if let Action::Wait { .. } = node.action {
+            continue;
+        }
+        let outcome = scheduler.outcomes[i].expect("every step has finished");
+        let duration = durations[i].map(format_duration).unwrap_or_default();
+        println!(
+            "  {:<16} {duration:>8}  {}",
+            outcome.to_string(),
+            node.name()
+        );
+        summary.push(outcome);
+    }
+
+    let count = |f: fn(&Outcome) -> bool| summary.iter().filter(|o| f(o)).count();
+    let failed = count(|o| o.failed());
+    println!(
+        "{} passed, {failed} failed, {} skipped",
+        count(|o| *o == Outcome::Passed),
+        count(|o| *o == Outcome::Skipped),
+    );
+
+    failed == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::validate::{validate, Origin};
+
+    fn pipeline(steps: Value) -> (Vec<Step>, StepGraph) {
+        let steps: Vec<Step> = serde_json::from_value(steps).unwrap();
+        let graph = validate(&steps, &vec![Origin::Generated; steps.len()]).unwrap();
+        (steps, graph)
+    }
+
+    fn command(key: &str) -> Value {
+        json!({"type": "command", "key": key, "command": "true"})
+    }
+
+    fn wait(key: &str, continue_on_failure: bool) -> Value {
+        json!({
+            "type": "wait",
+            "key": key,
+            "allow_dependency_failure": false,
+            "continue_on_failure": continue_on_failure,
+        })
+    }
+
+    fn with(mut step: Value, field: &str, value: Value) -> Value {
+        step[field] = value;
+        step
+    }
+
+    struct Simulation {
+        /// Keys of the steps started after each step finished (and at first)
+        started: Vec<Vec<String>>,
+        outcomes: Vec<(String, Outcome)>,
+    }
+
+    /// Schedule `nodes`, finishing running steps in the order they started,
+    /// with the outcome given in `results` (or passing).
+    fn simulate(nodes: &[Node], jobs: usize, results: &[(&str, Outcome)]) -> Simulation {
+        let mut scheduler = Scheduler::new(nodes, jobs);
+        let mut started = Vec::new();
+        let mut running = Vec::new();
+        loop {
+            let mut batch = Vec::new();
+            for update in scheduler.advance() {
+                if let Update::Start(i) = update {
+                    batch.push(nodes[i].key.clone());
+                    running.push(i);
+                }
+            }
+            assert!(running.len() <= jobs, "more than {jobs} steps running");
+            if !batch.is_empty() {
+                started.push(batch);
+            }
+            if running.is_empty() {
+                break;
+            }
+
+            let i = running.remove(0);
+            let outcome = results
+                .iter()
+                .find(|(key, _)| *key == nodes[i].key)
+                .map_or(Outcome::Passed, |(_, o)| *o);
+            scheduler.finish(i, outcome);
+        }
+
+        let outcomes = nodes
+            .iter()
+            .zip(&scheduler.outcomes)
+            .map(|(n, o)| (n.key.clone(), o.expect("every step has finished")))
+            .collect();
+        Simulation { started, outcomes }
+    }
+
+    fn outcome_of(sim: &Simulation, key: &str) -> Outcome {
+        sim.outcomes.iter().find(|(k, _)| k == key).unwrap().1
+    }
+
+    #[test]
+    fn build_nodes_only_runs_command_steps() {
+        let (steps, graph) = pipeline(json!([
+            command("build"),
+            command("deploy"),
+            {"type": "block", "block": "Release?", "blocked_state": "passed"},
+            {"type": "trigger", "async": false, "build": {"key": "trigger", "label": "Trigger"}},
+            wait("wait", true),
+        ]));
+        let nodes = build_nodes(&steps, &graph, &["deploy"]);
+
+        assert!(matches!(nodes[0].action, Action::Run(s) if s.key == "build"));
+        assert!(matches!(nodes[1].action, Action::NotRun));
+        assert!(matches!(nodes[2].action, Action::NotRun));
+        assert_eq!(nodes[2].name(), "Release?");
+        assert!(matches!(nodes[3].action, Action::NotRun));
+        assert_eq!(nodes[3].name(), "Trigger");
+        assert!(matches!(
+            nodes[4].action,
+            Action::Wait {
+                continue_on_failure: true
+            }
+        ));
+        assert_eq!(nodes[4].deps, [3, 2]);
+    }
+
+    #[test]
+    fn wait_steps_hold_back_later_steps() {
+        let (steps, graph) = pipeline(json!([
+            command("a"),
+            command("b"),
+            wait("wait", false),
+            command("c"),
+        ]));
+        let nodes = build_nodes(&steps, &graph, &[]);
+        let sim = simulate(&nodes, 4, &[]);
+
+        assert_eq!(sim.started, [vec!["a", "b"], vec!["c"]]);
+        assert!(sim.outcomes.iter().all(|(_, o)| *o == Outcome::Passed));
+    }
+
+    #[test]
+    fn failures_skip_dependent_steps() {
+        let (steps, graph) = pipeline(json!([
+            command("a"),
+            command("b"),
+            with(command("after-a"), "depends_on", json!(["a"])),
+            wait("wait", false),
+            command("c"),
+            with(command("d"), "allow_dependency_failure", json!(true)),
+        ]));
+        let nodes = build_nodes(&steps, &graph, &[]);
+        let sim = simulate(&nodes, 4, &[("a", Outcome::Failed(Some(1)))]);
+
+        assert_eq!(sim.started, [vec!["a", "b"], vec!["d"]]);
+        assert_eq!(outcome_of(&sim, "after-a"), Outcome::Skipped);
+        assert_eq!(outcome_of(&sim, "wait"), Outcome::Skipped);
+        assert_eq!(outcome_of(&sim, "c"), Outcome::Skipped);
+        assert_eq!(outcome_of(&sim, "d"), Outcome::Passed);
+    }
+
+    #[test]
+    fn continue_on_failure_runs_steps_after_failures() {
+        let (steps, graph) = pipeline(json!([command("a"), wait("wait", true), command("b"),]));
+        let nodes = build_nodes(&steps, &graph, &[]);
+        let sim = simulate(&nodes, 4, &[("a", Outcome::TimedOut)]);
+
+        assert_eq!(sim.started, [vec!["a"], vec!["b"]]);
+        assert_eq!(outcome_of(&sim, "wait"), Outcome::Passed);
+        assert_eq!(outcome_of(&sim, "b"), Outcome::Passed);
+    }
+
+    #[test]
+    fn concurrency_groups_and_jobs_limit_running_steps() {
+        let group = |key| with(command(key), "concurrency_group", json!("deploy"));
+        let (steps, graph) = pipeline(json!([group("a"), group("b"), command("c"), command("d"),]));
+        let nodes = build_nodes(&steps, &graph, &[]);
+
+        let sim = simulate(&nodes, 4, &[]);
+        assert_eq!(sim.started, [vec!["a", "c", "d"], vec!["b"]]);
+
+        let sim = simulate(&nodes, 2, &[]);
+        assert_eq!(sim.started, [vec!["a", "c"], vec!["b"], vec!["d"]]);
+    }
+
+    #[test]
+    fn steps_that_need_buildkite_dont_hold_up_others() {
+        let (steps, graph) = pipeline(json!([
+            command("upload"),
+            {"type": "block", "block": "Release?", "blocked_state": "passed"},
+            command("release"),
+        ]));
+        let nodes = build_nodes(&steps, &graph, &["upload"]);
+        let sim = simulate(&nodes, 4, &[]);
+
+        assert_eq!(sim.started, [vec!["release"]]);
+        assert_eq!(outcome_of(&sim, "upload"), Outcome::NotRun);
+        assert_eq!(outcome_of(&sim, "block-1"), Outcome::NotRun);
+        assert_eq!(outcome_of(&sim, "release"), Outcome::Passed);
+    }
+
+    #[test]
+    fn step_runner_reports_outcomes() {
+        let (done, finished) = mpsc::channel();
+        let runner = StepRunner {
+            dir: PathBuf::from("/"),
+            env: Vec::new(),
+            done,
+        };
+        let step = |command: &str, timeout| {
+            let mut builder = CommandStep::builder();
+            builder.set_timeout_in_minutes(timeout);
+            builder.build("step".to_string(), command.to_string())
+        };
+
+        runner.start(0, &step("true", 1));
+        runner.start(1, &step("exit 3", 1));
+        // The background sleep holds the step's output open, so this only
+        // finishes if it's killed too
+        runner.start(2, &step("sleep 60 & sleep 60", 0));
+
+        let mut outcomes: Vec<_> = (0..3)
+            .map(|_| finished.recv_timeout(Duration::from_secs(30)).unwrap())
+            .map(|(i, outcome, _)| (i, outcome))
+            .collect();
+        outcomes.sort_by_key(|(i, _)| *i);
+        assert_eq!(
+            outcomes,
+            [
+                (0, Outcome::Passed),
+                (1, Outcome::Failed(Some(3))),
+                (2, Outcome::TimedOut)
+            ]
+        );
+    }
+}
@@ -1,32 +1,58 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::path::Path;
 use std::process::Command;
+use std::thread;
 
-use build_info::{CIRunStateWriteToFileError, EvaluationError};
+use api::{BuildRecord, BuildSchedule, CacheClient, ScheduledStep, StepResult};
+use build_info::{
+    ci_systems, config_attribute, CIRunStateWriteToFileError, EvaluationError, SYSTEM,
+};
 use buildkite::{RunError, WaitStep};
+use chrono::Utc;
 use clap::Parser;
-use flags::{Action, BuildkiteArgs};
+use flags::{Action, BuildkiteArgs, Format};
 use git::{
     apply_patch, fetch_patch, ApplyPatchError, CreateCommitError, FetchPatchError,
-    UploadingPatchError,
+    ReadingRepoError, UploadingPatchError,
 };
 use serde::Serialize;
 use simple_logger::SimpleLogger;
 
-use crate::build_info::{BuildEvaluation, CIRunState};
+use crate::build_info::{BuildEvaluation, CIRunState, FoundDerivationBuild};
 use crate::buildkite::{Cli, CommandStep, Step};
+use crate::derivations::command_dependencies;
 use crate::flags::CliArgs;
 use crate::git::{create_state_commit, upload_patch};
+use crate::lease::Acquired;
+use crate::results::{PipelineStep, ResultsError, ScheduledBuild, StepEvent};
+use crate::validate::{validate, Origin, StepGraph, ValidationError};
 
 mod build_info;
 #[allow(dead_code)]
 mod buildkite;
+mod derivations;
 #[cfg(debug_assertions)]
 mod develop;
 mod flags;
 mod git;
+mod lease;
+mod local;
+mod results;
+mod validate;
+
+const COLLECT_STEP_KEY: &str = "collect-results";
+const WAIT_BUILDS_KEY: &str = "wait-builds";
 
 #[derive(Serialize)]
 struct BuildkitePipeline {
     steps: Vec<Step>,
+    /// Derivations built by this pipeline, keyed by step key
+    #[serde(skip)]
+    builds: HashMap<String, ScheduledBuild>,
+    /// What each step waits for
+    #[serde(skip)]
+    graph: StepGraph,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -71,58 +97,436 @@ fn apply(args: &BuildkiteArgs) -> Result<(), ApplyError> {
 
 #[derive(thiserror::Error, Debug)]
 enum DerivePipelineError {
-    #[error("error capturing CI state in git: {0}")]
-    CapturingGitState(#[from] CaptureError),
     #[error("error evaluating CI state: {0}")]
     EvaluatingState(#[from] EvaluationError),
+    #[error("invalid pipeline, {0}")]
+    Invalid(#[from] ValidationError),
 }
 
 #[derive(thiserror::Error, Debug)]
 enum EvaluateError {
+    #[error("error capturing CI state in git: {0}")]
+    CapturingGitState(#[from] CaptureError),
     #[error("error deriving pipeline: {0}")]
     Deriving(#[from] DerivePipelineError),
     #[error("error encoding pipeline to JSON: {0}")]
     Encoding(#[from] serde_json::Error),
     #[error("error running buildkite-agent: {0}")]
     UploadingPipeline(#[from] RunError),
+    #[error("error recording scheduled builds: {0}")]
+    RecordingBuilds(#[from] ResultsError),
+}
+
+/// Find derivations (by output hash) that the cache server has a successful
+/// build recorded for.
+///
+/// Failing to reach the cache server isn't fatal, we just build everything.
+fn find_cached_builds(
+    cache: &CacheClient,
+    builds: &HashMap<String, FoundDerivationBuild>,
+) -> HashMap<String, BuildRecord> {
+    let hashes: Vec<_> = builds.values().filter_map(|b| b.output_hash()).collect();
+    if hashes.is_empty() {
+        return HashMap::new();
+    }
+
+    let records = match cache.query(&hashes) {
+        Ok(records) => records,
+        Err(e) => {
+            log::warn!("error querying cache server, building everything: {e}");
+            return HashMap::new();
+        }
+    };
+
+    records
+        .into_iter()
+        .filter(|r| r.succeeded())
+        .map(|r| (r.hash.clone(), r))
+        .collect()
+}
+
+/// Find the builds each command in the CI config for `system` needs (by
+/// build key).
+///
+/// Failing to inspect the derivation graph isn't fatal, every command just
+/// waits for every build.
+fn find_command_dependencies(
+    path: &Path,
+    system: &str,
+    eval: &BuildEvaluation,
+) -> HashMap<String, Vec<String>> {
+    let keys: Vec<_> = eval
+        .steps
+        .iter()
+        .filter_map(|step| match step {
+            Step::Command(s) => Some(s.key.as_str()),
+            _ => None,
+        })
+        .collect();
+    if keys.is_empty() {
+        return HashMap::new();
+    }
+
+    match command_dependencies(path, system, &keys, &eval.builds) {
+        Ok(needs) => needs,
+        Err(e) => {
+            log::warn!("error inspecting derivation graph, waiting for every build: {e}");
+            HashMap::new()
+        }
+    }
+}
+
+type PlacedStep = (Step, Origin);
+
+/// Split steps from the CI config into those that can start as soon as the
+/// build steps they need (from `needs`) are done, which get `depends_on`
+/// those steps, and those that have to wait for every build.
+fn schedule_user_steps(
+    steps: Vec<PlacedStep>,
+    needs: &HashMap<String, Vec<String>>,
+    build_keys: &HashSet<String>,
+) -> (Vec<PlacedStep>, Vec<PlacedStep>) {
+    // Only commands we know the needs of, and that come before any wait (or
+    // block) steps, can go ahead of the builds
+    let first_barrier = steps
+        .iter()
+        .position(|(s, _)| s.is_barrier())
+        .unwrap_or(steps.len());
+    let candidates: HashMap<_, _> = steps[..first_barrier]
+        .iter()
+        .filter_map(|(step, _)| match step {
+            Step::Command(s) if needs.contains_key(&s.key) => Some((s.key.clone(), step)),
+            _ => None,
+        })
+        .collect();
+    let mut early: HashSet<_> = candidates.keys().cloned().collect();
+
+    // ...as long as they don't depend on anything that has to wait
+    loop {
+        let waiting: Vec<_> = early
+            .iter()
+            .filter(|key| {
+                candidates[*key]
+                    .depends_on()
+                    .iter()
+                    .any(|dep| !early.contains(dep) && !build_keys.contains(dep))
+            })
+            .cloned()
+            .collect();
+        if waiting.is_empty() {
+            break;
+        }
+        for key in waiting {
+            early.remove(&key);
+        }
+    }
+
+    let (mut early, late): (Vec<_>, Vec<_>) = steps
+        .into_iter()
+        .partition(|(step, _)| step.key().is_some_and(|k| early.contains(k)));
+    for (step, _) in &mut early {
+        let Step::Command(s) = step else { continue };
+        let builds = &needs[&s.key];
+        if builds.is_empty() {
+            continue;
+        }
+        let depends_on = s.depends_on.get_or_insert_with(Vec::new);
+        depends_on.extend(builds.iter().cloned());
+        depends_on.sort();
+        depends_on.dedup();
+    }
+
+    (early, late)
+}
+
+/// Order the steps from the CI config, to go after the build steps (keyed
+/// `build_keys`): `early` steps first, then the `late` ones, which wait for
+/// every build (but not for early steps, so they aren't held up by early
+/// steps failing).
+fn order_user_steps(
+    early: Vec<PlacedStep>,
+    mut late: Vec<PlacedStep>,
+    build_keys: &HashSet<String>,
+) -> Vec<PlacedStep> {
+    if early.is_empty() {
+        let wait_step = WaitStep::builder().build(WAIT_BUILDS_KEY.to_string());
+        let mut ordered = vec![(Step::Wait(wait_step), Origin::Generated)];
+        ordered.extend(late);
+        return ordered;
+    }
+
+    // Late steps after a wait (or block) step wait for the builds through it,
+    // those before need to depend on them directly (as do any depending on
+    // the wait step there'd otherwise be)
+    let mut build_keys: Vec<_> = build_keys.iter().cloned().collect();
+    build_keys.sort();
+    let leading = late.iter().take_while(|(s, _)| !s.is_barrier()).count();
+    for (i, (step, _)) in late.iter_mut().enumerate() {
+        let depends_on = step.depends_on_mut();
+        let waits = depends_on.iter().flatten().any(|d| d == WAIT_BUILDS_KEY);
+        if i >= leading && !waits {
+            continue;
+        }
+        let depends_on = depends_on.get_or_insert_with(Vec::new);
+        depends_on.retain(|d| d != WAIT_BUILDS_KEY);
+        depends_on.extend(build_keys.iter().cloned());
+        depends_on.sort();
+        depends_on.dedup();
+    }
+
+    early.into_iter().chain(late).collect()
+}
+
+/// A system to evaluate the CI config for, and where to run its steps.
+struct SystemTarget {
+    system: String,
+    /// The Buildkite queue of agents that build for the system (if unset,
+    /// steps run on any agent)
+    queue: Option<String>,
+}
+
+/// Find the systems to build for: those the CI config is declared for that
+/// have a queue of agents, or that we're running on.
+fn find_systems(args: &BuildkiteArgs) -> Result<Vec<SystemTarget>, DerivePipelineError> {
+    Ok(select_systems(
+        ci_systems(&args.path)?,
+        &args.queues,
+        SYSTEM,
+    ))
+}
+
+/// Pair each of `systems` with its queue from `queues`, leaving out those
+/// without one other than `host`.
+fn select_systems(
+    systems: Vec<String>,
+    queues: &HashMap<String, String>,
+    host: &str,
+) -> Vec<SystemTarget> {
+    let mut targets = Vec::new();
+    for system in systems {
+        let queue = queues.get(&system).cloned();
+        if queue.is_none() && system != host {
+            log::warn!("no queue configured for {system}, not building for it");
+            continue;
+        }
+        targets.push(SystemTarget { system, queue });
+    }
+
+    targets
+}
+
+/// Give the keys (and labels) of steps from the CI config for `system` the
+/// system's name, so they're unique across systems. `keys` are the keys of
+/// the config's steps and of the system's build steps, which dependencies on
+/// are renamed to match.
+fn qualify_user_step(step: &mut Step, system: &str, keys: &HashSet<String>) {
+    let rename = |key: &mut String| *key = format!("{key}-{system}");
+    let rename_deps = |deps: &mut Option<Vec<String>>| {
+        for dep in deps.iter_mut().flatten() {
+            if keys.contains(dep) {
+                rename(dep);
+            }
+        }
+    };
+    let relabel = |label: &mut Option<String>| {
+        if let Some(label) = label {
+            *label = format!("{label} ({system})");
+        }
+    };
+
+    match step {
+        Step::Block(s) => {
+            rename_deps(&mut s.depends_on);
+            relabel(&mut s.label);
+        }
+        Step::Command(s) => {
+            rename(&mut s.key);
+            rename_deps(&mut s.depends_on);
+            relabel(&mut s.label);
+        }
+        Step::Trigger(s) => {
+            rename(&mut s.build.key);
+            rename_deps(&mut s.build.depends_on);
+            relabel(&mut s.build.label);
+        }
+        Step::Wait(s) => {
+            rename(&mut s.key);
+            rename_deps(&mut s.depends_on);
+        }
+    }
+}
+
+/// Quote `s` to be passed to the shell as a single word, as is.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// The CI config evaluated for a system.
+struct SystemEvaluation<'a> {
+    target: &'a SystemTarget,
+    eval: BuildEvaluation,
+    /// The builds each command needs (by build key), from the derivation
+    /// graph
+    needs: HashMap<String, Vec<String>>,
 }
 
 // TODO: should this have its' own error type?
 fn make_buildkite_pipeline(
     cmd: String,
     args: BuildkiteArgs,
+    cache: Option<&CacheClient>,
+    systems: &[SystemTarget],
 ) -> Result<BuildkitePipeline, DerivePipelineError> {
-    capture_buildkite_state(args.clone())?;
-    let mut eval = BuildEvaluation::from_env(&args.path)?;
+    let mut evals = Vec::with_capacity(systems.len());
+    for target in systems {
+        let eval = BuildEvaluation::from_env(&args.path, &target.system)?;
+        let needs = find_command_dependencies(&args.path, &target.system, &eval);
+        evals.push(SystemEvaluation {
+            target,
+            eval,
+            needs,
+        });
+    }
 
-    // start with all the steps building our derivations
-    // TODO: check which derivations have been built already
-    let mut steps: Vec<_> = eval
-        .builds
-        .into_iter()
-        .map(|(k, v)| {
+    let mut cached = HashMap::new();
+    if let Some(c) = cache {
+        for e in &evals {
+            cached.extend(find_cached_builds(c, &e.eval.builds));
+        }
+    }
+
+    Ok(generate_pipeline(&cmd, evals, &cached)?)
+}
+
+/// The steps of the pipeline for the CI config of each system, running `ci`
+/// as `cmd`. Derivations with a successful build in `cached` (by output hash)
+/// aren't built again.
+fn generate_pipeline(
+    cmd: &str,
+    evals: Vec<SystemEvaluation>,
+    cached: &HashMap<String, BuildRecord>,
+) -> Result<BuildkitePipeline, ValidationError> {
+    // Keys (and labels) only need the system when there's more than one
+    let qualified = evals.len() > 1;
+    let qualify = |key: String, system: &str| match qualified {
+        true => format!("{key}-{system}"),
+        false => key,
+    };
+    let qualify_label = |label: String, system: &str| match qualified {
+        true => format!("{label} ({system})"),
+        false => label,
+    };
+
+    let mut n_cached = 0;
+    let mut builds = HashMap::new();
+    let mut steps = Vec::new();
+    let mut origins = Vec::new();
+    // with the build steps each needs, per system
+    let mut user_steps: Vec<(Vec<PlacedStep>, HashMap<_, _>)> = Vec::new();
+    for SystemEvaluation {
+        target,
+        mut eval,
+        needs,
+    } in evals
+    {
+        let system = target.system.as_str();
+        // the unqualified keys config steps can depend on
+        let mut local_keys: HashSet<_> = eval.builds.keys().map(|k| format!("build-{k}")).collect();
+        // start with all the steps building our derivations, in a stable
+        // order so rendered pipelines can be diffed
+        let mut found: Vec<_> = eval.builds.into_iter().collect();
+        found.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (k, v) in found {
+            let key = qualify(format!("build-{k}"), system);
             let mut b = CommandStep::builder();
-            let args = format!("$CI_COMMAND build {}", v.tag);
-            b.set_label(v.label());
-            Step::Command(b.build(format!("build-{k}"), args))
-        })
-        .collect();
+            if let Some(queue) = &target.queue {
+                b.set_agent("queue".to_string(), queue.clone());
+            }
+            origins.push(Origin::Build(v.tag.clone()));
 
-    if !eval.steps.is_empty() {
-        // add a wait step so all builds run first (necessary?)
-        steps.push(Step::Wait(
-            WaitStep::builder().build("wait-builds".to_string()),
-        ));
+            let prev = v.output_hash().and_then(|h| cached.get(&h));
+            if let Some(record) = prev {
+                // Keep a (trivial) step with the same key in place of the
+                // build, so that anything depending on it still resolves.
+                n_cached += 1;
+                let note = format!("{} was already built in {}", v.tag, record.build_url);
+                let note = format!("echo {}", shell_quote(&note));
+                b.set_label(qualify_label(v.cached_label(), system));
+                steps.push(Step::Command(b.build(key, note)));
+                continue;
+            }
+
+            let args = format!("$CI_COMMAND build {}", v.tag);
+            b.set_label(qualify_label(v.label(), system));
+            if let Some(hash) = v.output_hash() {
+                let tag = v.tag.clone();
+                builds.insert(key.clone(), ScheduledBuild { hash, tag });
+            }
+            steps.push(Step::Command(b.build(key, args)));
+        }
 
         eval.steps.iter_mut().for_each(|mut step| {
             if let Step::Command(ref mut s) = &mut step {
-                s.command = s.command.replace("@tool@", &cmd);
+                s.command = s.command.replace("@tool@", cmd);
             }
         });
 
         // add the additional requested ones from our evaluated config
         // (likely releases, deployments, other automated actions)
-        steps.extend(eval.steps);
+        local_keys.extend(eval.steps.iter().filter_map(Step::key).map(str::to_string));
+        let placed = eval.steps.into_iter().enumerate().map(|(i, mut step)| {
+            let attr = match step.key() {
+                Some(key) => config_attribute(system, &format!("steps.{key}")),
+                None => format!("{}.{i}", config_attribute(system, "evaluation.steps")),
+            };
+            if qualified {
+                qualify_user_step(&mut step, system, &local_keys);
+            }
+            if let (Step::Command(s), Some(queue)) = (&mut step, &target.queue) {
+                let agents = s.agents.get_or_insert_with(BTreeMap::new);
+                agents.entry("queue".to_string()).or_insert(queue.clone());
+            }
+            (step, Origin::Config(attr))
+        });
+        let needs = needs
+            .into_iter()
+            .map(|(key, ks)| {
+                let deps = ks
+                    .into_iter()
+                    .map(|k| qualify(format!("build-{k}"), system))
+                    .collect();
+                (qualify(key, system), deps)
+            })
+            .collect();
+        user_steps.push((placed.collect(), needs));
+    }
+
+    if n_cached > 0 {
+        log::info!("skipping {n_cached} already-built derivations");
+    }
+
+    if user_steps.iter().any(|(placed, _)| !placed.is_empty()) {
+        let build_keys: HashSet<_> = steps
+            .iter()
+            .filter_map(Step::key)
+            .map(str::to_string)
+            .collect();
+        let (mut early, mut late) = (Vec::new(), Vec::new());
+        for (placed, needs) in user_steps {
+            let (e, l) = schedule_user_steps(placed, &needs, &build_keys);
+            early.extend(e);
+            late.extend(l);
+        }
+        if !early.is_empty() {
+            log::info!("{} steps can start before every build is done", early.len());
+        }
+
+        let (user_steps, user_origins): (Vec<_>, Vec<_>) =
+            order_user_steps(early, late, &build_keys)
+                .into_iter()
+                .unzip();
+        steps.extend(user_steps);
+        origins.extend(user_origins);
     }
 
     let mut wait_step_b = WaitStep::builder();
@@ -137,28 +541,135 @@ fn make_buildkite_pipeline(
         .set_label(":shopping_trolley: collect results".to_string())
         .set_timeout_in_minutes(3)
         .set_allow_dependency_failure(true);
-    let cmd_step = cmd_step_b.build(
-        "collect-results".to_string(),
-        [cmd, "collect".to_string()].join(" "),
-    );
+    let cmd_step = cmd_step_b.build(COLLECT_STEP_KEY.to_string(), format!("{cmd} collect"));
 
     steps.extend([Step::Wait(wait_step), Step::Command(cmd_step)]);
+    origins.extend([Origin::Generated, Origin::Generated]);
+
+    let graph = validate(&steps, &origins)?;
+
+    Ok(BuildkitePipeline {
+        steps,
+        builds,
+        graph,
+    })
+}
 
-    Ok(BuildkitePipeline { steps })
+/// Tell the cache server about the builds we've scheduled, so it can record
+/// their results even if `collect` never runs.
+///
+/// This is best-effort, `collect` records results either way.
+fn register_scheduled_builds(
+    cache: Option<&CacheClient>,
+    args: &BuildkiteArgs,
+    builds: &HashMap<String, ScheduledBuild>,
+) {
+    let (Some(cache), Some(build_id), Some(build_url)) = (cache, &args.build_id, &args.build_url)
+    else {
+        return;
+    };
+    if builds.is_empty() {
+        return;
+    }
+
+    let schedule = BuildSchedule {
+        build_id: build_id.clone(),
+        build_url: build_url.clone(),
+        steps: builds
+            .iter()
+            .map(|(key, build)| ScheduledStep {
+                step_key: key.clone(),
+                hash: build.hash.clone(),
+                tag: Some(build.tag.clone()),
+            })
+            .collect(),
+    };
+    if let Err(e) = cache.schedule_builds(&schedule) {
+        log::warn!("error registering scheduled builds with cache server: {e}");
+    }
 }
 
-fn evaluate(cmd_name: String, args: BuildkiteArgs) -> Result<i32, EvaluateError> {
+/// Tell the cache server about this build, so `collect` can record the
+/// outcome of each of its steps.
+///
+/// This is best-effort, derivation builds are recorded either way.
+fn register_build(cache: Option<&CacheClient>, args: &BuildkiteArgs) {
+    let (Some(cache), Some(build_id), Some(build_url)) = (cache, &args.build_id, &args.build_url)
+    else {
+        return;
+    };
+
+    let report = CIRunState::from_args(args.clone()).build_report(build_url.clone());
+    if let Err(e) = cache.record_build(build_id, &report) {
+        log::warn!("error registering build with cache server: {e}");
+    }
+}
+
+/// The command steps whose outcomes `collect` reports (i.e., all of them
+/// except `collect` itself).
+fn pipeline_steps(steps: &[Step]) -> Vec<PipelineStep> {
+    steps
+        .iter()
+        .filter_map(|step| match step {
+            Step::Command(s) if s.key != COLLECT_STEP_KEY => Some(PipelineStep {
+                key: s.key.clone(),
+                label: s.label.clone(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+fn evaluate(
+    cmd_name: String,
+    args: BuildkiteArgs,
+    cache: Option<CacheClient>,
+) -> Result<i32, EvaluateError> {
+    capture_buildkite_state(args.clone())?;
     log::info!("Evaluating pipeline");
-    let pipeline = make_buildkite_pipeline(cmd_name, args)?;
+    let systems = find_systems(&args)?;
+    let pipeline = make_buildkite_pipeline(cmd_name, args.clone(), cache.as_ref(), &systems)?;
     log::trace!("Encoding to JSON");
     let json_data = serde_json::to_vec(&pipeline)?;
 
+    log::info!("Recording scheduled builds");
+    results::record_scheduled_builds(&pipeline.builds)?;
+    results::record_pipeline_steps(&pipeline_steps(&pipeline.steps))?;
+    register_build(cache.as_ref(), &args);
+    register_scheduled_builds(cache.as_ref(), &args, &pipeline.builds);
+
     log::info!("Uploading buildkite pipeline");
     Cli.pipeline_upload_bytes(&json_data)?;
 
     Ok(0)
 }
 
+#[derive(thiserror::Error, Debug)]
+enum RenderError {
+    #[error("error deriving pipeline: {0}")]
+    Deriving(#[from] DerivePipelineError),
+    #[error("error encoding pipeline to JSON: {0}")]
+    EncodingJson(#[from] serde_json::Error),
+    #[error("error encoding pipeline to YAML: {0}")]
+    EncodingYaml(#[from] serde_yaml::Error),
+}
+
+/// Print the pipeline `evaluate` would upload, e.g. to review changes to it.
+///
+/// Nothing is recorded, and builds aren't looked up in the cache server, so
+/// the output depends only on what's checked out.
+fn render(cmd_name: String, args: BuildkiteArgs, format: Format) -> Result<i32, RenderError> {
+    let systems = find_systems(&args)?;
+    let pipeline = make_buildkite_pipeline(cmd_name, args, None, &systems)?;
+    let rendered = match format {
+        Format::Json => serde_json::to_string_pretty(&pipeline)? + "\n",
+        Format::Yaml => serde_yaml::to_string(&pipeline)?,
+    };
+    print!("{rendered}");
+
+    Ok(0)
+}
+
 #[derive(thiserror::Error, Debug)]
 enum ExecuteError {
     #[error("error applying git state: {0}")]
@@ -176,7 +687,10 @@ fn nix_action(
 ) -> Result<i32, ExecuteError> {
     let msg = action.join(" ");
     log::info!("preparing `nix {msg}`");
-    apply(&args)?;
+    // Local runs build the working tree as it is
+    if !args.local {
+        apply(&args)?;
+    }
     let target_str = format!(".#{target}");
     log::info!("running `nix {msg} {target_str}`");
     let res = Command::new("nix")
@@ -190,13 +704,246 @@ fn nix_action(
     Ok(res.code().unwrap_or(1))
 }
 
+/// Claim the lease on building the derivation built by this step, so no
+/// other pipeline builds it at the same time.
+///
+/// Leases are best-effort, if anything goes wrong we just build.
+fn claim_build_lease(
+    cache: &CacheClient,
+    args: &BuildkiteArgs,
+    step_key: &str,
+) -> Option<(ScheduledBuild, Acquired)> {
+    let (build_id, build_url) = (args.build_id.as_ref()?, args.build_url.as_ref()?);
+    let mut builds = match results::scheduled_builds() {
+        Ok(builds) => builds,
+        Err(e) => {
+            log::warn!("error reading scheduled builds, not claiming a lease: {e}");
+            return None;
+        }
+    };
+    let build = builds.remove(step_key)?;
+
+    match lease::acquire(cache, &build.hash, build_id, build_url) {
+        Ok(acquired) => Some((build, acquired)),
+        Err(e) => {
+            log::warn!("error claiming build lease, building anyway: {e}");
+            None
+        }
+    }
+}
+
+fn build(
+    args: BuildkiteArgs,
+    target: String,
+    cache: Option<CacheClient>,
+) -> Result<i32, ExecuteError> {
+    // Only steps we generated have keys we can map back to derivations, and
+    // local runs have no scheduled builds to map them to
+    let step_key = std::env::var("BUILDKITE_STEP_KEY")
+        .ok()
+        .filter(|_| !args.local);
+    let record_time = |event| {
+        if let Some(key) = &step_key {
+            if let Err(e) = results::record_step_time(event, key) {
+                log::warn!("error recording build time: {e}");
+            }
+        }
+    };
+
+    let claimed = match (&cache, &step_key) {
+        (Some(cache), Some(key)) => claim_build_lease(cache, &args, key),
+        _ => None,
+    };
+    let (build, guard) = match claimed {
+        Some((build, Acquired::BuiltElsewhere(record))) => {
+            // Without a recorded start time, `collect` won't record this step
+            // as a build
+            log::info!("{} was built by {}, skipping", build.tag, record.build_url);
+            return Ok(0);
+        }
+        Some((build, Acquired::Held(guard))) => (Some(build), Some(guard)),
+        None => (None, None),
+    };
+
+    let (build_id, build_url) = (args.build_id.clone(), args.build_url.clone());
+    let started_at = Utc::now();
+    record_time(StepEvent::Started);
+    let code = nix_action(&["build", "--no-link"], args, target)?;
+    record_time(StepEvent::Finished);
+
+    // Builds waiting on our lease need the result before `collect` runs
+    if let (Some(cache), Some(build), Some(build_id), Some(build_url)) =
+        (cache, build, build_id, build_url)
+    {
+        let record = BuildRecord {
+            hash: build.hash,
+            build_id,
+            build_url,
+            started_at,
+            finished_at: Some(Utc::now()),
+            success: Some(code == 0),
+            tag: Some(build.tag.clone()),
+        };
+        let res = cache
+            .insert_start(&record)
+            .and_then(|_| cache.mark_finish(&record));
+        if let Err(e) = res {
+            log::warn!("error recording build of {}: {e}", build.tag);
+        }
+    }
+    drop(guard);
+
+    Ok(code)
+}
+
 #[derive(thiserror::Error, Debug)]
-pub enum CollectError {}
+pub enum CollectError {
+    #[error("missing {0}, is this running in buildkite?")]
+    MissingBuildInfo(&'static str),
+    #[error("error reading build results: {0}")]
+    ReadingResults(#[from] ResultsError),
+}
 
-fn collect_final_pipeline_state(_args: BuildkiteArgs) -> Result<i32, CollectError> {
-    Ok(0)
-    // TODO: Collect results from the builds and send them back to DB for
-    // caching
+/// Report the outcome of every step of this build to the cache server.
+///
+/// This is best-effort, as step outcomes are only informational.
+fn record_step_runs(
+    cache: &CacheClient,
+    build_id: &str,
+    scheduled: &HashMap<String, ScheduledBuild>,
+) -> Result<(), CollectError> {
+    for step in results::pipeline_steps()? {
+        let result = StepResult {
+            label: step.label,
+            hash: scheduled.get(&step.key).map(|b| b.hash.clone()),
+            started_at: results::step_time(StepEvent::Started, &step.key)?,
+            finished_at: results::step_time(StepEvent::Finished, &step.key)?,
+            success: results::step_outcome(&step.key)?,
+        };
+        if let Err(e) = cache.record_step_run(build_id, &step.key, &result) {
+            log::warn!("error recording outcome of step {}: {e}", step.key);
+        }
+    }
+
+    Ok(())
+}
+
+fn collect_final_pipeline_state(
+    args: BuildkiteArgs,
+    cache: Option<CacheClient>,
+) -> Result<i32, CollectError> {
+    let Some(cache) = cache else {
+        log::warn!("no cache server configured, not recording build results");
+        return Ok(0);
+    };
+
+    let build_id = args
+        .build_id
+        .ok_or(CollectError::MissingBuildInfo("build ID"))?;
+    let build_url = args
+        .build_url
+        .ok_or(CollectError::MissingBuildInfo("build URL"))?;
+
+    let scheduled = results::scheduled_builds()?;
+
+    // Builds that held a lease have already recorded their results
+    let hashes: Vec<_> = scheduled.values().map(|b| b.hash.clone()).collect();
+    let recorded: Vec<_> = match cache.query(&hashes) {
+        Ok(records) => records
+            .into_iter()
+            .filter(|r| r.build_id == build_id && r.finished_at.is_some())
+            .map(|r| r.hash)
+            .collect(),
+        Err(e) => {
+            log::warn!("error querying recorded builds: {e}");
+            Vec::new()
+        }
+    };
+
+    let mut n_failed = 0;
+    for (key, build) in &scheduled {
+        if recorded.contains(&build.hash) {
+            log::debug!("build of {} was recorded by its step", build.tag);
+            continue;
+        }
+
+        let Some(success) = results::step_outcome(key)? else {
+            log::info!("step {key} has no final outcome, not recording");
+            continue;
+        };
+
+        let started_at = results::step_time(StepEvent::Started, key)?;
+        let finished_at = results::step_time(StepEvent::Finished, key)?;
+        let Some(started_at) = started_at else {
+            log::warn!("step {key} has no recorded start time, not recording");
+            continue;
+        };
+
+        let record = BuildRecord {
+            hash: build.hash.clone(),
+            build_id: build_id.clone(),
+            build_url: build_url.clone(),
+            started_at,
+            finished_at: Some(finished_at.unwrap_or_else(Utc::now)),
+            success: Some(success),
+            tag: Some(build.tag.clone()),
+        };
+
+        log::info!("recording build of {} (success: {success})", build.tag);
+        let res = cache
+            .insert_start(&record)
+            .and_then(|_| cache.mark_finish(&record));
+        if let Err(e) = res {
+            log::error!("error recording build of {}: {e}", build.tag);
+            n_failed += 1;
+        }
+    }
+
+    // After the builds, so steps can be linked to the derivations they built
+    record_step_runs(&cache, &build_id, &scheduled)?;
+
+    Ok(if n_failed > 0 { 1 } else { 0 })
+}
+
+#[derive(thiserror::Error, Debug)]
+enum RunLocalError {
+    #[error("error deriving pipeline: {0}")]
+    Deriving(#[from] DerivePipelineError),
+    #[error("error finding path to `ci`: {0}")]
+    FindingExecutable(std::io::Error),
+}
+
+/// Run the pipeline's steps on this machine, for trying out changes to it
+/// without pushing them.
+fn run_local(
+    args: BuildkiteArgs,
+    jobs: Option<usize>,
+    cache: Option<CacheClient>,
+) -> Result<i32, RunLocalError> {
+    let exe = std::env::current_exe().map_err(RunLocalError::FindingExecutable)?;
+    log::info!("Evaluating pipeline");
+    // Steps call back into this executable, which is passed to them in
+    // `$CI_COMMAND`
+    let cmd = r#""$CI_COMMAND""#.to_string();
+    // Only steps for this machine's system can run here
+    let systems = [SystemTarget {
+        system: SYSTEM.to_string(),
+        queue: None,
+    }];
+    let pipeline = make_buildkite_pipeline(cmd, args.clone(), cache.as_ref(), &systems)?;
+
+    let jobs = jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, NonZeroUsize::get));
+    log::info!("Running {} steps, {jobs} at a time", pipeline.steps.len());
+    let passed = local::run(
+        &pipeline.steps,
+        &pipeline.graph,
+        &[COLLECT_STEP_KEY],
+        &args,
+        &exe,
+        jobs,
+    );
+
+    Ok(if passed { 0 } else { 1 })
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -207,23 +954,30 @@ enum MainError {
     Executing(#[from] ExecuteError),
     #[error("error collecting final pipeline state: {0}")]
     Collecting(#[from] CollectError),
+    #[error("error running pipeline locally: {0}")]
+    RunningLocally(#[from] RunLocalError),
+    #[error("error rendering pipeline: {0}")]
+    Rendering(#[from] RenderError),
+    #[error("error reading local git checkout: {0}")]
+    ReadingRepo(#[from] ReadingRepoError),
 }
 
 fn real_main() -> Result<i32, MainError> {
     let args = CliArgs::parse();
 
-    let (cmd, log_level, action, bk) = args.into_parts();
+    let (cmd, server, log_level, action, bk) = args.into_parts()?;
     SimpleLogger::new()
         .with_level(log_level)
         .init()
         .expect("failed to set logging");
+    let cache = server.url.map(|url| CacheClient::new(url, server.token));
     let code = match action {
-        Action::Evaluate => evaluate(cmd, bk)?,
+        Action::Evaluate => evaluate(cmd, bk, cache)?,
         Action::Execute { target } => nix_action(&["run"], bk, target)?,
-        Action::Build { target } => nix_action(&["build", "--no-link"], bk, target)?,
-        // TODO: need to have this collect information about the CI job after
-        // all steps have finished
-        Action::Collect => collect_final_pipeline_state(bk)?,
+        Action::Build { target } => build(bk, target, cache)?,
+        Action::Collect => collect_final_pipeline_state(bk, cache)?,
+        Action::RunLocal { jobs } => run_local(bk, jobs, cache)?,
+        Action::Render { format } => render(cmd, bk, format)?,
     };
 
     Ok(code)
@@ -251,3 +1005,302 @@ fn main() {
 
     std::process::exit(code);
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn placed(steps: Value) -> Vec<PlacedStep> {
+        let steps: Vec<Step> = serde_json::from_value(steps).unwrap();
+        steps.into_iter().map(|s| (s, Origin::Generated)).collect()
+    }
+
+    fn command(key: &str, depends_on: &[&str]) -> Value {
+        json!({"type": "command", "key": key, "command": "true", "depends_on": depends_on})
+    }
+
+    fn wait(key: &str) -> Value {
+        json!({
+            "type": "wait",
+            "key": key,
+            "allow_dependency_failure": false,
+            "continue_on_failure": false,
+        })
+    }
+
+    fn keys(steps: &[PlacedStep]) -> Vec<&str> {
+        steps.iter().map(|(s, _)| s.key().unwrap()).collect()
+    }
+
+    fn depends_on<'a>(steps: &'a [PlacedStep], key: &str) -> &'a [String] {
+        let (step, _) = steps.iter().find(|(s, _)| s.key() == Some(key)).unwrap();
+        step.depends_on()
+    }
+
+    #[test]
+    fn commands_before_waits_start_with_the_builds_they_need() {
+        let steps = placed(json!([
+            command("deploy", &[]),
+            command("notify", &["deploy"]),
+            command("unknown", &[]),
+            command("after-unknown", &["unknown"]),
+            command("after-build", &["build-tool"]),
+            wait("wait"),
+            command("release", &[]),
+        ]));
+        let needs = HashMap::from([
+            ("deploy".to_string(), vec!["build-app".to_string()]),
+            ("notify".to_string(), vec![]),
+            ("after-unknown".to_string(), vec![]),
+            ("after-build".to_string(), vec!["build-app".to_string()]),
+            ("release".to_string(), vec!["build-app".to_string()]),
+        ]);
+        let build_keys = HashSet::from(["build-app".to_string(), "build-tool".to_string()]);
+
+        let (early, late) = schedule_user_steps(steps, &needs, &build_keys);
+
+        assert_eq!(keys(&early), ["deploy", "notify", "after-build"]);
+        assert_eq!(keys(&late), ["unknown", "after-unknown", "wait", "release"]);
+        assert_eq!(depends_on(&early, "deploy"), ["build-app"]);
+        assert_eq!(depends_on(&early, "notify"), ["deploy"]);
+        assert_eq!(
+            depends_on(&early, "after-build"),
+            ["build-app", "build-tool"]
+        );
+        assert!(depends_on(&late, "release").is_empty());
+    }
+
+    #[test]
+    fn late_steps_dont_wait_for_early_ones() {
+        let builds = placed(json!([
+            command("build-app", &[]),
+            command("build-tool", &[])
+        ]));
+        let early = placed(json!([command("deploy", &["build-app"])]));
+        let late = placed(json!([
+            command("lint", &[]),
+            command("test", &["lint"]),
+            wait("wait"),
+            command("release", &[]),
+            command("package", &["wait-builds"]),
+        ]));
+        let build_keys = HashSet::from(["build-app".to_string(), "build-tool".to_string()]);
+
+        let ordered = order_user_steps(early, late, &build_keys);
+        assert_eq!(
+            keys(&ordered),
+            ["deploy", "lint", "test", "wait", "release", "package"]
+        );
+        assert_eq!(depends_on(&ordered, "lint"), ["build-app", "build-tool"]);
+        assert_eq!(
+            depends_on(&ordered, "test"),
+            ["build-app", "build-tool", "lint"]
+        );
+        assert!(depends_on(&ordered, "release").is_empty());
+        assert_eq!(depends_on(&ordered, "package"), ["build-app", "build-tool"]);
+
+        let (steps, origins): (Vec<_>, Vec<_>) = builds.into_iter().chain(ordered).unzip();
+        let graph = validate(&steps, &origins).unwrap();
+        // build-app, build-tool, deploy, lint, test, wait, release, package
+        assert_eq!(graph.deps[3], [0, 1]);
+        assert_eq!(graph.deps[4], [0, 1, 3]);
+        assert_eq!(graph.deps[5], [0, 1, 2, 3, 4]);
+        assert_eq!(graph.deps[6], [5]);
+        assert_eq!(graph.deps[7], [5, 0, 1]);
+    }
+
+    #[test]
+    fn late_steps_wait_for_every_build_without_early_steps() {
+        let late = placed(json!([command("lint", &[])]));
+        let build_keys = HashSet::from(["build-app".to_string()]);
+
+        let ordered = order_user_steps(Vec::new(), late, &build_keys);
+        assert_eq!(keys(&ordered), ["wait-builds", "lint"]);
+        assert!(depends_on(&ordered, "lint").is_empty());
+    }
+
+    #[test]
+    fn systems_without_queues_are_skipped_unless_local() {
+        let systems = ["aarch64-darwin", "aarch64-linux", "x86_64-linux"];
+        let systems = systems.map(str::to_string).to_vec();
+        let queues = HashMap::from([
+            ("aarch64-linux".to_string(), "arm".to_string()),
+            ("riscv64-linux".to_string(), "riscv".to_string()),
+        ]);
+
+        let targets = select_systems(systems, &queues, "x86_64-linux");
+        let targets: Vec<_> = targets
+            .iter()
+            .map(|t| (t.system.as_str(), t.queue.as_deref()))
+            .collect();
+        assert_eq!(
+            targets,
+            [("aarch64-linux", Some("arm")), ("x86_64-linux", None)]
+        );
+    }
+
+    #[test]
+    fn user_steps_are_qualified_by_system() {
+        let mut steps: Vec<Step> = serde_json::from_value(json!([
+            {
+                "type": "command",
+                "key": "deploy",
+                "label": "Deploy",
+                "command": "true",
+                "depends_on": ["test", "build-hello"],
+            },
+            {
+                "type": "wait",
+                "key": "wait",
+                "allow_dependency_failure": false,
+                "continue_on_failure": false,
+                "depends_on": ["deploy"],
+            },
+            {
+                "type": "block",
+                "block": "Release?",
+                "label": "Release",
+                "blocked_state": "passed",
+                "depends_on": ["deploy"],
+            },
+            {
+                "type": "trigger",
+                "async": false,
+                "build": {"key": "downstream", "label": "Downstream"},
+            },
+        ]))
+        .unwrap();
+        let keys = ["build-hello", "deploy", "test", "wait", "downstream"];
+        let keys = HashSet::from(keys.map(String::from));
+
+        for step in &mut steps {
+            qualify_user_step(step, "x86_64-linux", &keys);
+        }
+
+        let Step::Command(deploy) = &steps[0] else {
+            unreachable!()
+        };
+        assert_eq!(deploy.key, "deploy-x86_64-linux");
+        assert_eq!(deploy.label.as_deref(), Some("Deploy (x86_64-linux)"));
+        assert_eq!(
+            deploy.depends_on.as_deref().unwrap(),
+            ["test-x86_64-linux", "build-hello-x86_64-linux"]
+        );
+        assert_eq!(steps[1].key(), Some("wait-x86_64-linux"));
+        assert_eq!(steps[1].depends_on(), ["deploy-x86_64-linux"]);
+        let Step::Block(block) = &steps[2] else {
+            unreachable!()
+        };
+        assert_eq!(block.label.as_deref(), Some("Release (x86_64-linux)"));
+        assert_eq!(steps[2].depends_on(), ["deploy-x86_64-linux"]);
+        let Step::Trigger(trigger) = &steps[3] else {
+            unreachable!()
+        };
+        assert_eq!(trigger.build.key, "downstream-x86_64-linux");
+        assert_eq!(
+            trigger.build.label.as_deref(),
+            Some("Downstream (x86_64-linux)")
+        );
+    }
+
+    #[test]
+    fn shell_quoted_words_are_passed_as_is() {
+        for word in ["", "it's", "''", "$HOME `id` \"x\" \\", "a b\nc"] {
+            let output = Command::new("sh")
+                .args(["-c", &format!("printf %s {}", shell_quote(word))])
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), word);
+        }
+    }
+
+    /// The CI config for `system`, whose derivations' outputs have hashes
+    /// starting with `hash`.
+    fn evaluation(system: &str, hash: &str) -> BuildEvaluation {
+        let build = |name: &str| {
+            json!({
+                "name": name,
+                "build_type": "package",
+                "path": format!("/nix/store/{hash}{name}-{name}"),
+                "tag": format!("packages.{system}.{name}"),
+            })
+        };
+        serde_json::from_value(json!({
+            "builds": {"package-hello": build("hello"), "package-tool": build("tool")},
+            "steps": [
+                {"type": "command", "key": "deploy", "label": "Deploy", "command": "@tool@ deploy"},
+                {"type": "command", "key": "lint", "command": "lint"},
+                {
+                    "type": "wait",
+                    "key": "wait",
+                    "allow_dependency_failure": false,
+                    "continue_on_failure": false,
+                },
+                {
+                    "type": "command",
+                    "key": "release",
+                    "command": "release",
+                    "depends_on": ["deploy", "build-package-tool"],
+                },
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn generated_pipeline() {
+        let targets = [
+            SystemTarget {
+                system: "x86_64-linux".to_string(),
+                queue: None,
+            },
+            SystemTarget {
+                system: "aarch64-linux".to_string(),
+                queue: Some("arm".to_string()),
+            },
+        ];
+        let evals = targets
+            .iter()
+            .zip(["x", "a"])
+            .map(|(target, hash)| SystemEvaluation {
+                target,
+                eval: evaluation(&target.system, hash),
+                needs: HashMap::from([("deploy".to_string(), vec!["package-hello".to_string()])]),
+            })
+            .collect();
+        let cached: BuildRecord = serde_json::from_value(json!({
+            "hash": "atool",
+            "build_id": "018e9c2f-3f9a-4a7c-9a0e-8b1f2f6f1e2d",
+            "build_url": "https://buildkite.com/org/pipeline/builds/1",
+            "started_at": "2024-04-01T12:00:00Z",
+            "finished_at": "2024-04-01T12:05:00Z",
+            "success": true,
+        }))
+        .unwrap();
+        let cached = HashMap::from([("atool".to_string(), cached)]);
+
+        let pipeline = generate_pipeline("ci", evals, &cached).unwrap();
+
+        let snapshot = include_str!("../testdata/pipeline.yaml");
+        assert_eq!(serde_yaml::to_string(&pipeline).unwrap(), snapshot);
+        let snapshot: Value = serde_yaml::from_str(snapshot).unwrap();
+        assert_eq!(serde_json::to_value(&pipeline).unwrap(), snapshot);
+
+        let mut built: Vec<_> = pipeline
+            .builds
+            .iter()
+            .map(|(key, b)| (key.as_str(), b.hash.as_str()))
+            .collect();
+        built.sort();
+        assert_eq!(
+            built,
+            [
+                ("build-package-hello-aarch64-linux", "ahello"),
+                ("build-package-hello-x86_64-linux", "xhello"),
+                ("build-package-tool-x86_64-linux", "xtool"),
+            ]
+        );
+    }
+}
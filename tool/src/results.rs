@@ -0,0 +1,127 @@
+// Build results are passed between the steps of a CI run through buildkite
+// meta-data:
+//  - `evaluate` records which derivation each `build-*` step is building,
+//    and the key and label of every command step
+//  - each `build` step records when it started and finished
+//  - `collect` reads all of the above back to report to the cache server
+//    (except for builds that held a lease, which report their own results as
+//    soon as they finish), along with the outcome of every step
+//
+// `evaluate` also registers the scheduled builds with the cache server, which
+// records their results from Buildkite webhooks in case `collect` never runs.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::buildkite::{Cli, RunError};
+
+const SCHEDULED_BUILDS_KEY: &str = "ci:builds";
+const PIPELINE_STEPS_KEY: &str = "ci:steps";
+
+/// A derivation build that was scheduled in the uploaded pipeline.
+#[derive(Deserialize, Serialize)]
+pub struct ScheduledBuild {
+    pub hash: String,
+    pub tag: String,
+}
+
+/// A command step in the uploaded pipeline, whether or not it builds a
+/// derivation.
+#[derive(Deserialize, Serialize)]
+pub struct PipelineStep {
+    pub key: String,
+    pub label: Option<String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ResultsError {
+    #[error("error running buildkite-agent: {0}")]
+    RunningBKAgent(#[from] RunError),
+    #[error("error parsing scheduled builds: {0}")]
+    ParsingBuilds(#[from] serde_json::Error),
+    #[error("error parsing timestamp: {0}")]
+    ParsingTimestamp(#[from] chrono::ParseError),
+}
+
+/// Record which derivations are built by which steps, keyed by step key.
+pub fn record_scheduled_builds(
+    builds: &HashMap<String, ScheduledBuild>,
+) -> Result<(), ResultsError> {
+    let data = serde_json::to_string(builds)?;
+    Cli.meta_data_set(SCHEDULED_BUILDS_KEY, &data)?;
+
+    Ok(())
+}
+
+pub fn scheduled_builds() -> Result<HashMap<String, ScheduledBuild>, ResultsError> {
+    let builds = match Cli.meta_data_get(SCHEDULED_BUILDS_KEY)? {
+        Some(data) => serde_json::from_str(&data)?,
+        None => HashMap::new(),
+    };
+
+    Ok(builds)
+}
+
+pub fn record_pipeline_steps(steps: &[PipelineStep]) -> Result<(), ResultsError> {
+    let data = serde_json::to_string(steps)?;
+    Cli.meta_data_set(PIPELINE_STEPS_KEY, &data)?;
+
+    Ok(())
+}
+
+pub fn pipeline_steps() -> Result<Vec<PipelineStep>, ResultsError> {
+    let steps = match Cli.meta_data_get(PIPELINE_STEPS_KEY)? {
+        Some(data) => serde_json::from_str(&data)?,
+        None => Vec::new(),
+    };
+
+    Ok(steps)
+}
+
+/// The final outcome of a step, or `None` if it didn't run to completion
+/// (e.g., it was skipped, or is still running).
+pub fn step_outcome(step_key: &str) -> Result<Option<bool>, ResultsError> {
+    // https://buildkite.com/docs/agent/v3/cli-step#getting-a-step
+    let outcome = match Cli.step_get("outcome", step_key)?.as_str() {
+        "passed" => Some(true),
+        "soft_failed" | "hard_failed" | "errored" => Some(false),
+        _ => None,
+    };
+
+    Ok(outcome)
+}
+
+#[derive(Clone, Copy)]
+pub enum StepEvent {
+    Started,
+    Finished,
+}
+
+impl StepEvent {
+    fn meta_data_key(self, step_key: &str) -> String {
+        let event = match self {
+            Self::Started => "started",
+            Self::Finished => "finished",
+        };
+
+        format!("ci:{event}:{step_key}")
+    }
+}
+
+pub fn record_step_time(event: StepEvent, step_key: &str) -> Result<(), ResultsError> {
+    let now = Utc::now().to_rfc3339();
+    Cli.meta_data_set(&event.meta_data_key(step_key), &now)?;
+
+    Ok(())
+}
+
+pub fn step_time(event: StepEvent, step_key: &str) -> Result<Option<DateTime<Utc>>, ResultsError> {
+    let time = match Cli.meta_data_get(&event.meta_data_key(step_key))? {
+        Some(t) => Some(DateTime::parse_from_rfc3339(&t)?.with_timezone(&Utc)),
+        None => None,
+    };
+
+    Ok(time)
+}
@@ -0,0 +1,454 @@
+// Checks on a pipeline before it's uploaded, so mistakes in the CI config are
+// all reported at once by `evaluate`, rather than one at a time by
+// `buildkite-agent pipeline upload` (or not at all, as a build that never
+// finishes).
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::buildkite::Step;
+
+/// Where a step in the pipeline came from, to point at it in errors.
+#[derive(Clone, Debug)]
+pub enum Origin {
+    /// Building the derivation at this flake attribute
+    Build(String),
+    /// This attribute of the CI config
+    Config(String),
+    /// Added by `ci` itself
+    Generated,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Build(tag) => write!(f, "the build of `{tag}`"),
+            Self::Config(attr) => write!(f, "`{attr}`"),
+            Self::Generated => f.write_str("`ci`"),
+        }
+    }
+}
+
+/// A step, as described in errors.
+#[derive(Clone, Debug)]
+pub struct StepRef {
+    key: Option<String>,
+    origin: Origin,
+}
+
+impl fmt::Display for StepRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.key {
+            Some(key) => write!(f, "{key:?} (from {})", self.origin),
+            None => write!(f, "block step (from {})", self.origin),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Problem {
+    DuplicateKey { key: String, steps: Vec<StepRef> },
+    InvalidKey { step: StepRef, reason: &'static str },
+    UnknownDependency { step: StepRef, dependency: String },
+    Cycle(Vec<StepRef>),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let join = |steps: &[StepRef]| {
+            let steps: Vec<_> = steps.iter().map(|s| s.to_string()).collect();
+            steps.join(", ")
+        };
+        match self {
+            Self::DuplicateKey { key, steps } => {
+                let origins: Vec<_> = steps.iter().map(|s| s.origin.to_string()).collect();
+                write!(
+                    f,
+                    "more than one step has the key {key:?}, from {}",
+                    origins.join(", ")
+                )
+            }
+            Self::InvalidKey { step, reason } => {
+                write!(f, "step {step} has an invalid key: {reason}")
+            }
+            Self::UnknownDependency { step, dependency } => write!(
+                f,
+                "step {step} depends on {dependency:?}, which isn't the key of any step"
+            ),
+            Self::Cycle(steps) => {
+                write!(f, "steps wait on each other in a cycle: {}", join(steps))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ValidationError(pub Vec<Problem>);
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.len() {
+            1 => write!(f, "found 1 problem:")?,
+            n => write!(f, "found {n} problems:")?,
+        }
+        for problem in &self.0 {
+            write!(f, "\n  - {problem}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// What each step of a pipeline waits for, by index: the wait (or block) step
+/// before it and anything it `depends_on`, or for wait steps, every step
+/// since the one before.
+pub struct StepGraph {
+    pub deps: Vec<Vec<usize>>,
+}
+
+/// Check the steps of a pipeline, given where each came from, and work out
+/// what each one waits for.
+pub fn validate(steps: &[Step], origins: &[Origin]) -> Result<StepGraph, ValidationError> {
+    let describe = |i: usize| StepRef {
+        key: steps[i].key().map(str::to_string),
+        origin: origins[i].clone(),
+    };
+    let mut problems = Vec::new();
+
+    let mut by_key: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, step) in steps.iter().enumerate() {
+        let Some(key) = step.key() else { continue };
+        if let Some(reason) = key_problem(key) {
+            let step = describe(i);
+            problems.push(Problem::InvalidKey { step, reason });
+        }
+        by_key.entry(key).or_default().push(i);
+    }
+
+    let mut duplicates: Vec<_> = by_key.iter().filter(|(_, s)| s.len() > 1).collect();
+    duplicates.sort_by_key(|(_, s)| s[0]);
+    for (key, indices) in duplicates {
+        problems.push(Problem::DuplicateKey {
+            key: key.to_string(),
+            steps: indices.iter().copied().map(describe).collect(),
+        });
+    }
+
+    let mut deps = implicit_deps(steps);
+    for (i, step) in steps.iter().enumerate() {
+        for dependency in step.depends_on() {
+            match by_key.get(dependency.as_str()) {
+                Some(indices) => deps[i].extend(indices),
+                None => problems.push(Problem::UnknownDependency {
+                    step: describe(i),
+                    dependency: dependency.clone(),
+                }),
+            }
+        }
+    }
+
+    for cycle in find_cycles(&deps) {
+        problems.push(Problem::Cycle(cycle.into_iter().map(describe).collect()));
+    }
+
+    match problems.is_empty() {
+        true => Ok(StepGraph { deps }),
+        false => Err(ValidationError(problems)),
+    }
+}
+
+/// Why Buildkite wouldn't accept `key`, if it wouldn't.
+fn key_problem(key: &str) -> Option<&'static str> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | ':');
+    if key.is_empty() {
+        Some("keys can't be empty")
+    } else if !key.chars().all(allowed) {
+        Some("keys can only contain letters, numbers, `_`, `-` and `:`")
+    } else if looks_like_uuid(key) {
+        Some("keys can't look like UUIDs")
+    } else {
+        None
+    }
+}
+
+fn looks_like_uuid(key: &str) -> bool {
+    let groups: Vec<_> = key.split('-').collect();
+    groups.iter().map(|g| g.len()).eq([8, 4, 4, 4, 12])
+        && groups
+            .iter()
+            .all(|g| g.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// The steps each step waits for, without `depends_on`.
+fn implicit_deps(steps: &[Step]) -> Vec<Vec<usize>> {
+    let mut last_barrier = None;
+    let mut since_barrier = Vec::new();
+
+    steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| match step.is_barrier() {
+            true => {
+                let deps = since_barrier.drain(..).chain(last_barrier).collect();
+                last_barrier = Some(i);
+                deps
+            }
+            false => {
+                since_barrier.push(i);
+                last_barrier.into_iter().collect()
+            }
+        })
+        .collect()
+}
+
+/// Groups of steps that wait on each other (through other steps), and so
+/// would never run, each in pipeline order.
+fn find_cycles(deps: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let mut dependents = vec![Vec::new(); deps.len()];
+    for (i, step_deps) in deps.iter().enumerate() {
+        for &dep in step_deps {
+            dependents[dep].push(i);
+        }
+    }
+
+    // Order steps by when we're done with everything they wait on...
+    let mut order = Vec::with_capacity(deps.len());
+    let mut visited = vec![false; deps.len()];
+    for root in 0..deps.len() {
+        if visited[root] {
+            continue;
+        }
+        visited[root] = true;
+        let mut stack = vec![(root, 0)];
+        while let Some((i, next)) = stack.last_mut() {
+            let i = *i;
+            match deps[i].get(*next) {
+                Some(&dep) => {
+                    *next += 1;
+                    if !visited[dep] {
+                        visited[dep] = true;
+                        stack.push((dep, 0));
+                    }
+                }
+                None => {
+                    order.push(i);
+                    stack.pop();
+                }
+            }
+        }
+    }
+
+    // ...then, latest first, the steps waiting on each that haven't been
+    // grouped yet are those it also waits on
+    let mut grouped = vec![false; deps.len()];
+    let mut cycles = Vec::new();
+    for &root in order.iter().rev() {
+        if grouped[root] {
+            continue;
+        }
+        grouped[root] = true;
+        let mut group = vec![root];
+        let mut stack = vec![root];
+        while let Some(i) = stack.pop() {
+            for &j in &dependents[i] {
+                if !grouped[j] {
+                    grouped[j] = true;
+                    group.push(j);
+                    stack.push(j);
+                }
+            }
+        }
+        if group.len() > 1 || deps[root].contains(&root) {
+            group.sort();
+            cycles.push(group);
+        }
+    }
+    cycles.sort();
+
+    cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn command(key: &str, depends_on: &[&str]) -> Value {
+        json!({"type": "command", "key": key, "command": "true", "depends_on": depends_on})
+    }
+
+    fn wait(key: &str) -> Value {
+        json!({
+            "type": "wait",
+            "key": key,
+            "allow_dependency_failure": false,
+            "continue_on_failure": false,
+        })
+    }
+
+    /// Validate `steps`, from the CI config unless they're in `origins`.
+    fn problems(steps: Value, origins: &[(usize, Origin)]) -> Vec<Problem> {
+        let steps: Vec<Step> = serde_json::from_value(steps).unwrap();
+        let mut all_origins: Vec<_> = steps
+            .iter()
+            .map(|s| Origin::Config(format!("steps.{}", s.key().unwrap_or_default())))
+            .collect();
+        for (i, origin) in origins {
+            all_origins[*i] = origin.clone();
+        }
+
+        match validate(&steps, &all_origins) {
+            Ok(_) => Vec::new(),
+            Err(ValidationError(problems)) => problems,
+        }
+    }
+
+    fn keys(steps: &[StepRef]) -> Vec<&str> {
+        steps.iter().filter_map(|s| s.key.as_deref()).collect()
+    }
+
+    #[test]
+    fn accepts_valid_pipelines() {
+        let problems = problems(
+            json!([
+                command("build-hello", &[]),
+                command("deploy", &["build-hello"]),
+                wait("wait"),
+                command("release:1_0", &["deploy"]),
+            ]),
+            &[],
+        );
+        assert!(problems.is_empty(), "{problems:?}");
+    }
+
+    #[test]
+    fn reports_duplicate_keys() {
+        let problems = problems(
+            json!([
+                command("build-hello", &[]),
+                command("deploy", &[]),
+                command("build-hello", &[]),
+            ]),
+            &[(0, Origin::Build("packages.x86_64-linux.hello".to_string()))],
+        );
+
+        let [Problem::DuplicateKey { key, steps }] = &problems[..] else {
+            panic!("{problems:?}");
+        };
+        assert_eq!(key, "build-hello");
+        assert_eq!(keys(steps), ["build-hello", "build-hello"]);
+        assert_eq!(
+            problems[0].to_string(),
+            "more than one step has the key \"build-hello\", from the build of \
+             `packages.x86_64-linux.hello`, `steps.build-hello`"
+        );
+    }
+
+    #[test]
+    fn reports_unknown_dependencies() {
+        let problems = problems(
+            json!([command("deploy", &["build-hello", "build-missing"])]),
+            &[],
+        );
+
+        let [Problem::UnknownDependency {
+            step,
+            dependency: a,
+        }, Problem::UnknownDependency { dependency: b, .. }] = &problems[..]
+        else {
+            panic!("{problems:?}");
+        };
+        assert_eq!(step.key.as_deref(), Some("deploy"));
+        assert_eq!([a, b], ["build-hello", "build-missing"]);
+    }
+
+    #[test]
+    fn reports_only_the_steps_in_cycles() {
+        // `stuck` waits on one cycle and is waited on by the other, but isn't
+        // part of either. `loop` waits on itself through the wait step
+        let problems = problems(
+            json!([
+                command("a", &["b"]),
+                command("b", &["a"]),
+                command("stuck", &["a"]),
+                command("loop", &["after"]),
+                wait("wait"),
+                command("after", &[]),
+            ]),
+            &[],
+        );
+
+        let [Problem::Cycle(first), Problem::Cycle(second)] = &problems[..] else {
+            panic!("{problems:?}");
+        };
+        assert_eq!(keys(first), ["a", "b"]);
+        assert_eq!(keys(second), ["loop", "wait", "after"]);
+        assert_eq!(
+            problems[0].to_string(),
+            "steps wait on each other in a cycle: \"a\" (from `steps.a`), \"b\" (from `steps.b`)"
+        );
+    }
+
+    #[test]
+    fn reports_invalid_keys() {
+        let problems = problems(
+            json!([
+                command("", &[]),
+                command("deploy prod", &[]),
+                command("0e0b9b1c-4a7e-4f4b-9c4f-2d0c5c2b6a1e", &[]),
+                command("0e0b9b1c-4a7e-4f4b-9c4f", &[]),
+            ]),
+            &[],
+        );
+
+        let reasons: Vec<_> = problems
+            .iter()
+            .map(|p| match p {
+                Problem::InvalidKey { step, reason } => (step.key.as_deref().unwrap(), *reason),
+                _ => panic!("{problems:?}"),
+            })
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                ("", "keys can't be empty"),
+                (
+                    "deploy prod",
+                    "keys can only contain letters, numbers, `_`, `-` and `:`"
+                ),
+                (
+                    "0e0b9b1c-4a7e-4f4b-9c4f-2d0c5c2b6a1e",
+                    "keys can't look like UUIDs"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let steps = json!([
+            command("deploy", &["missing"]),
+            command("deploy", &[]),
+            command("bad key", &[]),
+            command("a", &["b"]),
+            command("b", &["a"]),
+        ]);
+        let problems = problems(steps, &[]);
+
+        assert!(matches!(
+            &problems[..],
+            [
+                Problem::InvalidKey { .. },
+                Problem::DuplicateKey { .. },
+                Problem::UnknownDependency { .. },
+                Problem::Cycle(_),
+            ]
+        ));
+        let message = ValidationError(problems).to_string();
+        assert!(message.starts_with("found 4 problems:\n  - step \"bad key\""));
+        assert_eq!(message.lines().count(), 5);
+    }
+}